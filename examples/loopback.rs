@@ -0,0 +1,56 @@
+//! Send a message from a client to a server in the same process, using CryptoMaterial::crypto_config to go from
+//! passphrase to config in one call. Run with `cargo run --example loopback`
+
+use qcat::{
+    core::{QcatClient, QcatServer},
+    crypto::{CryptoMaterial, SaltedPassphrase},
+};
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // the server generates a passphrase, which the client would normally be told out-of-band
+    let server_material = CryptoMaterial::generate()?;
+    let passphrase = server_material.passphrase().to_string();
+    println!("Passphrase: {}", passphrase);
+
+    let cancel = CancellationToken::new();
+    let mut server = QcatServer::new(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        server_material.crypto_config(),
+    )?
+    .with_cancellation_token(cancel.clone());
+    let server_addr = server.local_addrs()?[0];
+
+    let client_material =
+        CryptoMaterial::generate_from_passphrase(SaltedPassphrase::from_str(&passphrase)?)?;
+    let mut client = QcatClient::new(client_material.crypto_config())?;
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut server_output = Arc::clone(&output);
+    let message = b"hello over QUIC\n";
+    let send = async {
+        client.run(server_addr, &mut &message[..]).await?;
+        // the client is done once the server has acknowledged everything, give it a moment to write it out
+        while output.lock().await.len() < message.len() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        cancel.cancel();
+        Ok::<_, Box<dyn Error>>(())
+    };
+    let (_, sent) = tokio::join!(server.run(&mut server_output), send);
+    sent?;
+
+    print!(
+        "Server received: {}",
+        String::from_utf8_lossy(&output.lock().await)
+    );
+    Ok(())
+}
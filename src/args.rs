@@ -1,18 +1,1050 @@
-use clap::Parser;
+use crate::{
+    compression::CompressionMode,
+    crypto::{self, KdfParams, PinMode, SaltKind, SecurityLevel},
+    masque::MasqueProxy,
+    rotate::RotateThreshold,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
+use std::{fmt, path::PathBuf, str::FromStr, sync::OnceLock};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    long_version = long_version(),
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 // TODO: clean all this up
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(short, long)]
     pub listen: bool,
     #[arg(short, long)]
     pub debug: bool,
-    // TODO: actually make this hostname, currently just parsing as ip addr
-    #[arg(help = "Hostname to either connect to or listen on (i.e. localhost)")]
-    pub hostname: String,
     #[arg(
-        help = "Port to utilize. If in server mode, this is the port to listen on. If in client mode, this is the port to connect to."
+        short,
+        long,
+        help = "Hosts must be numeric IP addresses, never look them up with DNS"
     )]
+    pub numeric: bool,
+    #[arg(
+        long,
+        help = "Print how long each phase of setup took (DNS, key derivation, handshake, first byte) to stderr on exit"
+    )]
+    pub timings: bool,
+    #[arg(
+        long,
+        help = "Don't color our human facing output (timings, hints). Also off when stderr isn't a terminal or NO_COLOR is set"
+    )]
+    pub no_color: bool,
+    #[arg(
+        required_unless_present = "qr_file",
+        help = "Hostname to either connect to or listen on (i.e. localhost). A client also accepts a qcat://PASSPHRASE@HOST:PORT URI in place of the hostname, port and passphrase"
+    )]
+    pub hostname: Option<String>,
+    #[arg(
+        value_parser = parse_port,
+        help = "Port to utilize, or a service name from the services database (i.e. https). If in server mode, this is the port to listen on. If in client mode, this is the port to connect to."
+    )]
+    pub port: Option<u16>,
+    #[arg(
+        long = "cipher",
+        value_name = "SUITE",
+        help = "Restrict TLS1.3 cipher suites to the given suite (i.e. TLS13_AES_256_GCM_SHA384). Can be repeated, both sides must share at least one suite"
+    )]
+    pub ciphers: Vec<String>,
+    #[arg(
+        long = "sig-scheme",
+        value_name = "SCHEME",
+        help = "Only accept peer signatures using this scheme (i.e. ED25519). Can be repeated. Our certs are Ed25519, so ED25519 must be allowed"
+    )]
+    pub signature_schemes: Vec<String>,
+    #[arg(long, value_enum, default_value_t = LogFormat::Plain, help = "Format of log output")]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write logs to this file (appending) rather than stderr"
+    )]
+    pub log_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SOCKET",
+        help = "Relay data through a UNIX socket instead of stdin/stdout. Prefix with '@' for the abstract namespace (Linux only)"
+    )]
+    pub unix: Option<String>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Maximum MTU to use for QUIC packets, lower this if handshakes stall on paths with small MTUs"
+    )]
+    pub mtu: Option<u16>,
+    #[arg(
+        long,
+        conflicts_with = "mtu",
+        help = "Use a conservative MTU (1280) suitable for VPNs and tunnels"
+    )]
+    pub conservative_mtu: bool,
+    #[arg(
+        long = "passphrase",
+        value_name = "PASSPHRASE",
+        help = "Use this salted passphrase rather than generating (server) or prompting for (client) one. The server accepts this flag multiple times to accept several passphrases while rotating, the first is presented by default"
+    )]
+    pub passphrases: Vec<String>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Maximum duration of the whole session. Once exceeded the transfer is stopped, received data is flushed and qcat exits with status 124"
+    )]
+    pub max_duration: Option<u64>,
+    #[arg(
+        long,
+        help = "Send every read from input immediately rather than coalescing, for low latency interactive use"
+    )]
+    pub no_delay: bool,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        num_args = 0..=1,
+        default_missing_value = "4096",
+        value_parser = clap::value_parser!(u64).range(512..),
+        help = "Copy data through small fixed size buffers (4KiB unless given), never holding much of it in memory"
+    )]
+    pub low_memory: Option<u64>,
+    #[arg(
+        long = "recv-window",
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Connection flow control window. Raise for high bandwidth * latency links (i.e. 12500000 for 1Gbit/s at 100ms), at the cost of buffering up to this much per connection"
+    )]
+    pub receive_window: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Per-stream flow control window, also capped by --recv-window"
+    )]
+    pub stream_window: Option<u64>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write received data to this file rather than stdout"
+    )]
+    pub output: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "output",
+        conflicts_with_all = ["append", "output_rotate"],
+        help = "Linux only. Write --output with O_DIRECT, bypassing the page cache, for large transfers to fast storage. Falls back to normal writes where unsupported"
+    )]
+    pub direct_io: bool,
+    #[arg(
+        long,
+        value_name = "SIZE|DURATION",
+        requires = "output",
+        help = "Rotate the --output file once it reaches a size (i.e. 100M) or age (i.e. 30s, 5min, 1h), writing numbered files like capture-0001.bin"
+    )]
+    pub output_rotate: Option<RotateThreshold>,
+    #[arg(
+        long,
+        requires = "output",
+        conflicts_with = "output_rotate",
+        help = "Append to the --output file rather than truncating it"
+    )]
+    pub append: bool,
+    #[arg(
+        long,
+        requires_all = ["listen", "output"],
+        conflicts_with_all = ["output_rotate", "append"],
+        help = "Server only. Encrypt the --output file at rest with a key derived from the passphrase. Recover it with qcat decrypt"
+    )]
+    pub encrypt_output: bool,
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Pass data through this shell command, sent input before it goes out and received data before it's written (i.e. \"gzip\" when sending, \"gunzip\" when receiving)"
+    )]
+    pub filter: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write a timestamped transcript of every chunk sent (>) and received (<) to PATH, with binary data escaped"
+    )]
+    pub transcript: Option<PathBuf>,
+    #[arg(
+        long = "input",
+        visible_alias = "concat",
+        value_name = "FILE",
+        conflicts_with_all = ["listen", "unix"],
+        help = "Client only. Send these files as a single raw stream rather than stdin, back to back unless --merge. Repeat for each file, i.e. --input a --input b"
+    )]
+    pub inputs: Vec<PathBuf>,
+    #[arg(
+        long,
+        requires = "inputs",
+        help = "Client only. Interleave the --input files (i.e. FIFOs or growing logs) as each has data, rather than sending them back to back"
+    )]
+    pub merge: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["unix", "inputs", "send", "output"],
+        help = "Send the text on the system clipboard rather than stdin, and put received text on the clipboard rather than stdout"
+    )]
+    pub clipboard: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["listen", "unix", "inputs", "duplex", "receive"],
+        help = "Client only. Send FILE along with its name. A server without --output saves it under that name in its current directory"
+    )]
+    pub send: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        requires = "send",
+        conflicts_with_all = ["filter", "transcript"],
+        help = "Client only. If the connection drops while sending a file with --send, reconnect up to N times and carry on where the server left off"
+    )]
+    pub reconnect: u32,
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "send",
+        conflicts_with_all = ["reconnect", "filter", "transcript"],
+        value_parser = clap::value_parser!(u64).range(1..=100),
+        help = "Client only. Split the --send file into N segments sent side by side over as many streams, for links one stream can't fill. The server checks the reassembled file's digest"
+    )]
+    pub parallel: Option<u64>,
+    #[arg(
+        long,
+        requires = "send",
+        help = "Client only. Send the file's permissions and modification time along with it, for the server to restore on the file it saves"
+    )]
+    pub preserve: bool,
+    #[arg(
+        long,
+        value_name = "IMAGE",
+        conflicts_with = "listen",
+        help = "Client only. Read a qcat:// URI (in place of the hostname, port and passphrase) or passphrase from a QR code in IMAGE"
+    )]
+    pub qr_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "listen",
+        help = "Server only. Send this file to each client that connects rather than receiving"
+    )]
+    pub push: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "listen",
+        help = "Client only. Receive data from the server (i.e. one started with --push) rather than sending"
+    )]
+    pub receive: bool,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Echo everything received back to the client (use with a --duplex --keep-receiving client)"
+    )]
+    pub echo: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["listen", "receive"],
+        help = "Client only. Send input while writing anything the server sends back to output"
+    )]
+    pub duplex: bool,
+    #[arg(
+        long,
+        requires = "duplex",
+        help = "Client only. After the handshake, show the peer's fingerprint phrase and wait for you to confirm it before any data flows. Skipped when stdin isn't a terminal"
+    )]
+    pub confirm: bool,
+    #[arg(
+        long,
+        value_name = "RATE",
+        value_parser = parse_rate,
+        help = "Client only. In --duplex or --tcp-connect mode, send at most RATE bytes per second (optional K/M/G suffix, i.e. 512K)"
+    )]
+    pub rate_up: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Log throughput each time another SIZE bytes have been sent (optional K/M/G suffix, i.e. 100M)"
+    )]
+    pub checkpoint_every: Option<u64>,
+    #[arg(
+        long,
+        value_name = "RATE",
+        value_parser = parse_rate,
+        help = "Client only. In --duplex or --tcp-connect mode, receive at most RATE bytes per second (optional K/M/G suffix, i.e. 2M)"
+    )]
+    pub rate_down: Option<u64>,
+    #[arg(
+        long,
+        requires = "duplex",
+        help = "With --duplex, keep receiving after input hits EOF until the server closes, rather than exiting"
+    )]
+    pub keep_receiving: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["listen", "receive", "duplex"],
+        help = "Client only. Send all of input, then write the server's whole reply to output and exit once it closes"
+    )]
+    pub request: bool,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = ["push", "echo", "receive", "duplex", "unix", "output"],
+        help = "Tunnel a TCP service over the connection. The client connects to ADDR and bridges it to the server, which bridges it on to its own --tcp-connect ADDR"
+    )]
+    pub tcp_connect: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "listen",
+        conflicts_with_all = ["push", "echo", "tcp_connect", "output"],
+        help = "Server only. Forward TCP connections accepted on ADDR to the connected client, which bridges them to its --tcp-connect ADDR (use with a --reverse client)"
+    )]
+    pub tcp_listen: Option<String>,
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        requires = "listen",
+        conflicts_with_all = ["push", "echo", "tcp_connect", "tcp_listen"],
+        help = "Server only. Relay everything received on to the qcat server at HOST:PORT. Received data is only written locally with --output or --unix"
+    )]
+    pub forward: Option<String>,
+    #[arg(
+        long,
+        value_name = "PASSPHRASE",
+        requires = "forward",
+        help = "Server only. Passphrase of the --forward server, if it isn't using ours"
+    )]
+    pub forward_passphrase: Option<String>,
+    #[arg(
+        long,
+        requires = "tcp_connect",
+        conflicts_with = "listen",
+        help = "Client only. With --tcp-connect, bridge the TCP connections the server forwards from its --tcp-listen ADDR rather than tunneling our own"
+    )]
+    pub reverse: bool,
+    #[arg(
+        long,
+        conflicts_with = "listen",
+        help = "Client only. Print the fingerprint of the cert derived from the passphrase and exit without connecting"
+    )]
+    pub show_fingerprint: bool,
+    #[arg(
+        long = "0rtt",
+        help = "Allow 0-RTT early data when resuming sessions. Early data can be replayed, so only use this for idempotent transfers. Both sides need this set"
+    )]
+    pub zero_rtt: bool,
+    #[arg(
+        long,
+        help = "Only use post-quantum hybrid key exchange (X25519MLKEM768). Both sides need this set or the handshake fails"
+    )]
+    pub pq: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PinMode::Cert,
+        help = "Pin the peer's whole cert, or only its public key (spki) so other cert fields can differ"
+    )]
+    pub pin_mode: PinMode,
+    #[arg(
+        long,
+        help = "If the peer's cert doesn't match, log how it differs from ours (subject, key type, key, validity)"
+    )]
+    pub explain_cert: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SecurityLevel::Std,
+        help = "high only allows 256 bit cipher suites, Ed25519/Ed448/ECDSA P-384+ signatures and post-quantum key exchange, rejecting peers that can't"
+    )]
+    pub security_level: SecurityLevel,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Log a qcat:// URI with our host, port and passphrase that a client can connect with"
+    )]
+    pub uri: bool,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Print the command a client runs to connect to us, along with the passphrase to enter"
+    )]
+    pub print_peer_command: bool,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Don't log a generated passphrase, or the --uri holding it, so it can't end up in log aggregation. The default with --log-file. Get it with --print-passphrase, --uri or --print-peer-command instead"
+    )]
+    pub no_log_passphrase: bool,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Print a generated passphrase to stderr, outside the logs"
+    )]
+    pub print_passphrase: bool,
+    #[arg(
+        long,
+        value_name = "HOST",
+        requires = "listen",
+        help = "Server only. Host clients reach us at, for --uri and --print-peer-command, when it isn't the one we listen on (i.e. 0.0.0.0)"
+    )]
+    pub advertise_host: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with = "listen",
+        help = "Client only. Server name (SNI) to send in the handshake, for load balancers that route by it. Only affects routing, the server's cert is pinned regardless. Replaces the hint a server holding several passphrases picks its cert by"
+    )]
+    pub sni: Option<String>,
+    #[arg(
+        long,
+        value_name = "VALUE",
+        value_parser = clap::value_parser!(u8).range(0..=63),
+        help = "Mark our packets with this DSCP value (0-63) for QoS"
+    )]
+    pub dscp: Option<u8>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Warn when the peer's clock differs from ours by more than SECONDS. A client waits for the server's ready signal to check it"
+    )]
+    pub max_clock_skew: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        requires = "listen",
+        help = "Server only. The passphrase expires SECONDS from now, after which clients are rejected"
+    )]
+    pub expire_in: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "listen",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Server only. Close connections that receive no data for SECS, however long QUIC would keep them open"
+    )]
+    pub reap_idle: Option<u64>,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Reject clients whose wordlist differs from ours, rather than warning"
+    )]
+    pub strict_wordlist: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "listen",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Server only. Block a peer IP for --block-duration after N consecutive failed handshakes (i.e. wrong passphrases)"
+    )]
+    pub max_failed_attempts: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "listen",
+        help = "Server only. Serve Prometheus metrics over HTTP on ADDR (i.e. 127.0.0.1:9090)"
+    )]
+    pub metrics_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 300,
+        requires = "max_failed_attempts",
+        help = "Server only. How long a peer is blocked for after --max-failed-attempts"
+    )]
+    pub block_duration: u64,
+    #[arg(
+        long,
+        value_name = "USER",
+        requires = "listen",
+        help = "Server only (Unix). Drop privileges to USER (name or uid) once our socket is bound. Required when run as root"
+    )]
+    pub user: Option<String>,
+    #[arg(
+        long,
+        value_name = "GROUP",
+        requires = "user",
+        help = "Server only (Unix). Group (name or gid) to drop to along with --user, defaults to the user's primary group"
+    )]
+    pub group: Option<String>,
+    #[arg(
+        long,
+        value_name = "MS",
+        hide = true,
+        conflicts_with = "listen",
+        help = "Client only, for testing. Delay every datagram in both directions by MS milliseconds"
+    )]
+    pub simulate_latency: Option<u64>,
+    #[arg(
+        long,
+        value_name = "PCT",
+        hide = true,
+        conflicts_with = "listen",
+        value_parser = parse_percentage,
+        help = "Client only, for testing. Randomly drop PCT percent of datagrams in both directions"
+    )]
+    pub simulate_loss: Option<f64>,
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with = "listen",
+        help = "Client only. Tunnel through a MASQUE (CONNECT-UDP over HTTP/1.1) proxy, i.e. http://proxy:8080"
+    )]
+    pub masque_proxy: Option<MasqueProxy>,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Listen on every address the hostname resolves to rather than just the first"
+    )]
+    pub bind_all: bool,
+    #[arg(
+        long = "allow-cidr",
+        value_name = "CIDR",
+        requires = "listen",
+        help = "Server only. Only accept peers from this network (i.e. 10.0.0.0/8). Can be repeated"
+    )]
+    pub allowed_networks: Vec<IpNet>,
+    #[arg(
+        long,
+        conflicts_with = "listen",
+        help = "Client only. Wait for the server to signal it's ready to receive before sending, logging when it is"
+    )]
+    pub wait_ready: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 0,
+        conflicts_with = "listen",
+        help = "Client only. Give up if the passphrase isn't entered at the prompt within SECS seconds. 0 waits forever"
+    )]
+    pub prompt_timeout: u64,
+    #[arg(
+        long,
+        conflicts_with = "listen",
+        help = "Client only. Keep retrying until the server answers, for starting the client before the server"
+    )]
+    pub wait: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 1.0,
+        value_parser = parse_delay,
+        requires = "wait",
+        help = "With --wait, delay before retrying, doubling with each attempt up to 30s"
+    )]
+    pub retry_delay: f64,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Send at most N bytes of the input, then close our side. Shorter input is sent whole"
+    )]
+    pub send_bytes: Option<u64>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Write at most N bytes received on each stream, then stop receiving it"
+    )]
+    pub recv_bytes: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_interval,
+        conflicts_with = "listen",
+        help = "Client only. Ping the server at this interval, logging the round trip time"
+    )]
+    pub ping: Option<f64>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CompressionMode::Off,
+        help = "Compress sent data with zstd. auto only compresses if a sample of the input compresses well"
+    )]
+    pub compress: CompressionMode,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "zstd dictionary to compress with, or that the server expects compressed data to use. Both ends need the same file, shared out-of-band like the passphrase"
+    )]
+    pub zstd_dict: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "listen",
+        value_parser = clap::value_parser!(u8).range(1..),
+        help = "Server only. Number of words in a generated passphrase's salt"
+    )]
+    pub salt_words: u8,
+    #[arg(
+        long,
+        requires = "listen",
+        conflicts_with = "salt_words",
+        help = "Server only. Use random bytes (hex encoded) rather than words for a generated passphrase's salt"
+    )]
+    pub random_salt: bool,
+    #[arg(
+        long,
+        value_name = "CODE",
+        requires = "listen",
+        help = "Server only. Language of the wordlist generated passphrases use (en or es), falling back to English for ones we don't have"
+    )]
+    pub wordlist_lang: Option<String>,
+    #[arg(
+        long,
+        requires = "listen",
+        help = "Server only. Generate passphrases from short (4-7 letter) words that are easier to type, at the cost of some entropy"
+    )]
+    pub easy_words: bool,
+    #[arg(
+        long,
+        value_name = "SALT",
+        conflicts_with_all = ["salt_words", "random_salt"],
+        help = "Use a salt distributed out-of-band (at least 8 bytes). Passphrases are then given and shown without their salt"
+    )]
+    pub salt: Option<String>,
+    #[arg(
+        long,
+        value_name = "KIB",
+        default_value_t = KdfParams::default().memory_kib,
+        help = "Argon2 memory cost in KiB used to derive keys from the passphrase. Both sides must match, see kdf-bench"
+    )]
+    pub kdf_mem: u32,
+    #[arg(
+        long,
+        value_name = "ITERATIONS",
+        default_value_t = KdfParams::default().iterations,
+        help = "Argon2 iterations used to derive keys from the passphrase. Both sides must match, see kdf-bench"
+    )]
+    pub kdf_iters: u32,
+}
+
+impl Args {
+    pub fn kdf_params(&self) -> KdfParams {
+        KdfParams {
+            memory_kib: self.kdf_mem,
+            iterations: self.kdf_iters,
+        }
+    }
+
+    /// Whether a generated passphrase can go in our logs. Not when they're going to a file, where they're likely to
+    /// be kept and shipped elsewhere
+    pub fn log_passphrase(&self) -> bool {
+        !self.no_log_passphrase && self.log_file.is_none()
+    }
+
+    pub fn salt_kind(&self) -> SaltKind {
+        if let Some(salt) = &self.salt {
+            SaltKind::Fixed(salt.clone())
+        } else if self.random_salt {
+            SaltKind::Random
+        } else {
+            SaltKind::Words(self.salt_words.into())
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Time key derivation with increasing Argon2 costs to help pick --kdf-mem/--kdf-iters for this machine
+    KdfBench {
+        #[arg(
+            long,
+            value_name = "SECS",
+            default_value_t = 1.0,
+            value_parser = parse_interval,
+            help = "Target derivation time to recommend settings for"
+        )]
+        target: f64,
+    },
+    /// Decrypt a file written with --encrypt-output
+    Decrypt {
+        #[arg(help = "Encrypted file to decrypt")]
+        input: PathBuf,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write the decrypted data to this file rather than stdout"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PASSPHRASE",
+            help = "The server's salted passphrase, prompted for if not given"
+        )]
+        passphrase: Option<String>,
+        #[arg(
+            long,
+            value_name = "SALT",
+            help = "The salt, if the server was given one with --salt"
+        )]
+        salt: Option<String>,
+    },
+    /// Check a salted passphrase is well formed and estimate its strength, without connecting anywhere
+    Check {
+        #[arg(help = "The salted passphrase to check")]
+        passphrase: String,
+    },
+}
+
+/// Output style for our logger
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Default env_logger format
+    Plain,
+    /// One JSON object per log record
+    Json,
+    /// Level and message only
+    Compact,
+}
+
+/// Scheme of the URIs clients accept in place of the hostname, port and passphrase
+pub const QCAT_URI_SCHEME: &str = "qcat://";
+
+/// Everything a client needs to connect, as a single qcat://PASSPHRASE@HOST:PORT URI. The passphrase is percent
+/// encoded, IPv6 hosts are bracketed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QcatUri {
+    pub passphrase: String,
+    pub host: String,
     pub port: u16,
 }
+
+impl FromStr for QcatUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(QCAT_URI_SCHEME)
+            .ok_or_else(|| format!("URI must start with {}", QCAT_URI_SCHEME))?;
+        let (passphrase, host_port) = rest
+            .rsplit_once('@')
+            .ok_or("URI is missing a passphrase, expected qcat://PASSPHRASE@HOST:PORT")?;
+
+        let (host, port) = match host_port.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or("URI has an unterminated IPv6 address")?;
+                (host, port.strip_prefix(':').ok_or("URI is missing a port")?)
+            }
+            None => host_port.rsplit_once(':').ok_or("URI is missing a port")?,
+        };
+
+        if host.is_empty() {
+            return Err("URI is missing a host".to_owned());
+        }
+
+        Ok(Self {
+            passphrase: percent_decode(passphrase)?,
+            host: host.to_owned(),
+            port: port
+                .trim_end_matches('/')
+                .parse()
+                .map_err(|e| format!("Invalid port in URI: {}", e))?,
+        })
+    }
+}
+
+impl fmt::Display for QcatUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let passphrase = percent_encode(&self.passphrase);
+        if self.host.contains(':') {
+            write!(
+                f,
+                "{}{}@[{}]:{}",
+                QCAT_URI_SCHEME, passphrase, self.host, self.port
+            )
+        } else {
+            write!(
+                f,
+                "{}{}@{}:{}",
+                QCAT_URI_SCHEME, passphrase, self.host, self.port
+            )
+        }
+    }
+}
+
+/// Percent encode everything but RFC 3986 unreserved characters
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or("URI has an invalid percent encoding")?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| "URI passphrase is not valid UTF-8".to_owned())
+}
+
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let percentage: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err("must be between 0 and 100".to_owned());
+    }
+
+    Ok(percentage)
+}
+
+/// Parse a number of seconds to wait, which may be 0
+fn parse_delay(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err("must be a number of seconds, 0 or more".to_owned());
+    }
+
+    Ok(secs)
+}
+
+/// Parse a number of seconds something takes or repeats every, which must be more than 0
+fn parse_interval(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err("must be a number of seconds more than 0".to_owned());
+    }
+
+    Ok(secs)
+}
+
+/// Parse a rate in bytes per second, with an optional K/M/G suffix
+fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "rate")
+}
+
+/// Parse a size in bytes, with an optional K/M/G suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "size")
+}
+
+/// Parse a non-zero number of bytes with an optional K/M/G suffix, naming it what in errors
+fn parse_bytes(s: &str, what: &str) -> Result<u64, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (value, unit) = lower.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid {}: {}", what, s))?;
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown {} unit: {}", what, unit)),
+    };
+    if value == 0 {
+        return Err(format!("{} must be greater than zero", what));
+    }
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{} is too large: {}", what, s))
+}
+
+/// Parse a port number, or look up a service name like netcat does
+fn parse_port(s: &str) -> Result<u16, String> {
+    if let Ok(port) = s.parse() {
+        return Ok(port);
+    }
+
+    let services = std::fs::read_to_string(SERVICES_PATH)
+        .map_err(|e| format!("not a port and unable to read {}: {}", SERVICES_PATH, e))?;
+    lookup_service(&services, s).ok_or_else(|| format!("unknown port or service name: {}", s))
+}
+
+/// Path to the services database mapping service names to ports
+#[cfg(not(windows))]
+const SERVICES_PATH: &str = "/etc/services";
+#[cfg(windows)]
+const SERVICES_PATH: &str = r"C:\Windows\System32\drivers\etc\services";
+
+/// Find a service's port in the contents of a services database, where each line is "name port/protocol aliases...".
+/// We're QUIC so UDP entries win, though most services list the same port for both protocols
+fn lookup_service(services: &str, name: &str) -> Option<u16> {
+    let mut tcp_port = None;
+
+    for line in services.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let (Some(service), Some(port_protocol)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if service != name && !fields.any(|alias| alias == name) {
+            continue;
+        }
+        let Some((port, protocol)) = port_protocol.split_once('/') else {
+            continue;
+        };
+        let Ok(port) = port.parse() else {
+            continue;
+        };
+
+        match protocol {
+            "udp" => return Some(port),
+            "tcp" => tcp_port = tcp_port.or(Some(port)),
+            _ => {}
+        }
+    }
+
+    tcp_port
+}
+
+/// Version plus details about the embedded wordlist, shown by --version
+fn long_version() -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+
+    LONG_VERSION.get_or_init(|| {
+        format!(
+            "{}\nwordlist: {} words (~{:.1} bits per passphrase)",
+            env!("CARGO_PKG_VERSION"),
+            crypto::wordlist_len(),
+            crypto::passphrase_entropy_bits()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(passphrase: &str, host: &str) -> QcatUri {
+        let uri = QcatUri {
+            passphrase: passphrase.to_owned(),
+            host: host.to_owned(),
+            port: 4433,
+        };
+        let parsed: QcatUri = uri.to_string().parse().unwrap();
+        assert_eq!(parsed, uri);
+        parsed
+    }
+
+    #[test]
+    fn uri_round_trips() {
+        let uri = QcatUri {
+            passphrase: "salt-correct-horse-battery".to_owned(),
+            host: "example.com".to_owned(),
+            port: 4433,
+        };
+        assert_eq!(
+            uri.to_string(),
+            "qcat://salt-correct-horse-battery@example.com:4433"
+        );
+
+        round_trip("salt-correct-horse-battery", "192.0.2.1");
+        round_trip("salt-correct-horse-battery", "2001:db8::1");
+        // characters that mean something in a URI, and ones that aren't ASCII
+        round_trip("s@lt-p:ss/w%rd?#[]+ space", "example.com");
+        round_trip("sel-mot-de-passe-été", "example.com");
+    }
+
+    #[test]
+    fn uri_encodes_reserved_characters() {
+        let uri = round_trip("salt-a@b%c", "example.com");
+        assert_eq!(uri.to_string(), "qcat://salt-a%40b%25c@example.com:4433");
+    }
+
+    #[test]
+    fn bad_uris_are_rejected() {
+        for uri in [
+            "https://salt-words@example.com:4433",
+            "qcat://example.com:4433",
+            "qcat://salt-words@example.com",
+            "qcat://salt-words@:4433",
+            "qcat://salt-words@[2001:db8::1:4433",
+            "qcat://salt-words@example.com:notaport",
+            "qcat://salt-%zzwords@example.com:4433",
+            "qcat://salt-%ffwords@example.com:4433",
+        ] {
+            assert!(uri.parse::<QcatUri>().is_err(), "{}", uri);
+        }
+    }
+
+    const SERVICES: &str = "\
+# comments and blank lines are skipped
+
+domain          53/tcp
+domain          53/udp          # name-domain server
+https           443/tcp         http-ssl
+http-alt        8080/tcp        webcache
+";
+
+    #[test]
+    fn service_names_resolve_to_ports() {
+        assert_eq!(lookup_service(SERVICES, "https"), Some(443));
+        assert_eq!(lookup_service(SERVICES, "http-ssl"), Some(443));
+        assert_eq!(lookup_service(SERVICES, "domain"), Some(53));
+        assert_eq!(lookup_service(SERVICES, "no-such-service"), None);
+    }
+
+    #[test]
+    fn ports_parse_numerically_first() {
+        assert_eq!(parse_port("4433"), Ok(4433));
+        assert!(parse_port("no-such-service")
+            .unwrap_err()
+            .contains("no-such-service"));
+    }
+
+    #[test]
+    fn merge_reads_its_files_from_input_or_concat() {
+        let args = Args::try_parse_from([
+            "qcat", "--input", "a", "--concat", "b", "--merge", "host", "4433",
+        ])
+        .unwrap();
+        assert_eq!(args.inputs, [PathBuf::from("a"), PathBuf::from("b")]);
+        assert!(args.merge);
+
+        assert!(Args::try_parse_from(["qcat", "--merge", "host", "4433"]).is_err());
+    }
+
+    #[test]
+    fn delays_must_be_finite_and_not_negative() {
+        assert_eq!(parse_delay("0"), Ok(0.0));
+        assert_eq!(parse_delay("1.5"), Ok(1.5));
+        for bad in ["-1", "NaN", "inf", "soon"] {
+            assert!(parse_delay(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn intervals_must_be_finite_and_positive() {
+        assert_eq!(parse_interval("0.5"), Ok(0.5));
+        for bad in ["0", "-1", "NaN", "inf"] {
+            assert!(parse_interval(bad).is_err(), "{}", bad);
+        }
+    }
+}
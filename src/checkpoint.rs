@@ -0,0 +1,83 @@
+use log::info;
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Logs throughput each time another interval of bytes has been read through it, to see how it holds up across a
+/// long transfer rather than just its average
+pub struct CheckpointReader<R> {
+    inner: R,
+    /// None when not checkpointing, so callers can wrap unconditionally
+    interval: Option<u64>,
+    total: u64,
+    start: Instant,
+    /// When we passed the last checkpoint, or started, and how many bytes we'd read then
+    last: (Instant, u64),
+}
+
+impl<R> CheckpointReader<R> {
+    /// Log a checkpoint every interval bytes read from inner, or pass reads through untouched if interval is None
+    pub fn new(inner: R, interval: Option<u64>) -> Self {
+        let start = Instant::now();
+        Self {
+            inner,
+            interval,
+            total: 0,
+            start,
+            last: (start, 0),
+        }
+    }
+
+    fn checkpoint(&mut self) {
+        let now = Instant::now();
+        let (last_at, last_total) = self.last;
+        let since_last = now.duration_since(last_at).as_secs_f64();
+        let since_start = now.duration_since(self.start).as_secs_f64();
+        let rate = (self.total - last_total) as f64 / since_last.max(f64::EPSILON);
+        let average = self.total as f64 / since_start.max(f64::EPSILON);
+        let unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        info!(
+            unix_ms = unix_ms,
+            bytes = self.total,
+            elapsed_ms = (since_start * 1000.0) as u64,
+            bytes_per_sec = rate as u64,
+            average_bytes_per_sec = average as u64;
+            "Checkpoint: {} bytes after {:.1}s, {:.2} MiB/s since the last, {:.2} MiB/s overall",
+            self.total,
+            since_start,
+            rate / (1024.0 * 1024.0),
+            average / (1024.0 * 1024.0)
+        );
+        self.last = (now, self.total);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CheckpointReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if let Some(interval) = self.interval {
+            let before = self.total;
+            self.total += (buf.filled().len() - filled) as u64;
+            // a big read can pass more than one checkpoint, one line covers them
+            if self.total / interval > before / interval {
+                self.checkpoint();
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
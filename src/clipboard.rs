@@ -0,0 +1,213 @@
+use arboard::Clipboard;
+use log::debug;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{io::AsyncWrite, task::JoinHandle};
+
+/// A clipboard holding text, the system's outside of tests. Calls block, so are made from the blocking pool
+pub trait TextClipboard: Send + 'static {
+    fn get_text(&self) -> Result<String, arboard::Error>;
+    fn set_text(&self, text: String) -> Result<(), arboard::Error>;
+}
+
+/// The system clipboard, opened afresh for each call
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClipboard;
+
+impl TextClipboard for SystemClipboard {
+    fn get_text(&self) -> Result<String, arboard::Error> {
+        Clipboard::new()?.get_text()
+    }
+
+    fn set_text(&self, text: String) -> Result<(), arboard::Error> {
+        Clipboard::new()?.set_text(text)
+    }
+}
+
+/// The text on the system clipboard, to send. Anything else on the clipboard (i.e. an image) is an error rather than
+/// being sent as something the other side can't make sense of
+pub async fn read_clipboard() -> io::Result<Vec<u8>> {
+    read_clipboard_from(SystemClipboard).await
+}
+
+/// read_clipboard, from the given clipboard
+pub async fn read_clipboard_from<C: TextClipboard>(clipboard: C) -> io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        clipboard
+            .get_text()
+            .map(String::into_bytes)
+            .map_err(clipboard_error)
+    })
+    .await?
+}
+
+fn clipboard_error(error: arboard::Error) -> io::Error {
+    match error {
+        arboard::Error::ContentNotAvailable => io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Clipboard doesn't hold any text to send",
+        ),
+        error => io::Error::other(format!("Unable to use the clipboard: {}", error)),
+    }
+}
+
+/// Most we'll put on the clipboard, well past any text someone means to paste. Stops a peer filling our memory
+const MAX_CLIPBOARD_LEN: usize = 16 * 1024 * 1024;
+
+/// Collects everything written to it, putting it on the system clipboard as text once shut down. The clipboard only
+/// holds text, so received data that isn't UTF-8 fails the shutdown, as does receiving more than MAX_CLIPBOARD_LEN
+#[derive(Debug)]
+pub struct ClipboardWriter<C = SystemClipboard> {
+    /// Where received goes once shut down, taken when we start copying
+    clipboard: Option<C>,
+    received: Vec<u8>,
+    /// Setting the clipboard from the blocking pool, once shut down
+    copy: Option<JoinHandle<io::Result<()>>>,
+    /// Shut down already, so received has been copied
+    copied: bool,
+}
+
+impl ClipboardWriter {
+    pub fn new() -> Self {
+        Self::with_clipboard(SystemClipboard)
+    }
+}
+
+impl Default for ClipboardWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> ClipboardWriter<C> {
+    /// A writer putting what it receives on clipboard rather than the system's
+    pub fn with_clipboard(clipboard: C) -> Self {
+        Self {
+            clipboard: Some(clipboard),
+            received: Vec::new(),
+            copy: None,
+            copied: false,
+        }
+    }
+}
+
+impl<C: TextClipboard + Unpin> AsyncWrite for ClipboardWriter<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.received.len() + buf.len() > MAX_CLIPBOARD_LEN {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Received more than the {} bytes we'll put on the clipboard",
+                    MAX_CLIPBOARD_LEN
+                ),
+            )));
+        }
+
+        self.received.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.copied {
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.copy.is_none() {
+            let text = String::from_utf8(std::mem::take(&mut self.received)).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Received data isn't text, so can't go on the clipboard",
+                )
+            })?;
+
+            // on X11/Wayland the clipboard is served by whoever set it, so what we set only outlives us if a clipboard
+            // manager takes it over
+            debug!("Copying {} bytes to the clipboard", text.len());
+            let clipboard = self.clipboard.take().expect("only copied once");
+            self.copy = Some(tokio::task::spawn_blocking(move || {
+                clipboard.set_text(text).map_err(clipboard_error)
+            }));
+        }
+
+        let copy = self.copy.as_mut().expect("copy started above");
+        let result = ready!(Pin::new(copy).poll(cx));
+        self.copy = None;
+        self.copied = true;
+        Poll::Ready(result.map_err(io::Error::other)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{transfer_in_memory, QcatOptions};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncWriteExt;
+
+    /// Stands in for the system clipboard, which a headless test machine may not have
+    #[derive(Debug, Clone, Default)]
+    struct MockClipboard(Arc<Mutex<Option<String>>>);
+
+    impl TextClipboard for MockClipboard {
+        fn get_text(&self) -> Result<String, arboard::Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or(arboard::Error::ContentNotAvailable)
+        }
+
+        fn set_text(&self, text: String) -> Result<(), arboard::Error> {
+            *self.0.lock().unwrap() = Some(text);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn text_round_trips_between_clipboards() {
+        let source = MockClipboard::default();
+        source.set_text("snippet to share ✂".to_owned()).unwrap();
+        let destination = MockClipboard::default();
+
+        let input = read_clipboard_from(source).await.unwrap();
+        let output = Arc::new(tokio::sync::Mutex::new(ClipboardWriter::with_clipboard(
+            destination.clone(),
+        )));
+        transfer_in_memory(&QcatOptions::default(), &mut input.as_slice(), &output)
+            .await
+            .unwrap();
+        output.lock().await.shutdown().await.unwrap();
+
+        assert_eq!(
+            destination.get_text().unwrap(),
+            "snippet to share ✂".to_owned()
+        );
+    }
+
+    #[tokio::test]
+    async fn non_text_is_refused() {
+        let error = read_clipboard_from(MockClipboard::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let destination = MockClipboard::default();
+        let mut writer = ClipboardWriter::with_clipboard(destination.clone());
+        writer.write_all(&[0xff, 0xfe, 0x00]).await.unwrap();
+        let error = writer.shutdown().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(destination.get_text().is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::{fmt, io, path::Path, sync::Arc};
+
+/// Size of the sample we compress up front to decide whether compression is worthwhile in auto mode
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// zstd level used to estimate compressibility, cheap since we only care about the rough ratio
+const SAMPLE_COMPRESSION_LEVEL: i32 = 1;
+
+/// Compressed/uncompressed ratio a sample must beat to enable compression in auto mode
+const MAX_WORTHWHILE_RATIO: f64 = 0.9;
+
+/// When the sender compresses data with zstd
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CompressionMode {
+    /// Never compress
+    #[default]
+    Off,
+    /// Always compress
+    On,
+    /// Compress if a sample of the input compresses well
+    Auto,
+}
+
+impl CompressionMode {
+    /// Decide whether to compress, given a sample from the start of the input
+    pub fn should_compress(&self, sample: &[u8]) -> bool {
+        match self {
+            Self::Off => false,
+            Self::On => true,
+            Self::Auto => is_compressible(sample),
+        }
+    }
+}
+
+/// Estimate if data similar to sample is worth compressing
+pub fn is_compressible(sample: &[u8]) -> bool {
+    if sample.is_empty() {
+        return false;
+    }
+
+    match zstd::bulk::compress(sample, SAMPLE_COMPRESSION_LEVEL) {
+        Ok(compressed) => (compressed.len() as f64 / sample.len() as f64) < MAX_WORTHWHILE_RATIO,
+        Err(_) => false,
+    }
+}
+
+/// A zstd dictionary both ends load from the same file, shared out-of-band like the passphrase, which compresses
+/// small or repetitive structured data far better than starting from nothing. Identified by a hash of its contents
+/// so a peer using a different one is caught before anything is decompressed with it
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    data: Arc<[u8]>,
+    id: u64,
+}
+
+impl ZstdDictionary {
+    pub fn new(data: Vec<u8>) -> Self {
+        let digest = Sha256::digest(&data);
+        let id = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 is 32 bytes"));
+        Self {
+            data: data.into(),
+            id,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        std::fs::read(path).map(Self::new)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// First 8 bytes of the SHA-256 of the dictionary, what we send the peer to compare against
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("len", &self.data.len())
+            .field("id", &format_args!("{:016x}", self.id))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_data() -> Vec<u8> {
+        let mut data = vec![0u8; SAMPLE_SIZE];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    fn text() -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n".repeat(SAMPLE_SIZE / 44)
+    }
+
+    #[test]
+    fn auto_skips_random_data() {
+        assert!(!is_compressible(&random_data()));
+        assert!(!CompressionMode::Auto.should_compress(&random_data()));
+        assert!(CompressionMode::On.should_compress(&random_data()));
+    }
+
+    #[test]
+    fn auto_compresses_text() {
+        assert!(is_compressible(&text()));
+        assert!(CompressionMode::Auto.should_compress(&text()));
+        assert!(!CompressionMode::Off.should_compress(&text()));
+    }
+
+    #[test]
+    fn empty_sample_is_not_compressed() {
+        assert!(!CompressionMode::Auto.should_compress(&[]));
+    }
+}
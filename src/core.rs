@@ -1,86 +1,4511 @@
-use s2n_quic::{client::Connect, Client, Server};
-use std::{error::Error, net::SocketAddr, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use async_compression::{
+    tokio::bufread::{ZstdDecoder, ZstdEncoder},
+    Level,
+};
+use futures_core::Stream;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use s2n_quic::{
+    client::Connect,
+    provider::{io, limits::Limits},
+    stream::BidirectionalStream,
+    Client, Connection, Server,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    io::{ErrorKind, SeekFrom},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt, BufReader, DuplexStream, Take,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Mutex,
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::crypto::QcatCryptoConfig;
+use crate::{
+    checkpoint::CheckpointReader,
+    compression::{self, CompressionMode, ZstdDictionary},
+    crypto::{self, QcatCryptoConfig},
+    delay::DelayedWriter,
+    events::{QcatConnectionContext, QcatEventSubscriber},
+    limiter::FailedAttemptLimiter,
+    masque::MasqueProxy,
+    message::MessageStream,
+    metrics::METRICS,
+    parallel::{self, PARALLEL_TRANSFERS},
+    protocol,
+    ratelimit::RateLimitedReader,
+    reaper::{self, ActivityMap, ActivityReader},
+    resume::{ResumeCounter, RESUMABLE_TRANSFERS},
+    split::{self, QcatReceiver, QcatSender},
+    timings::{FirstAckWriter, FirstByteReader, TIMINGS},
+};
+
+/// Conservative MTU for paths through VPNs/tunnels, the IPv6 minimum MTU
+pub const CONSERVATIVE_MTU: u16 = 1280;
+
+/// Application error code we close connections with when rejecting a peer
+const CONNECTION_REJECTED_ERROR_CODE: u32 = 1;
+
+/// Application error code we close connections with when the passphrase has expired, see
+/// [`QcatOptions::expires_at`]
+pub const PASSPHRASE_EXPIRED_ERROR_CODE: u32 = 2;
+
+/// Size of the buffer used when writing received data to our output
+const RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Max ack delay we advertise with no_delay, so the peer acknowledges our small sends promptly
+const NO_DELAY_MAX_ACK_DELAY: Duration = Duration::from_millis(1);
+
+/// Buffer size used when copying input to the stream with no_delay
+const NO_DELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Bytes buffered in each direction of the pipe used by transfer_in_memory
+const IN_MEMORY_PIPE_SIZE: usize = 64 * 1024;
+
+/// Delay before retrying when waiting for a server, if retry_delay isn't set
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Most we back off between attempts when waiting for a server to appear
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often we wait for acknowledgement when reporting acknowledged bytes
+const ACK_CALLBACK_INTERVAL: usize = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CoreError {
+    #[error("Transfer was cancelled")]
+    Cancelled,
+    #[error("Maximum session duration exceeded")]
+    MaxDurationExceeded,
+    #[error(
+        "Incompatible version: peer speaks handshake version {peer}, we need at least {minimum}"
+    )]
+    IncompatibleVersion { peer: u8, minimum: u8 },
+    #[error("Peer requested unsupported features: {0:#010x}")]
+    UnsupportedFeatures(u32),
+    #[error("Peer's wordlist (hash {peer:016x}) differs from ours (hash {ours:016x})")]
+    WordlistMismatch { peer: u64, ours: u64 },
+    #[error("Passphrase expired")]
+    PassphraseExpired,
+    #[error("Peer's fingerprint wasn't confirmed")]
+    PeerNotConfirmed,
+}
+
+/// Current version of the handshake header. Version 1 was a bare flags byte, version 2 had no timestamp, version 3 no
+/// wordlist hash, version 4 no expiry and version 5 no length
+pub const HANDSHAKE_VERSION: u8 = 6;
+
+/// First handshake version to give the length of its fields after the version byte, so a reader can skip fields
+/// added by versions newer than its own
+const LENGTH_PREFIXED_VERSION: u8 = 6;
+
+/// Oldest handshake version we can talk to, the first with a header. Each version since appends a field, which is
+/// unknown when an older peer sends the header without it
+const MIN_HANDSHAKE_VERSION: u8 = 2;
+
+/// Encoded size of a [`Handshake`], a version byte and the length of the fields that follow it: a big endian feature
+/// bitmask, the sender's unix timestamp in seconds, the hash of its wordlist and when its passphrase expires
+const HANDSHAKE_LEN: usize = 30;
+
+/// Length of the fields of a handshake from before LENGTH_PREFIXED_VERSION, which only has the fields its version had
+fn unprefixed_fields_len(version: u8) -> usize {
+    match version {
+        ..=2 => 4,
+        3 => 12,
+        4 => 20,
+        _ => 28,
+    }
+}
+
+/// Feature bit indicating the rest of the stream is zstd compressed
+pub const FEATURE_ZSTD: u32 = 1 << 0;
+
+/// Feature bit indicating a u16 length prefixed file name follows the handshake, naming the data that follows
+pub const FEATURE_FILE_NAME: u32 = 1 << 1;
+
+/// Feature bit indicating a u64 transfer ID and the u64 offset the stream starts at follow the handshake (after any
+/// file name), continuing a transfer an earlier stream was interrupted in. See [`QcatClient::run_resumable`]
+pub const FEATURE_RESUME: u32 = 1 << 2;
+
+/// Feature bit indicating the sent file's u32 Unix mode and u64 modification time (unix seconds) follow the file name,
+/// each 0 when unknown. See [`FileMetadata`]
+pub const FEATURE_METADATA: u32 = 1 << 3;
+
+/// Feature bit indicating the stream carries one segment of a file sent in parallel over several streams: a
+/// [`Segment`] header follows the handshake (after any file name, metadata and resume point). See
+/// [`QcatClient::run_parallel`]
+pub const FEATURE_SEGMENT: u32 = 1 << 4;
+
+/// Feature bit indicating the zstd compressed stream uses a dictionary, whose u64 ID follows the handshake (after
+/// everything else). See [`ZstdDictionary`]
+pub const FEATURE_ZSTD_DICT: u32 = 1 << 5;
+
+/// Every feature this build understands
+pub const SUPPORTED_FEATURES: u32 = FEATURE_ZSTD
+    | FEATURE_FILE_NAME
+    | FEATURE_RESUME
+    | FEATURE_METADATA
+    | FEATURE_SEGMENT
+    | FEATURE_ZSTD_DICT;
+
+/// Header the client sends at the start of each data stream, announcing which optional behaviors it uses. Negotiation
+/// is one-way: the server checks it supports them all without sending features of its own back, so a client using a
+/// feature the server lacks fails the stream rather than falling back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u8,
+    pub features: u32,
+    /// Sender's wall clock time as a unix timestamp in seconds, so the receiver can spot a misconfigured clock. None
+    /// from senders before version 3
+    pub timestamp: Option<u64>,
+    /// Hash of the sender's wordlist, so the receiver can spot peers generating words from a different list. None
+    /// from senders before version 4
+    pub wordlist_hash: Option<u64>,
+    /// Unix timestamp in seconds after which the sender considers the passphrase expired, 0 if it never expires or
+    /// the sender is from before version 5
+    pub expires_at: u64,
+}
+
+impl Handshake {
+    pub fn new(features: u32) -> Self {
+        Self {
+            version: HANDSHAKE_VERSION,
+            features,
+            timestamp: Some(unix_timestamp()),
+            wordlist_hash: Some(crypto::wordlist_hash()),
+            expires_at: 0,
+        }
+    }
+
+    /// Advertise that our passphrase expires at expires_at
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at.map(system_time_to_unix).unwrap_or_default();
+        self
+    }
+
+    pub fn serialize(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = self.version;
+        bytes[1] = (HANDSHAKE_LEN - 2) as u8;
+        bytes[2..6].copy_from_slice(&self.features.to_be_bytes());
+        bytes[6..14].copy_from_slice(&self.timestamp.unwrap_or_default().to_be_bytes());
+        bytes[14..22].copy_from_slice(&self.wordlist_hash.unwrap_or_default().to_be_bytes());
+        bytes[22..].copy_from_slice(&self.expires_at.to_be_bytes());
+        bytes
+    }
+
+    /// Parse the fields of a handshake of version, at least the features. Fields past the end of fields are unknown,
+    /// anything after the fields we know of is from a newer version and ignored. fields must hold the features, which
+    /// read checks
+    fn parse(version: u8, fields: &[u8]) -> Self {
+        let u64_at = |offset: usize| {
+            fields
+                .get(offset..offset + 8)
+                .map(|field| u64::from_be_bytes(field.try_into().expect("field is 8 bytes")))
+        };
+        let mut features = [0u8; 4];
+        features.copy_from_slice(&fields[..4]);
+
+        Self {
+            version,
+            features: u32::from_be_bytes(features),
+            timestamp: u64_at(4),
+            wordlist_hash: u64_at(12),
+            expires_at: u64_at(20).unwrap_or_default(),
+        }
+    }
+
+    /// Whether the passphrase has expired, going by the earlier of the sender's expiry and ours. It has if either
+    /// our clock or the sender's is past it, so a peer with a skewed clock can't stretch the window
+    pub fn is_expired(&self, ours: Option<SystemTime>) -> bool {
+        let expiry = [
+            self.expires_at,
+            ours.map(system_time_to_unix).unwrap_or_default(),
+        ]
+        .into_iter()
+        .filter(|&expires_at| expires_at != 0)
+        .min();
+
+        match expiry {
+            Some(expires_at) => {
+                unix_timestamp() > expires_at
+                    || self
+                        .timestamp
+                        .is_some_and(|timestamp| timestamp > expires_at)
+            }
+            None => false,
+        }
+    }
+
+    /// How far the sender's clock was from ours, ignoring the time the handshake spent in flight. None if the sender
+    /// didn't say
+    pub fn clock_skew(&self) -> Option<Duration> {
+        self.timestamp
+            .map(|timestamp| Duration::from_secs(unix_timestamp().abs_diff(timestamp)))
+    }
+
+    /// Check the client's handshake against what we support, returning the features in use. Features the client uses
+    /// that we don't understand are an error, as we couldn't interpret the rest of the stream
+    pub fn negotiate(&self, supported: u32) -> Result<u32, CoreError> {
+        if self.version < MIN_HANDSHAKE_VERSION {
+            return Err(CoreError::IncompatibleVersion {
+                peer: self.version,
+                minimum: MIN_HANDSHAKE_VERSION,
+            });
+        }
+
+        let unsupported = self.features & !supported;
+        if unsupported != 0 {
+            return Err(CoreError::UnsupportedFeatures(unsupported));
+        }
+
+        Ok(self.features & supported)
+    }
+
+    pub async fn write<W: AsyncWriteExt + Unpin + ?Sized>(
+        &self,
+        stream: &mut W,
+    ) -> std::io::Result<()> {
+        stream.write_all(&self.serialize()).await
+    }
+
+    /// Read a handshake of any version, only as many bytes as that version sent, including fields from versions
+    /// newer than ours that we skip
+    pub async fn read<R: AsyncReadExt + Unpin + ?Sized>(stream: &mut R) -> std::io::Result<Self> {
+        let version = stream.read_u8().await?;
+        let len = if version >= LENGTH_PREFIXED_VERSION {
+            stream.read_u8().await? as usize
+        } else {
+            unprefixed_fields_len(version)
+        };
+        if len < 4 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Handshake of {} bytes is too short for its features", len),
+            ));
+        }
+
+        let mut fields = vec![0u8; len];
+        stream.read_exact(&mut fields).await?;
+        Ok(Self::parse(version, &fields))
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    system_time_to_unix(SystemTime::now())
+}
+
+fn system_time_to_unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Called with the running total of bytes the peer has acknowledged as we send, see [`QcatOptions::on_acknowledged`]
+#[derive(Clone)]
+pub struct AckCallback(Arc<dyn Fn(u64) + Send + Sync>);
+
+impl AckCallback {
+    pub fn new(callback: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    fn call(&self, acknowledged: u64) {
+        (self.0)(acknowledged)
+    }
+}
+
+impl fmt::Debug for AckCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AckCallback")
+    }
+}
+
+/// Asked whether to go ahead with a peer given its cert's fingerprint phrase, see [`QcatOptions::confirm_peer`]. Runs
+/// on the blocking pool, so it's free to wait on the user
+#[derive(Clone)]
+pub struct ConfirmCallback(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl ConfirmCallback {
+    pub fn new(callback: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    async fn call(&self, phrase: String) -> bool {
+        let callback = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || callback(&phrase))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for ConfirmCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfirmCallback")
+    }
+}
+
+/// Tunable options for the QUIC endpoints
+#[derive(Debug, Default, Clone)]
+pub struct QcatOptions {
+    /// Maximum MTU s2n-quic will use/probe for. Defaults to s2n-quic's default if not set
+    pub max_mtu: Option<u16>,
+    /// Server name (SNI) the client sends in its handshake. Defaults to "localhost". A server holding several
+    /// passphrases picks the cert to present by it, and load balancers may route by it. The server's cert is pinned
+    /// whatever the name, so it doesn't affect verification
+    pub server_name: Option<String>,
+    /// Upper bound on how long a run may take. Once elapsed, the transfer is stopped, output is flushed and run
+    /// returns [`CoreError::MaxDurationExceeded`]
+    pub max_duration: Option<Duration>,
+    /// Minimize latency for small interactive writes, analogous to TCP_NODELAY. Every read from the input is flushed
+    /// to the stream immediately rather than waiting to coalesce, and we advertise a 1ms max ack delay
+    pub no_delay: bool,
+    /// If not empty, the server only accepts peers from these networks. Checked after the handshake, in addition to
+    /// cert pinning
+    pub allowed_networks: Vec<IpNet>,
+    /// Client waits for the server to signal it's ready to receive before sending any data
+    pub wait_for_ready: bool,
+    /// In full-duplex mode, keep receiving from the peer after our input hits EOF, until the peer closes. Otherwise
+    /// EOF on our input ends the session
+    pub keep_receiving: bool,
+    /// Client pings the server over a control stream at this interval, logging the round trip time
+    pub ping_interval: Option<Duration>,
+    /// Whether the client compresses the data it sends. The decision is signaled to the server in the handshake
+    pub compression: CompressionMode,
+    /// Dictionary compressed streams are sent with, and that the server expects compressed streams it receives to have
+    /// used. Both ends need the same one
+    pub zstd_dictionary: Option<ZstdDictionary>,
+    /// Client relays its traffic through a local socket that delays and drops datagrams, for testing how we behave
+    /// on a bad network
+    pub network_impairment: Option<NetworkImpairment>,
+    /// Client tunnels its packets through this CONNECT-UDP proxy rather than sending them to the server directly
+    pub masque_proxy: Option<MasqueProxy>,
+    /// DSCP value (0-63) our packets are marked with for QoS, via IP_TOS/IPV6_TCLASS on the UDP socket. s2n-quic
+    /// sets the ECN bits per packet where supported, which on some platforms replaces the socket's traffic class
+    pub dscp: Option<u8>,
+    /// Name the client sends along with its data, i.e. the name of the file being sent
+    pub file_name: Option<String>,
+    /// Attributes of the file being sent the client sends along with its name, for the server to restore on the file
+    /// it saves
+    pub file_metadata: Option<FileMetadata>,
+    /// Server saves data sent with a file name to that (sanitized) name in this directory, rather than our output
+    pub save_named_files: Option<PathBuf>,
+    /// Server blocks a peer IP for block_duration after this many consecutive failed handshakes
+    pub max_failed_attempts: Option<u32>,
+    pub block_duration: Duration,
+    /// Client reports how much of what it sent the server has acknowledged. Counts bytes written to the stream, so
+    /// compressed bytes when compressing. Waiting on acks stalls sending every 1MiB (or every write with no_delay)
+    pub on_acknowledged: Option<AckCallback>,
+    /// Client in duplex mode asks this to confirm the peer's fingerprint phrase after the handshake, before any data
+    /// flows either way, as a check against a man in the middle who has the passphrase. Ends the session if refused
+    pub confirm_peer: Option<ConfirmCallback>,
+    /// Caps the buffers we copy data through, sending and receiving, so memory use stays small and fixed no matter
+    /// how much data goes through. zstd keeps its own window on top of this when compressing
+    pub buffer_size: Option<usize>,
+    /// Flow control window for a whole connection, the most data the peer may send us before we've read it. Only
+    /// worth raising above s2n-quic's default on fat, long pipes, where it needs to cover bandwidth * RTT (i.e.
+    /// ~12.5MB for 1Gbit/s at 100ms). Each connection can buffer up to this much in memory
+    pub receive_window: Option<u64>,
+    /// Client keeps retrying until a server answers rather than failing on the first attempt, i.e. when started
+    /// before the server
+    pub wait_for_server: bool,
+    /// Delay before the first retry with wait_for_server (1s if zero), doubling on each further attempt
+    pub retry_delay: Duration,
+    /// Flow control window for each stream, as with receive_window. A stream is also limited by the connection's
+    /// window, so raise both
+    pub stream_window: Option<u64>,
+    /// Server fails connections from clients whose wordlist differs from ours, rather than just warning
+    pub strict_wordlist: bool,
+    /// Warn when the peer's clock, sent in its handshake, differs from ours by more than this. The server checks the
+    /// client's on each stream, the client waits for the server's ready signal to check the server's
+    pub max_clock_skew: Option<Duration>,
+    /// Passphrase stops working after this time. The server rejects new connections, closing them with
+    /// PASSPHRASE_EXPIRED_ERROR_CODE, and streams whose handshake shows either side's clock past the expiry
+    pub expires_at: Option<SystemTime>,
+    /// In full-duplex mode, most bytes per second we send. Limited independently of rate_down
+    pub rate_up: Option<u64>,
+    /// In full-duplex mode, most bytes per second we receive. Reading slower than the peer sends lets flow control
+    /// push back on it
+    pub rate_down: Option<u64>,
+    /// Send at most this many bytes of our input, then close our side as if it had ended. Shorter input is sent whole
+    pub send_limit: Option<u64>,
+    /// Receive at most this many bytes of each stream, then stop reading it, i.e. to extract a fixed length payload
+    pub receive_limit: Option<u64>,
+    /// Server closes connections that haven't received any data for this long, whatever QUIC's idle timeout says. Only
+    /// applies to run, the other modes don't track activity
+    pub reap_idle: Option<Duration>,
+    /// How many times run_resumable reconnects and carries on after the connection drops mid-transfer
+    pub reconnect_attempts: u32,
+    /// Testing only. transfer_in_memory holds each write the sender makes back by this long, to check the timing of
+    /// what it sends, i.e. that no_delay gets small writes out promptly
+    pub in_memory_write_delay: Option<Duration>,
+    /// Log throughput each time this many more bytes have been sent (after any compression), to see how it holds up
+    /// over a long transfer
+    pub checkpoint_every: Option<u64>,
+}
+
+/// Bind a UDP socket for s2n-quic to use, marking everything sent on it with the given DSCP value
+fn bind_udp_socket_with_dscp(addr: SocketAddr, dscp: u8) -> std::io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    // DSCP is the upper 6 bits of the traffic class, the lower 2 are ECN
+    let traffic_class = u32::from(dscp) << 2;
+    if addr.is_ipv4() {
+        socket.set_tos(traffic_class)?;
+    } else {
+        set_ipv6_traffic_class(&socket, traffic_class)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos"
+))]
+fn set_ipv6_traffic_class(socket: &Socket, traffic_class: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(traffic_class)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos"
+)))]
+fn set_ipv6_traffic_class(_socket: &Socket, _traffic_class: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "Setting DSCP on IPv6 sockets is not supported on this platform",
+    ))
+}
+
+/// Artificial latency and loss applied to every datagram, in both directions
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkImpairment {
+    pub latency: Duration,
+    /// Percentage of datagrams dropped, 0 to 100
+    pub loss_percent: f64,
+}
+
+impl NetworkImpairment {
+    /// Relay datagrams between our client and the server through local sockets, delaying and dropping them as we go.
+    /// Returns the address the client should connect to in place of the server
+    async fn spawn_relay(self, server_addr: SocketAddr) -> std::io::Result<SocketAddr> {
+        // our client endpoint is bound to an IPv4 address
+        let client_side = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let unspecified: IpAddr = if server_addr.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let server_side = Arc::new(UdpSocket::bind((unspecified, 0)).await?);
+        server_side.connect(server_addr).await?;
+        let relay_addr = client_side.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut client_buf = vec![0u8; u16::MAX as usize];
+            let mut server_buf = vec![0u8; u16::MAX as usize];
+            let mut client_addr = None;
+
+            loop {
+                tokio::select! {
+                    received = client_side.recv_from(&mut client_buf) => {
+                        let Ok((len, from)) = received else { break };
+                        client_addr = Some(from);
+                        self.forward(Arc::clone(&server_side), client_buf[..len].to_vec(), None);
+                    }
+                    received = server_side.recv(&mut server_buf) => {
+                        let Ok(len) = received else { break };
+                        if let Some(client_addr) = client_addr {
+                            self.forward(Arc::clone(&client_side), server_buf[..len].to_vec(), Some(client_addr));
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(relay_addr)
+    }
+
+    /// Send a datagram on after our latency, unless it's randomly dropped. Sent to the socket's connected peer unless
+    /// to is given
+    fn forward(&self, socket: Arc<UdpSocket>, datagram: Vec<u8>, to: Option<SocketAddr>) {
+        if rand::random::<f64>() * 100.0 < self.loss_percent {
+            return;
+        }
+
+        let latency = self.latency;
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            let sent = match to {
+                Some(to) => socket.send_to(&datagram, to).await,
+                None => socket.send(&datagram).await,
+            };
+            if let Err(e) = sent {
+                debug!("Impaired relay failed to forward datagram: {}", e);
+            }
+        });
+    }
+}
+
+impl QcatOptions {
+    /// Size of a buffer we'd otherwise make default bytes, capped by buffer_size
+    fn buffer_size(&self, default: usize) -> usize {
+        self.buffer_size
+            .map_or(default, |buffer_size| buffer_size.min(default))
+    }
+
+    /// Build our IO provider, bound to the given address
+    fn build_io(&self, addr: SocketAddr) -> Result<io::Default, Box<dyn Error>> {
+        let builder = match self.dscp {
+            Some(dscp) => {
+                let socket = bind_udp_socket_with_dscp(addr, dscp)?;
+                io::Default::builder()
+                    .with_rx_socket(socket.try_clone()?)?
+                    .with_tx_socket(socket)?
+            }
+            None => io::Default::builder().with_receive_address(addr)?,
+        };
+
+        self.finish_io(builder)
+    }
+
+    /// Build our IO provider on a socket that's already bound, i.e. one passed to us by systemd
+    fn build_io_from_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> Result<io::Default, Box<dyn Error>> {
+        let builder = io::Default::builder()
+            .with_rx_socket(socket.try_clone()?)?
+            .with_tx_socket(socket)?;
+
+        self.finish_io(builder)
+    }
+
+    fn finish_io(&self, mut builder: io::tokio::Builder) -> Result<io::Default, Box<dyn Error>> {
+        if let Some(max_mtu) = self.max_mtu {
+            builder = builder.with_max_mtu(max_mtu)?;
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Build our connection limits
+    fn build_limits(&self) -> Result<Limits, Box<dyn Error>> {
+        let mut limits = Limits::default();
+
+        if self.no_delay {
+            limits = limits.with_max_ack_delay(NO_DELAY_MAX_ACK_DELAY)?;
+        }
+        if let Some(receive_window) = self.receive_window {
+            limits = limits.with_data_window(receive_window)?;
+        }
+        if let Some(stream_window) = self.stream_window {
+            limits = limits
+                .with_bidirectional_local_data_window(stream_window)?
+                .with_bidirectional_remote_data_window(stream_window)?
+                .with_unidirectional_data_window(stream_window)?;
+        }
+
+        Ok(limits)
+    }
+
+    /// Whether a peer at addr is allowed to connect
+    fn peer_is_allowed(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.allowed_networks.is_empty()
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(&ip))
+    }
+
+    /// Stop reading input once we've sent send_limit bytes of it, if set
+    fn limit_send<R: AsyncRead + Unpin>(&self, input: R) -> Take<R> {
+        input.take(self.send_limit.unwrap_or(u64::MAX))
+    }
+
+    /// Stop reading a stream once we've received receive_limit bytes of it, if set
+    fn limit_receive<R: AsyncRead + Unpin>(&self, stream: R) -> Take<R> {
+        stream.take(self.receive_limit.unwrap_or(u64::MAX))
+    }
+
+    /// Whether our passphrase has expired, by our clock
+    fn passphrase_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| SystemTime::now() > expires_at)
+    }
+
+    /// Check a newly accepted connection against our allowed networks and passphrase expiry, closing it if it's turned
+    /// away. Returns the peer's address if it's admitted
+    fn admit(&self, conn: &Connection) -> std::io::Result<Option<SocketAddr>> {
+        let remote_addr = conn.remote_addr()?;
+
+        if !self.peer_is_allowed(&remote_addr) {
+            warn!(
+                "Connection {}: rejecting peer {}, not in an allowed network",
+                conn.id(),
+                remote_addr
+            );
+            conn.close(CONNECTION_REJECTED_ERROR_CODE.into());
+            return Ok(None);
+        }
+
+        if self.passphrase_expired() {
+            warn!(
+                "Connection {}: rejecting peer {}, passphrase expired",
+                conn.id(),
+                remote_addr
+            );
+            conn.close(PASSPHRASE_EXPIRED_ERROR_CODE.into());
+            return Ok(None);
+        }
+
+        Ok(Some(remote_addr))
+    }
+
+    /// When the session started now must end by, if bounded
+    fn deadline(&self) -> Option<Instant> {
+        self.max_duration
+            .map(|max_duration| Instant::now() + max_duration)
+    }
+}
+
+/// Resolves once a run should stop early, either from being cancelled or hitting its deadline
+async fn stop_requested(cancel: &CancellationToken, deadline: Option<Instant>) -> CoreError {
+    match deadline {
+        Some(deadline) => tokio::select! {
+            _ = cancel.cancelled() => CoreError::Cancelled,
+            _ = tokio::time::sleep_until(deadline) => CoreError::MaxDurationExceeded,
+        },
+        None => {
+            cancel.cancelled().await;
+            CoreError::Cancelled
+        }
+    }
+}
 
 /// Server component of qcat
 pub struct QcatServer {
-    server: Server,
+    /// One endpoint per bound address
+    servers: Vec<Server>,
+    options: QcatOptions,
+    cancel: CancellationToken,
+    /// When each connection last received data, for reaping idle ones with reap_idle
+    activity: ActivityMap,
 }
 
 impl QcatServer {
     pub fn new(socket_addr: SocketAddr, config: QcatCryptoConfig) -> Result<Self, Box<dyn Error>> {
-        let tls_config = config.build_server_config()?;
-        // new is deprecated, but there's no option in the alternative (builder) to configure some more advanced rustls
-        // features, like custom cert verifiers. Related issue for how s2n_quic exposes rustls features:
-        // https://github.com/aws/s2n-quic/issues/2178
-        let rustls_server = s2n_quic_rustls::Server::new(tls_config);
-        let server = Server::builder()
-            .with_tls(rustls_server)?
-            .with_io(socket_addr)?
-            .start()?;
+        Self::with_options(socket_addr, config, QcatOptions::default())
+    }
+
+    pub fn with_options(
+        socket_addr: SocketAddr,
+        config: QcatCryptoConfig,
+        options: QcatOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_addresses(&[socket_addr], config, options)
+    }
+
+    /// Bind an endpoint on each of the given addresses (i.e. all addresses a hostname resolves to), accepting
+    /// connections on all of them
+    pub fn with_addresses(
+        socket_addrs: &[SocketAddr],
+        config: QcatCryptoConfig,
+        options: QcatOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        if socket_addrs.is_empty() {
+            return Err("No addresses to bind to".into());
+        }
+
+        let endpoints = socket_addrs
+            .iter()
+            .map(|socket_addr| options.build_io(*socket_addr))
+            .collect::<Result<_, _>>()?;
+        Self::start(endpoints, config, options)
+    }
+
+    /// Create a server listening on sockets that are already bound, i.e. those systemd passes a socket activated
+    /// service (see [`crate::utils::systemd_listen_sockets`]). Any DSCP marking is up to whoever bound them
+    pub fn with_sockets(
+        sockets: Vec<std::net::UdpSocket>,
+        config: QcatCryptoConfig,
+        options: QcatOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        if sockets.is_empty() {
+            return Err("No sockets to listen on".into());
+        }
+
+        let endpoints = sockets
+            .into_iter()
+            .map(|socket| options.build_io_from_socket(socket))
+            .collect::<Result<_, _>>()?;
+        Self::start(endpoints, config, options)
+    }
+
+    /// Start an endpoint on each IO provider
+    fn start(
+        endpoints: Vec<io::Default>,
+        config: QcatCryptoConfig,
+        options: QcatOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        // shared across our endpoints so a peer can't dodge its block by switching addresses
+        let failed_attempts =
+            FailedAttemptLimiter::new(options.max_failed_attempts, options.block_duration);
+
+        let mut servers = Vec::with_capacity(endpoints.len());
+        for endpoint in endpoints {
+            let tls_config = config.build_server_config()?;
+            // from our own rustls config rather than the builder, which can't configure some more advanced rustls
+            // features, like custom cert verifiers. Related issue for how s2n_quic exposes rustls features:
+            // https://github.com/aws/s2n-quic/issues/2178
+            let rustls_server = s2n_quic_rustls::Server::from(tls_config);
+            let server = Server::builder()
+                .with_tls(rustls_server)?
+                .with_io(endpoint)?
+                .with_limits(options.build_limits()?)?
+                .with_endpoint_limits(failed_attempts.endpoint_limits())?
+                .with_event(QcatEventSubscriber::new(failed_attempts.clone()))?
+                .start()?;
+
+            info!("Listening on {}", server.local_addr()?);
+            servers.push(server);
+        }
+
+        Ok(Self {
+            servers,
+            options,
+            cancel: CancellationToken::new(),
+            activity: ActivityMap::default(),
+        })
+    }
+
+    /// Use the given token to stop the server. When cancelled, run flushes the output and returns
+    /// [`CoreError::Cancelled`]
+    pub fn with_cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
 
-        Ok(Self { server })
+    /// The addresses we're listening on, one per endpoint, i.e. to find the ports picked when binding port 0
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.servers.iter().map(Server::local_addr).collect()
     }
 
-    /// Starts the server
+    /// Starts the server, writing everything clients send to output
     pub async fn run<T: AsyncWriteExt + Unpin + Send + 'static>(
         &mut self,
         output: &mut Arc<Mutex<T>>,
     ) -> Result<(), Box<dyn Error>> {
-        while let Some(mut conn) = self.server.accept().await {
-            let output_clone = Arc::clone(output);
-            tokio::spawn(async move {
-                while let Ok(Some(mut stream)) = conn.accept_receive_stream().await {
-                    while let Ok(Some(data)) = stream.receive().await {
-                        let mut output_ref = output_clone.lock().await;
-                        output_ref.write_all(&data).await.unwrap();
+        let options = self.options.clone();
+        let activity = self.activity.clone();
+        let reap_idle = self.options.reap_idle;
+        let result = self
+            .serve("received", reap_idle, |conn| {
+                receive_connection(conn, Arc::clone(output), options.clone(), activity.clone())
+            })
+            .await;
+
+        if let Err(stop) = result {
+            output.lock().await.flush().await?;
+            return Err(stop.into());
+        }
+
+        Ok(())
+    }
+
+    /// Same as run, then shuts output down however the server stopped, so a writer that finishes on shutdown (i.e. an
+    /// EncryptingWriter writing its final chunk) always does. The server only stops on cancellation or max_duration,
+    /// which run returns as an error, so that error is returned once output is shut down
+    pub async fn run_and_shut_down<T: AsyncWriteExt + Unpin + Send + 'static>(
+        &mut self,
+        output: &mut Arc<Mutex<T>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let result = self.run(output).await;
+        let shut_down = output.lock().await.shutdown().await;
+        result?;
+        shut_down?;
+
+        Ok(())
+    }
+
+    /// Starts the server in push mode, sending the file at path to every client that connects
+    pub async fn run_push(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let send_limit = self.options.send_limit;
+        self.serve("sent", None, |conn| {
+            send_file(conn, path.to_owned(), send_limit)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Starts the server in echo mode, writing everything received on each bidirectional stream back to the sender
+    pub async fn run_echo(&mut self) -> Result<(), Box<dyn Error>> {
+        self.serve("both", None, echo_connection).await?;
+
+        Ok(())
+    }
+
+    /// Starts the server as the far end of a TCP tunnel, bridging each bidirectional stream a client opens to a new
+    /// TCP connection to target
+    pub async fn run_tcp_connect(&mut self, target: &str) -> Result<(), Box<dyn Error>> {
+        self.serve("both", None, |conn| {
+            tcp_connect_connection(conn, target.to_owned())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Starts the server as the near end of a reverse tunnel, forwarding each TCP connection accepted on listen_addr
+    /// to a connected client, which bridges it on to its own TCP target
+    pub async fn run_tcp_listen(&mut self, listen_addr: &str) -> Result<(), Box<dyn Error>> {
+        let listener = Arc::new(TcpListener::bind(listen_addr).await?);
+        info!("Forwarding TCP connections on {}", listener.local_addr()?);
+
+        self.serve("both", None, |conn| {
+            tcp_listen_connection(conn, Arc::clone(&listener))
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stream of connections from peers in our allowed networks, for handling them with your own logic rather than one
+    /// of the run methods. Unlike those, this doesn't stop on cancellation or max_duration
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming {
+            servers: &mut self.servers,
+            options: &self.options,
+        }
+    }
+
+    /// Accepts connections, spawning a task running handle_connection for each. Returns an error if we were stopped
+    /// early by cancellation or our deadline. direction (received, sent or both) labels each connection's final log.
+    /// With reap_idle, connections that go that long without handle_connection marking them active in our activity
+    /// map are closed
+    async fn serve<F, Fut>(
+        &mut self,
+        direction: &'static str,
+        reap_idle: Option<Duration>,
+        handle_connection: F,
+    ) -> Result<(), CoreError>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = std::io::Result<u64>> + Send + 'static,
+    {
+        let deadline = self.options.deadline();
+        // child token so we can stop our connection tasks when hitting the deadline without cancelling the caller's
+        // token
+        let connections_cancel = self.cancel.child_token();
+
+        let reaper = reap_idle.map(|max_idle| {
+            info!(
+                "Reaping connections idle for more than {}s",
+                max_idle.as_secs()
+            );
+            tokio::spawn(reaper::reap_idle(self.activity.clone(), max_idle))
+        });
+
+        loop {
+            let conn = tokio::select! {
+                conn = accept_any(&mut self.servers) => match conn {
+                    Some(conn) => conn,
+                    None => break,
+                },
+                stop = stop_requested(&self.cancel, deadline) => {
+                    connections_cancel.cancel();
+                    if let Some(reaper) = &reaper {
+                        reaper.abort();
                     }
+                    return Err(stop);
+                }
+            };
+
+            // s2n-quic's connection id, also used by our event subscriber, so all logs for a connection share an id
+            let id = conn.id();
+            let remote_addr = match self.options.admit(&conn) {
+                Ok(Some(remote_addr)) => remote_addr,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Connection {}: unable to get peer address: {}", id, e);
+                    continue;
                 }
+            };
+
+            info!("Connection {}: accepted from {}", id, remote_addr);
+
+            let handler = handle_connection(conn);
+            // our own token so the reaper can stop just this connection
+            let cancel = connections_cancel.child_token();
+            let activity = reap_idle.map(|_| self.activity.clone());
+            if let Some(activity) = &activity {
+                activity.register(id, cancel.clone());
+            }
+            let accepted_at = Instant::now();
+            METRICS.connection_opened();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = handler => match result {
+                        Ok(bytes) => {
+                            // fields for log based monitoring, carried as key-values alongside the message
+                            info!(
+                                connection = id,
+                                peer:% = remote_addr,
+                                bytes = bytes,
+                                duration_ms = accepted_at.elapsed().as_millis() as u64,
+                                direction = direction;
+                                "Connection {}: closed after transferring {} bytes", id, bytes
+                            );
+                            METRICS.bytes_transferred(bytes);
+                        }
+                        Err(e) => warn!("Connection {}: error handling connection: {}", id, e),
+                    },
+                    _ = cancel.cancelled() => debug!("Connection {}: cancelled", id),
+                }
+                if let Some(activity) = activity {
+                    activity.remove(id);
+                }
+                METRICS.connection_closed();
             });
         }
 
+        if let Some(reaper) = reaper {
+            reaper.abort();
+        }
+
         Ok(())
     }
 }
 
-/// Client component of qcat
-pub struct QcatClient {
-    client: Client,
+/// Accepts the next connection from any of our endpoints, returning None once all of them are closed
+async fn accept_any(servers: &mut [Server]) -> Option<Connection> {
+    std::future::poll_fn(|cx| poll_accept_any(servers, cx)).await
 }
 
-impl QcatClient {
-    pub fn new(config: QcatCryptoConfig) -> Result<Self, Box<dyn Error>> {
-        let tls_config = config.build_client_config()?;
-        // see comment above in Server::new about using Client::new here
-        let rustls_client = s2n_quic_rustls::Client::new(tls_config);
-        let client = Client::builder()
-            .with_tls(rustls_client)?
-            .with_io("0.0.0.0:0")? // TODO: configure this
-            .start()?;
+fn poll_accept_any(servers: &mut [Server], cx: &mut Context<'_>) -> Poll<Option<Connection>> {
+    let mut all_closed = true;
 
-        Ok(Self { client })
+    for server in servers.iter_mut() {
+        match server.poll_accept(cx) {
+            Poll::Ready(Some(conn)) => return Poll::Ready(Some(conn)),
+            Poll::Ready(None) => {}
+            Poll::Pending => all_closed = false,
+        }
     }
 
-    /// Starts the client
-    pub async fn run<T: AsyncReadExt + Unpin + ?Sized>(
-        &mut self,
-        addr: SocketAddr,
-        input: &mut T,
-    ) -> Result<(), Box<dyn Error>> {
-        // TODO: servername?
-        let connect = Connect::new(addr).with_server_name("localhost");
-        let mut conn = self.client.connect(connect).await?;
+    if all_closed {
+        Poll::Ready(None)
+    } else {
+        Poll::Pending
+    }
+}
 
-        conn.keep_alive(true)?;
+/// Stream of connections accepted by a [`QcatServer`], see [`QcatServer::incoming`]
+pub struct Incoming<'a> {
+    servers: &'a mut [Server],
+    options: &'a QcatOptions,
+}
 
-        let mut stream = conn.open_send_stream().await?;
+impl Stream for Incoming<'_> {
+    type Item = std::io::Result<QcatStream>;
 
-        tokio::io::copy(input, &mut stream).await?;
-        stream.close().await?;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-        Ok(())
+        loop {
+            let Some(conn) = ready!(poll_accept_any(this.servers, cx)) else {
+                return Poll::Ready(None);
+            };
+
+            let remote_addr = match this.options.admit(&conn) {
+                Ok(Some(remote_addr)) => remote_addr,
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+
+            return Poll::Ready(Some(Ok(QcatStream { conn, remote_addr })));
+        }
+    }
+}
+
+/// A connection accepted through [`QcatServer::incoming`] or opened with [`QcatClient::open`], to handle however you
+/// like
+pub struct QcatStream {
+    conn: Connection,
+    remote_addr: SocketAddr,
+}
+
+impl QcatStream {
+    pub fn id(&self) -> u64 {
+        self.conn.id()
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// The peer's leaf cert (DER), as pinned in the handshake, i.e. to record with [`crypto::cert_fingerprint`] for
+    /// trust on first use. None if the TLS provider didn't hand it over
+    pub fn peer_cert(&self) -> Option<Vec<u8>> {
+        connection_peer_cert(&self.conn)
+    }
+
+    /// Accept the next bidirectional stream the peer opens (i.e. a --duplex client's), None once the peer closes
+    pub async fn accept_bidirectional_stream(
+        &mut self,
+    ) -> std::io::Result<Option<BidirectionalStream>> {
+        Ok(self.conn.accept_bidirectional_stream().await?)
+    }
+
+    /// Open a bidirectional stream for exchanging whole messages with [`MessageStream::send_message`] and
+    /// [`MessageStream::recv_message`] rather than raw bytes
+    pub async fn open_message_stream(
+        &mut self,
+    ) -> std::io::Result<MessageStream<BidirectionalStream>> {
+        Ok(MessageStream::new(
+            self.conn.open_bidirectional_stream().await?,
+        ))
+    }
+
+    /// Accept the next bidirectional stream the peer opens as a [`MessageStream`], None once the peer closes
+    pub async fn accept_message_stream(
+        &mut self,
+    ) -> std::io::Result<Option<MessageStream<BidirectionalStream>>> {
+        Ok(self
+            .accept_bidirectional_stream()
+            .await?
+            .map(MessageStream::new))
+    }
+
+    /// Open a bidirectional stream for sending only, i.e. for an integration that should never read what the peer
+    /// sends. The peer takes it with [`QcatStream::accept_receiver`]
+    pub async fn open_sender(&mut self) -> std::io::Result<QcatSender> {
+        let (_, sender) = split::split(self.conn.open_bidirectional_stream().await?);
+        Ok(sender)
+    }
+
+    /// Accept the next bidirectional stream the peer opens for receiving only, None once the peer closes
+    pub async fn accept_receiver(&mut self) -> std::io::Result<Option<QcatReceiver>> {
+        Ok(self
+            .accept_bidirectional_stream()
+            .await?
+            .map(|stream| split::split(stream).0))
+    }
+
+    /// The underlying s2n-quic connection, for anything else
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+}
+
+/// The peer's leaf cert (DER) on conn, as our event subscriber kept it from the handshake
+fn connection_peer_cert(conn: &Connection) -> Option<Vec<u8>> {
+    conn.query_event_context(|context: &QcatConnectionContext| {
+        context.peer_cert().map(<[u8]>::to_vec)
+    })
+    .ok()
+    .flatten()
+}
+
+/// Warn if the peer's clock, going by its handshake, is further from ours than options allow
+fn warn_on_clock_skew(id: u64, handshake: &Handshake, options: &QcatOptions) {
+    if let (Some(max_clock_skew), Some(skew)) = (options.max_clock_skew, handshake.clock_skew()) {
+        if skew > max_clock_skew {
+            warn!(
+                "Connection {}: peer's clock is {}s off from ours, check both clocks are synchronized",
+                id,
+                skew.as_secs()
+            );
+        }
+    }
+}
+
+/// Writes everything received on a connection's streams to our output, returning the number of bytes received
+async fn receive_connection<T: AsyncWriteExt + Unpin + Send>(
+    conn: Connection,
+    output: Arc<Mutex<T>>,
+    options: QcatOptions,
+    activity: ActivityMap,
+) -> std::io::Result<u64> {
+    let id = conn.id();
+    let mut received = 0;
+    let mut accepted = Some(std::time::Instant::now());
+
+    // data arrives on streams the client opens, bidirectional streams are for control messages (i.e. pings)
+    let (mut handle, acceptor) = conn.split();
+    let (mut control_acceptor, mut data_acceptor) = acceptor.split();
+
+    let handshake = Handshake::new(SUPPORTED_FEATURES).with_expiry(options.expires_at);
+    protocol::send_ready(&mut handle, &handshake)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let control = tokio::spawn(async move {
+        while let Ok(Some(control_stream)) = control_acceptor.accept_bidirectional_stream().await {
+            if let Err(e) = protocol::serve_control(control_stream).await {
+                warn!("Connection {}: control stream error: {}", id, e);
+            }
+        }
+    });
+
+    // segments of a parallel transfer are received side by side, everything else in the order it arrives
+    let mut segments = JoinSet::new();
+    while let Some(stream) = data_acceptor.accept_receive_stream().await? {
+        debug!("Connection {}: accepted stream {}", id, stream.id());
+        let stream = FirstByteReader::since(stream, "first byte received", accepted.take());
+        let stream = ActivityReader::new(stream, activity.clone(), id);
+        received += receive_stream_into(id, stream, &output, &options, Some(&mut segments)).await?;
+    }
+    while let Some(segment) = segments.join_next().await {
+        received += segment.map_err(std::io::Error::other)??;
+    }
+
+    control.abort();
+
+    Ok(received)
+}
+
+/// Longest file name we send or accept, the usual filesystem limit
+const MAX_FILE_NAME_LEN: usize = 255;
+
+async fn write_file_name<W: AsyncWriteExt + Unpin + ?Sized>(
+    stream: &mut W,
+    file_name: &str,
+) -> std::io::Result<()> {
+    if file_name.len() > MAX_FILE_NAME_LEN {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "File name is too long to send",
+        ));
+    }
+
+    stream.write_u16(file_name.len() as u16).await?;
+    stream.write_all(file_name.as_bytes()).await
+}
+
+async fn read_file_name<R: AsyncReadExt + Unpin + ?Sized>(
+    stream: &mut R,
+) -> std::io::Result<String> {
+    let len = stream.read_u16().await? as usize;
+    if len > MAX_FILE_NAME_LEN {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Peer sent an overlong file name",
+        ));
+    }
+
+    let mut file_name = vec![0u8; len];
+    stream.read_exact(&mut file_name).await?;
+
+    String::from_utf8(file_name)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Peer sent a non UTF-8 file name"))
+}
+
+/// Strip a peer provided file name down to a single, plain path component, or None if that isn't possible (i.e.
+/// absolute paths, traversal with .., or names with separators)
+pub fn sanitize_file_name(file_name: &str) -> Option<&str> {
+    if file_name.contains(['/', '\\', '\0']) {
+        return None;
+    }
+
+    let mut components = Path::new(file_name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(file_name),
+        _ => None,
+    }
+}
+
+/// Receives one data stream from a client: its handshake, then its (maybe compressed) data, which is written to our
+/// output or saved under the name the client sent. This doesn't care what carries the stream, so it works over an
+/// in-memory pipe (i.e. tokio::io::duplex) as well as a QUIC receive stream. id only labels our logs. Returns the
+/// number of bytes received
+pub async fn receive_stream<R, T>(
+    id: u64,
+    stream: R,
+    output: &Arc<Mutex<T>>,
+    options: &QcatOptions,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    T: AsyncWriteExt + Unpin + Send,
+{
+    receive_stream_into(id, stream, output, options, None).await
+}
+
+/// receive_stream, but segments of a parallel transfer are received in the background on segments if given, so the
+/// caller can go on to accept the transfer's other streams. Those return 0 here, their bytes are counted by the task
+async fn receive_stream_into<R, T>(
+    id: u64,
+    stream: R,
+    output: &Arc<Mutex<T>>,
+    options: &QcatOptions,
+    segments: Option<&mut JoinSet<std::io::Result<u64>>>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    T: AsyncWriteExt + Unpin + Send,
+{
+    let mut stream = BufReader::with_capacity(options.buffer_size(RECEIVE_BUFFER_SIZE), stream);
+
+    let handshake = Handshake::read(&mut stream).await?;
+    let features = handshake
+        .negotiate(SUPPORTED_FEATURES)
+        .map_err(std::io::Error::other)?;
+
+    if let Some(peer) = handshake
+        .wordlist_hash
+        .filter(|&peer| peer != crypto::wordlist_hash())
+    {
+        let mismatch = CoreError::WordlistMismatch {
+            peer,
+            ours: crypto::wordlist_hash(),
+        };
+        if options.strict_wordlist {
+            return Err(std::io::Error::other(mismatch));
+        }
+        warn!("Connection {}: {}", id, mismatch);
+    }
+
+    if handshake.is_expired(options.expires_at) {
+        return Err(std::io::Error::other(CoreError::PassphraseExpired));
+    }
+
+    warn_on_clock_skew(id, &handshake, options);
+    let file_name = if features & FEATURE_FILE_NAME != 0 {
+        Some(read_file_name(&mut stream).await?)
+    } else {
+        None
+    };
+    let metadata = if features & FEATURE_METADATA != 0 {
+        Some(FileMetadata::read(&mut stream).await?)
+    } else {
+        None
+    };
+    let resume = if features & FEATURE_RESUME != 0 {
+        Some(read_resume_point(id, &mut stream).await?)
+    } else {
+        None
+    };
+    let segment = if features & FEATURE_SEGMENT != 0 {
+        Some(Segment::read(&mut stream).await?)
+    } else {
+        None
+    };
+    let dictionary = if features & FEATURE_ZSTD_DICT != 0 {
+        Some(read_zstd_dictionary(options, &mut stream).await?)
+    } else {
+        None
+    };
+
+    let compressed = features & FEATURE_ZSTD != 0;
+    let stream: Box<dyn AsyncRead + Unpin + Send> = match (compressed, dictionary) {
+        (true, Some(dictionary)) => {
+            debug!(
+                "Connection {}: stream is zstd compressed with a dictionary",
+                id
+            );
+            Box::new(ZstdDecoder::with_dict(stream, dictionary.data())?)
+        }
+        (true, None) => {
+            debug!("Connection {}: stream is zstd compressed", id);
+            Box::new(ZstdDecoder::new(stream))
+        }
+        (false, _) => Box::new(stream),
+    };
+    let stream: Box<dyn AsyncRead + Unpin + Send> = match resume {
+        Some(resume) => Box::new(ResumeCounter::new(stream, resume.transfer_id)),
+        None => stream,
+    };
+    let mut stream = options.limit_receive(stream);
+
+    if let Some(segment) = segment {
+        let Some((file_name, directory)) = file_name.zip(options.save_named_files.as_ref()) else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Peer sent part of a parallel transfer, which we can only save to a named file",
+            ));
+        };
+        let path = named_file_path(directory, &file_name)?;
+        let file = open_segment_file(id, &path, segment).await?;
+        let receive = receive_segment(id, stream, file, path, segment, metadata);
+        return match segments {
+            Some(segments) => {
+                segments.spawn(receive);
+                Ok(0)
+            }
+            None => receive.await,
+        };
+    }
+
+    let received = match (file_name, &options.save_named_files) {
+        (Some(file_name), Some(directory)) => {
+            let path = named_file_path(directory, &file_name)?;
+            info!("Connection {}: saving to {}", id, path.display());
+            // never clobber an existing file with whatever a peer sends us, unless we're carrying on writing it
+            let resuming = resume.is_some_and(|resume| resume.offset > 0);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(!resuming)
+                .append(resuming)
+                .open(&path)
+                .await?;
+            let written = tokio::io::copy(&mut stream, &mut file).await?;
+            file.flush().await?;
+            if let Some(metadata) = metadata {
+                metadata.apply(&file.into_std().await)?;
+            }
+            written
+        }
+        _ => {
+            write_chunks(
+                &mut stream,
+                output,
+                options.buffer_size(RECEIVE_BUFFER_SIZE),
+            )
+            .await?
+        }
+    };
+
+    if let Some(resume) = resume {
+        RESUMABLE_TRANSFERS.finish(resume.transfer_id);
+    }
+
+    Ok(received)
+}
+
+/// Where a named file the peer sent is saved in directory
+fn named_file_path(directory: &Path, file_name: &str) -> std::io::Result<PathBuf> {
+    let file_name = sanitize_file_name(file_name).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Refusing to save to unsafe file name {:?}", file_name),
+        )
+    })?;
+    Ok(directory.join(file_name))
+}
+
+/// Read the ID of the dictionary the peer compressed with, returning ours if it's the same one. Anything else would
+/// decompress into garbage, or not at all
+async fn read_zstd_dictionary<'a, R: AsyncReadExt + Unpin + ?Sized>(
+    options: &'a QcatOptions,
+    stream: &mut R,
+) -> std::io::Result<&'a ZstdDictionary> {
+    let peer_id = stream.read_u64().await?;
+    match &options.zstd_dictionary {
+        Some(dictionary) if dictionary.id() == peer_id => Ok(dictionary),
+        Some(_) => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Peer compressed with a different zstd dictionary than ours",
+        )),
+        None => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Peer compressed with a zstd dictionary, give the same one with --zstd-dict",
+        )),
+    }
+}
+
+/// One part of a file sent in parallel over several streams, see FEATURE_SEGMENT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    /// Shared by every segment of the file
+    transfer_id: u64,
+    /// Where in the file this segment goes
+    offset: u64,
+    len: u64,
+    /// Length of the whole file
+    total_len: u64,
+    /// SHA-256 of the whole file, to check it once reassembled
+    digest: [u8; 32],
+}
+
+impl Segment {
+    async fn write<W: AsyncWriteExt + Unpin + ?Sized>(
+        &self,
+        stream: &mut W,
+    ) -> std::io::Result<()> {
+        stream.write_u64(self.transfer_id).await?;
+        stream.write_u64(self.offset).await?;
+        stream.write_u64(self.len).await?;
+        stream.write_u64(self.total_len).await?;
+        stream.write_all(&self.digest).await
+    }
+
+    async fn read<R: AsyncReadExt + Unpin + ?Sized>(stream: &mut R) -> std::io::Result<Self> {
+        let mut segment = Self {
+            transfer_id: stream.read_u64().await?,
+            offset: stream.read_u64().await?,
+            len: stream.read_u64().await?,
+            total_len: stream.read_u64().await?,
+            digest: [0; 32],
+        };
+        stream.read_exact(&mut segment.digest).await?;
+
+        let end = segment.offset.checked_add(segment.len);
+        if !matches!(end, Some(end) if end <= segment.total_len) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Peer sent a segment that doesn't fit in its file",
+            ));
+        }
+        Ok(segment)
+    }
+}
+
+/// Open the file a segment is written into. The transfer's first segment creates it, never clobbering an existing
+/// file, the rest open what it created
+async fn open_segment_file(id: u64, path: &Path, segment: Segment) -> std::io::Result<File> {
+    let first = PARALLEL_TRANSFERS.start(segment.transfer_id);
+    if first {
+        info!(
+            "Connection {}: saving parallel transfer to {}",
+            id,
+            path.display()
+        );
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(first)
+        .open(path)
+        .await
+        .inspect_err(|_| PARALLEL_TRANSFERS.abandon(segment.transfer_id))
+}
+
+/// Write one segment of a parallel transfer into place in its file. Whichever segment completes the transfer checks
+/// the reassembled file against the digest the peer sent, and restores any metadata
+async fn receive_segment<R: AsyncRead + Unpin>(
+    id: u64,
+    mut stream: R,
+    mut file: File,
+    path: PathBuf,
+    segment: Segment,
+    metadata: Option<FileMetadata>,
+) -> std::io::Result<u64> {
+    let write = async {
+        file.seek(SeekFrom::Start(segment.offset)).await?;
+        let written = tokio::io::copy(&mut stream, &mut file).await?;
+        file.flush().await?;
+        if written != segment.len {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "Segment at byte {} ended after {} of its {} bytes",
+                    segment.offset, written, segment.len
+                ),
+            ));
+        }
+        Ok(written)
+    };
+    let written = write
+        .await
+        .inspect_err(|_| PARALLEL_TRANSFERS.abandon(segment.transfer_id))?;
+
+    if !PARALLEL_TRANSFERS.add(segment.transfer_id, written, segment.total_len) {
+        return Ok(written);
+    }
+
+    let (len, digest) = parallel::file_digest(&path).await?;
+    if len != segment.total_len || digest != segment.digest {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} doesn't match what the peer sent once reassembled, don't trust it",
+                path.display()
+            ),
+        ));
+    }
+    if let Some(metadata) = metadata {
+        metadata.apply(&std::fs::OpenOptions::new().write(true).open(&path)?)?;
+    }
+    info!(
+        "Connection {}: reassembled {} ({} bytes) and checked its digest",
+        id,
+        path.display(),
+        len
+    );
+
+    Ok(written)
+}
+
+/// File attributes sent along with a file's name so they can be restored on the copy, see FEATURE_METADATA. Either
+/// can be missing, i.e. there's no Unix mode on Windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Permission bits, as in st_mode
+    pub mode: Option<u32>,
+    /// Modification time, to the second
+    pub modified: Option<SystemTime>,
+}
+
+impl FileMetadata {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Self {
+            mode,
+            modified: metadata.modified().ok(),
+        }
+    }
+
+    async fn write<W: AsyncWriteExt + Unpin + ?Sized>(
+        &self,
+        stream: &mut W,
+    ) -> std::io::Result<()> {
+        stream.write_u32(self.mode.unwrap_or_default()).await?;
+        stream
+            .write_u64(self.modified.map(system_time_to_unix).unwrap_or_default())
+            .await
+    }
+
+    async fn read<R: AsyncReadExt + Unpin + ?Sized>(stream: &mut R) -> std::io::Result<Self> {
+        let mode = stream.read_u32().await?;
+        let modified = stream.read_u64().await?;
+        Ok(Self {
+            mode: (mode != 0).then_some(mode),
+            modified: (modified != 0).then(|| UNIX_EPOCH + Duration::from_secs(modified)),
+        })
+    }
+
+    /// Restore these attributes on file, skipping any this platform doesn't support
+    fn apply(&self, file: &std::fs::File) -> std::io::Result<()> {
+        if let Some(mode) = self.mode {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(std::fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+            #[cfg(not(unix))]
+            debug!("Ignoring file mode {:o}, not supported here", mode);
+        }
+        if let Some(modified) = self.modified {
+            file.set_modified(modified)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a resumable transfer's stream starts, see FEATURE_RESUME
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ResumePoint {
+    transfer_id: u64,
+    offset: u64,
+}
+
+/// Read the resume point following a handshake, failing unless it starts where we left the transfer, so nothing is
+/// lost or written twice
+async fn read_resume_point<R: AsyncReadExt + Unpin>(
+    id: u64,
+    stream: &mut R,
+) -> std::io::Result<ResumePoint> {
+    let resume = ResumePoint {
+        transfer_id: stream.read_u64().await?,
+        offset: stream.read_u64().await?,
+    };
+
+    let received = RESUMABLE_TRANSFERS.received(resume.transfer_id);
+    if resume.offset != received {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Peer resumed transfer {:016x} at byte {}, but we've received {} bytes of it",
+                resume.transfer_id, resume.offset, received
+            ),
+        ));
+    }
+    if resume.offset > 0 {
+        info!(
+            "Connection {}: resuming transfer {:016x} at byte {}",
+            id, resume.transfer_id, resume.offset
+        );
+    }
+
+    Ok(resume)
+}
+
+/// Writes everything read from input to our shared output, only holding the output lock per chunk so concurrent
+/// connections can interleave. Returns the number of bytes written
+async fn write_chunks<R: AsyncReadExt + Unpin, T: AsyncWriteExt + Unpin + Send>(
+    input: &mut R,
+    output: &Arc<Mutex<T>>,
+    buffer_size: usize,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; buffer_size];
+    let mut written = 0;
+
+    loop {
+        let read = input.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(written);
+        }
+
+        output.lock().await.write_all(&buf[..read]).await?;
+        written += read as u64;
+    }
+}
+
+/// Echoes everything received on each of the connection's bidirectional streams back on the same stream, returning
+/// the number of bytes echoed
+async fn echo_connection(mut conn: Connection) -> std::io::Result<u64> {
+    let mut echoed = 0;
+
+    while let Some(stream) = conn.accept_bidirectional_stream().await? {
+        debug!("Connection {}: echoing stream {}", conn.id(), stream.id());
+        // copy reads and writes concurrently, so we keep draining the receive side while the peer is flow control
+        // blocked on reading our echoes
+        let (mut receive_stream, mut send_stream) = stream.split();
+        echoed += tokio::io::copy(&mut receive_stream, &mut send_stream).await?;
+        send_stream.close().await?;
+    }
+
+    Ok(echoed)
+}
+
+/// Bridges each of the connection's bidirectional streams to a new TCP connection to target, returning the number of
+/// bytes bridged in both directions
+async fn tcp_connect_connection(mut conn: Connection, target: String) -> std::io::Result<u64> {
+    let id = conn.id();
+    let mut bridges = JoinSet::new();
+
+    while let Some(stream) = conn.accept_bidirectional_stream().await? {
+        debug!(
+            "Connection {}: bridging stream {} to {}",
+            id,
+            stream.id(),
+            target
+        );
+        let target = target.clone();
+        bridges.spawn(async move { bridge_tcp(stream, TcpStream::connect(target).await?).await });
+    }
+
+    Ok(join_bridges(id, bridges).await)
+}
+
+/// Forwards each TCP connection accepted on listener over a new bidirectional stream, until the peer closes the
+/// connection. Returns the number of bytes bridged in both directions
+async fn tcp_listen_connection(
+    conn: Connection,
+    listener: Arc<TcpListener>,
+) -> std::io::Result<u64> {
+    let id = conn.id();
+    let (mut handle, mut acceptor) = conn.split();
+    let mut bridges = JoinSet::new();
+
+    loop {
+        let (tcp_stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // the peer never opens streams of its own in a reverse tunnel, so this only returns once it's gone
+            _ = acceptor.accept() => break,
+        };
+
+        let stream = handle
+            .open_bidirectional_stream()
+            .await
+            .map_err(std::io::Error::from)?;
+        debug!(
+            "Connection {}: forwarding TCP connection from {} over stream {}",
+            id,
+            peer_addr,
+            stream.id()
+        );
+        bridges.spawn(bridge_tcp(stream, tcp_stream));
+    }
+
+    Ok(join_bridges(id, bridges).await)
+}
+
+/// Copies between a stream and a TCP connection until both directions are done, returning the bytes bridged
+async fn bridge_tcp(
+    mut stream: BidirectionalStream,
+    mut tcp_stream: TcpStream,
+) -> std::io::Result<u64> {
+    let (sent, received) = tokio::io::copy_bidirectional(&mut stream, &mut tcp_stream).await?;
+
+    Ok(sent + received)
+}
+
+/// Waits for every bridge to finish, logging failures rather than failing the whole connection over one TCP connection
+async fn join_bridges(id: u64, mut bridges: JoinSet<std::io::Result<u64>>) -> u64 {
+    let mut bridged = 0;
+
+    while let Some(result) = bridges.join_next().await {
+        match result {
+            Ok(Ok(bytes)) => bridged += bytes,
+            Ok(Err(e)) => warn!("Connection {}: TCP bridge error: {}", id, e),
+            Err(e) => warn!("Connection {}: TCP bridge task failed: {}", id, e),
+        }
+    }
+
+    bridged
+}
+
+/// Sends the file at path (at most send_limit bytes of it) to the client over a new stream, returning the number of bytes
+/// sent
+async fn send_file(
+    mut conn: Connection,
+    path: PathBuf,
+    send_limit: Option<u64>,
+) -> std::io::Result<u64> {
+    let file = File::open(&path).await?;
+    let mut stream = conn.open_send_stream().await?;
+
+    let sent = tokio::io::copy(&mut file.take(send_limit.unwrap_or(u64::MAX)), &mut stream).await?;
+    stream.close().await?;
+
+    Ok(sent)
+}
+
+/// Client component of qcat
+pub struct QcatClient {
+    client: Client,
+    options: QcatOptions,
+    cancel: CancellationToken,
+}
+
+impl QcatClient {
+    pub fn new(config: QcatCryptoConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_options(config, QcatOptions::default())
+    }
+
+    pub fn with_options(
+        config: QcatCryptoConfig,
+        options: QcatOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        // a bucket refilling at 0 bytes per second never has a byte to give
+        if options.rate_up == Some(0) || options.rate_down == Some(0) {
+            return Err("Rate limits must be at least 1 byte per second".into());
+        }
+
+        let tls_config = config.build_client_config()?;
+        // see comment above in Server::new about building from our own config
+        let rustls_client = s2n_quic_rustls::Client::from(tls_config);
+        let client = Client::builder()
+            .with_tls(rustls_client)?
+            .with_io(options.build_io("0.0.0.0:0".parse()?)?)? // TODO: configure this
+            .with_limits(options.build_limits()?)?
+            .with_event(QcatEventSubscriber::default())?
+            .start()?;
+
+        Ok(Self {
+            client,
+            options,
+            cancel: CancellationToken::new(),
+        })
+    }
+
+    /// Use the given token to stop the client. When cancelled, run flushes what has been sent so far, closes the
+    /// stream and returns [`CoreError::Cancelled`]
+    pub fn with_cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Starts the client, sending input to the server
+    pub async fn run<T: AsyncReadExt + Unpin + ?Sized>(
+        &mut self,
+        addr: SocketAddr,
+        input: &mut T,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = self.options.deadline();
+        let mut conn = self.connect(addr, deadline).await?;
+        let connected = std::time::Instant::now();
+
+        if self.options.wait_for_ready || self.options.max_clock_skew.is_some() {
+            let handshake = protocol::wait_for_ready(&mut conn).await?;
+            if self.options.wait_for_ready {
+                info!("Connection {}: server ready, starting transfer", conn.id());
+            }
+            match handshake {
+                Some(handshake) => warn_on_clock_skew(conn.id(), &handshake, &self.options),
+                None => debug!("Connection {}: server didn't send its clock", conn.id()),
+            }
+        }
+
+        let pinger = match self.options.ping_interval {
+            Some(interval) => {
+                let control_stream = conn.open_bidirectional_stream().await?;
+                Some(tokio::spawn(async move {
+                    if let Err(e) = protocol::ping_loop(control_stream, interval).await {
+                        warn!("Ping failed: {}", e);
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        let mut stream = FirstAckWriter::new(
+            conn.open_send_stream().await?,
+            "first byte acknowledged",
+            connected,
+        );
+
+        let send = send_stream(&self.options, input, &mut stream);
+
+        let stopped = tokio::select! {
+            result = send => {
+                result?;
+                None
+            }
+            stop = stop_requested(&self.cancel, deadline) => Some(stop),
+        };
+
+        stream.flush().await?;
+        stream.shutdown().await?;
+
+        if let Some(pinger) = pinger {
+            pinger.abort();
+        }
+
+        if let Some(stop) = stopped {
+            return Err(stop.into());
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the server without running a transfer, for applications that drive the connection themselves. The
+    /// handshake has pinned the server's cert by the time this returns, see [`QcatStream::peer_cert`]
+    pub async fn open(&mut self, addr: SocketAddr) -> Result<QcatStream, Box<dyn Error>> {
+        let conn = self.connect(addr, None).await?;
+        Ok(QcatStream {
+            conn,
+            remote_addr: addr,
+        })
+    }
+
+    /// Like run, but for a seekable input (i.e. a file) that survives the connection dropping. If sending fails we
+    /// reconnect, ask the server how much of the transfer it received and carry on from there, up to
+    /// reconnect_attempts times. stdin can't seek back, so use run for it
+    pub async fn run_resumable<F>(
+        &mut self,
+        addr: SocketAddr,
+        input: &mut F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: AsyncRead + AsyncSeek + Unpin,
+    {
+        let deadline = self.options.deadline();
+        let transfer_id = rand::random::<u64>();
+        let mut reconnects = 0;
+
+        loop {
+            let mut conn = self.connect(addr, deadline).await?;
+            let id = conn.id();
+            let offset = if reconnects == 0 {
+                0
+            } else {
+                protocol::query_resume_offset(&mut conn, transfer_id).await?
+            };
+            input.seek(SeekFrom::Start(offset)).await?;
+            if offset > 0 {
+                info!("Connection {}: resuming transfer at byte {}", id, offset);
+            }
+
+            let send = async {
+                let mut stream = conn.open_send_stream().await?;
+                let resume = ResumePoint {
+                    transfer_id,
+                    offset,
+                };
+                send_stream_from(&self.options, input, &mut stream, Some(resume), None).await?;
+                stream.close().await?;
+                Ok::<_, std::io::Error>(())
+            };
+
+            let result = tokio::select! {
+                result = send => result,
+                stop = stop_requested(&self.cancel, deadline) => return Err(stop.into()),
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if reconnects < self.options.reconnect_attempts => {
+                    reconnects += 1;
+                    warn!(
+                        "Connection {}: transfer interrupted ({}), reconnecting ({}/{})",
+                        id, e, reconnects, self.options.reconnect_attempts
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Send the file at path split into streams segments sent side by side over as many streams, for links a single
+    /// stream can't fill. The server writes each segment into place and checks the reassembled file's digest, so it
+    /// needs somewhere to save named files. The last segment takes up any remainder
+    pub async fn run_parallel(
+        &mut self,
+        addr: SocketAddr,
+        path: &Path,
+        streams: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = self.options.deadline();
+        let (total_len, digest) = parallel::file_digest(path).await?;
+        let segment_len = total_len.div_ceil(streams.max(1)).max(1);
+        let transfer_id = rand::random::<u64>();
+
+        let conn = self.connect(addr, deadline).await?;
+        let mut sends = JoinSet::new();
+        // an empty file is still sent, as a single empty segment
+        let mut offset = 0;
+        loop {
+            let segment = Segment {
+                transfer_id,
+                offset,
+                len: segment_len.min(total_len - offset),
+                total_len,
+                digest,
+            };
+            let mut handle = conn.handle();
+            let options = self.options.clone();
+            let path = path.to_owned();
+            sends.spawn(async move {
+                let mut file = File::open(&path).await?;
+                file.seek(SeekFrom::Start(segment.offset)).await?;
+                let mut input = file.take(segment.len);
+                let mut stream = handle.open_send_stream().await?;
+                send_stream_from(&options, &mut input, &mut stream, None, Some(segment)).await?;
+                stream.close().await?;
+                Ok::<_, std::io::Error>(())
+            });
+
+            offset += segment.len;
+            if offset >= total_len {
+                break;
+            }
+        }
+        debug!(
+            "Connection {}: sending {} bytes as {} segments",
+            conn.id(),
+            total_len,
+            sends.len()
+        );
+
+        let send_all = async {
+            while let Some(sent) = sends.join_next().await {
+                sent.map_err(std::io::Error::other)??;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        tokio::select! {
+            result = send_all => result?,
+            stop = stop_requested(&self.cancel, deadline) => return Err(stop.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Starts the client in full-duplex mode over a single bidirectional stream, sending input to the server while
+    /// writing anything the server sends back (i.e. a server in echo mode) to output
+    pub async fn run_duplex<R, W>(
+        &mut self,
+        addr: SocketAddr,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        R: AsyncReadExt + Unpin + ?Sized,
+        W: AsyncWriteExt + Unpin + ?Sized,
+    {
+        let deadline = self.options.deadline();
+        let mut conn = self.connect(addr, deadline).await?;
+
+        if let Some(confirm_peer) = &self.options.confirm_peer {
+            let phrase = connection_peer_cert(&conn)
+                .and_then(|cert| crypto::cert_fingerprint_phrase(&cert))
+                .ok_or("Unable to read the peer's cert to confirm its fingerprint")?;
+            if !confirm_peer.call(phrase).await {
+                return Err(CoreError::PeerNotConfirmed.into());
+            }
+            info!("Connection {}: peer's fingerprint confirmed", conn.id());
+        }
+
+        let (mut receive_stream, mut send_stream) = conn.open_bidirectional_stream().await?.split();
+
+        // unless we keep receiving, hitting EOF on our input tears down the session
+        let input_finished = CancellationToken::new();
+
+        let send = async {
+            let mut input =
+                RateLimitedReader::new(self.options.limit_send(input), self.options.rate_up);
+            send_input(&self.options, &mut input, &mut send_stream).await?;
+            send_stream.close().await?;
+            input_finished.cancel();
+            Ok::<_, std::io::Error>(())
+        };
+        let receive = async {
+            let mut receive_stream = RateLimitedReader::new(
+                self.options.limit_receive(&mut receive_stream),
+                self.options.rate_down,
+            );
+            tokio::select! {
+                result = tokio::io::copy(&mut receive_stream, output) => {
+                    result?;
+                }
+                _ = input_finished.cancelled(), if !self.options.keep_receiving => {
+                    debug!("Input finished, no longer receiving");
+                }
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let stopped = tokio::select! {
+            result = async { tokio::try_join!(send, receive) } => {
+                result?;
+                None
+            }
+            stop = stop_requested(&self.cancel, deadline) => Some(stop),
+        };
+
+        output.flush().await?;
+
+        if let Some(stop) = stopped {
+            return Err(stop.into());
+        }
+
+        Ok(())
+    }
+
+    /// Starts the client in request/response mode over a single bidirectional stream: sends all of input, closes our
+    /// side of the stream, then writes the server's whole reply to output until it closes its side. Unlike run_duplex
+    /// nothing is received until the request is sent, for HTTP-like exchanges (i.e. with a --tcp-connect server)
+    pub async fn run_request<R, W>(
+        &mut self,
+        addr: SocketAddr,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        R: AsyncReadExt + Unpin + ?Sized,
+        W: AsyncWriteExt + Unpin + ?Sized,
+    {
+        let deadline = self.options.deadline();
+        let mut conn = self.connect(addr, deadline).await?;
+
+        let (mut receive_stream, mut send_stream) = conn.open_bidirectional_stream().await?.split();
+
+        let exchange = async {
+            let sent = send_input(
+                &self.options,
+                &mut self.options.limit_send(input),
+                &mut send_stream,
+            )
+            .await?;
+            send_stream.close().await?;
+            debug!("Sent {} byte request, waiting for the reply", sent);
+
+            let received =
+                tokio::io::copy(&mut self.options.limit_receive(&mut receive_stream), output)
+                    .await?;
+            debug!("Received {} byte reply", received);
+            Ok::<_, std::io::Error>(())
+        };
+
+        let stopped = tokio::select! {
+            result = exchange => {
+                result?;
+                None
+            }
+            stop = stop_requested(&self.cancel, deadline) => Some(stop),
+        };
+
+        output.flush().await?;
+
+        if let Some(stop) = stopped {
+            return Err(stop.into());
+        }
+
+        Ok(())
+    }
+
+    /// Relay everything written to the returned pipe to the server at addr, i.e. for a server to forward what it
+    /// receives onward. The returned future sends until the pipe is shut down, pipe_size bytes are buffered between
+    pub fn forward(
+        mut self,
+        addr: SocketAddr,
+        pipe_size: usize,
+    ) -> (
+        DuplexStream,
+        impl Future<Output = Result<(), Box<dyn Error>>>,
+    ) {
+        let (output, mut input) = tokio::io::duplex(pipe_size);
+        (output, async move { self.run(addr, &mut input).await })
+    }
+
+    /// Starts the client in receive mode, writing what the server sends (i.e. a server in push mode) to output
+    pub async fn run_receive<T: AsyncWriteExt + Unpin + ?Sized>(
+        &mut self,
+        addr: SocketAddr,
+        output: &mut T,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = self.options.deadline();
+        let mut conn = self.connect(addr, deadline).await?;
+
+        let receive = async {
+            if let Some(stream) = conn.accept_receive_stream().await? {
+                let mut stream = self
+                    .options
+                    .limit_receive(FirstByteReader::new(stream, "first byte"));
+                tokio::io::copy(&mut stream, output).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let stopped = tokio::select! {
+            result = receive => {
+                result?;
+                None
+            }
+            stop = stop_requested(&self.cancel, deadline) => Some(stop),
+        };
+
+        output.flush().await?;
+
+        if let Some(stop) = stopped {
+            return Err(stop.into());
+        }
+
+        Ok(())
+    }
+
+    /// Starts the client as the far end of a reverse tunnel, bridging each bidirectional stream the server opens
+    /// (i.e. for each TCP connection to its --tcp-listen address) to a new TCP connection to target
+    pub async fn run_tcp_connect(
+        &mut self,
+        addr: SocketAddr,
+        target: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = self.options.deadline();
+        let conn = self.connect(addr, deadline).await?;
+
+        tokio::select! {
+            result = tcp_connect_connection(conn, target.to_owned()) => {
+                info!("Tunnel closed after bridging {} bytes", result?);
+            }
+            stop = stop_requested(&self.cancel, deadline) => return Err(stop.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the server. deadline only bounds waiting for it with wait_for_server, a single attempt gives up on
+    /// its own
+    async fn connect(
+        &mut self,
+        addr: SocketAddr,
+        deadline: Option<Instant>,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let server_name = self.options.server_name.as_deref().unwrap_or("localhost");
+        let connect_addr = match &self.options.masque_proxy {
+            Some(proxy) => {
+                let relay_addr = proxy.spawn_relay(addr).await?;
+                info!(
+                    "Tunneling through MASQUE proxy {} via {}",
+                    proxy, relay_addr
+                );
+                relay_addr
+            }
+            None => addr,
+        };
+        let connect_addr = match self.options.network_impairment {
+            Some(impairment) => {
+                let relay_addr = impairment.spawn_relay(connect_addr).await?;
+                warn!(
+                    "Simulating {}ms latency and {}% loss via {}",
+                    impairment.latency.as_millis(),
+                    impairment.loss_percent,
+                    relay_addr
+                );
+                relay_addr
+            }
+            None => connect_addr,
+        };
+        let connect = Connect::new(connect_addr).with_server_name(server_name);
+        let handshake_start = Instant::now();
+        let mut conn = if self.options.wait_for_server {
+            self.connect_when_available(connect, addr, deadline).await?
+        } else {
+            self.client.connect(connect).await?
+        };
+        TIMINGS.record("QUIC handshake", handshake_start.elapsed());
+        info!("Connection {}: connected to {}", conn.id(), addr);
+
+        conn.keep_alive(true)?;
+
+        Ok(conn)
+    }
+
+    /// Keep trying to connect until the server answers, backing off from retry_delay up to MAX_RETRY_DELAY between
+    /// attempts. Stops early if cancelled or once deadline passes
+    async fn connect_when_available(
+        &mut self,
+        connect: Connect,
+        addr: SocketAddr,
+        deadline: Option<Instant>,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let mut delay = if self.options.retry_delay.is_zero() {
+            DEFAULT_RETRY_DELAY
+        } else {
+            self.options.retry_delay
+        };
+
+        loop {
+            let error = match self.client.connect(connect.clone()).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => e,
+            };
+            info!(
+                "Waiting for a server at {} ({}), retrying in {:.1}s",
+                addr,
+                error,
+                delay.as_secs_f64()
+            );
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                stop = stop_requested(&self.cancel, deadline) => return Err(stop.into()),
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY.max(self.options.retry_delay));
+        }
+    }
+}
+
+/// Sends input as one data stream to the server: our handshake, then the (maybe compressed) input. Like
+/// receive_stream, this works over any transport, i.e. an in-memory pipe as well as a QUIC send stream. The caller
+/// closes the stream. Returns the number of bytes sent
+pub async fn send_stream<R, W>(
+    options: &QcatOptions,
+    input: &mut R,
+    stream: &mut W,
+) -> std::io::Result<u64>
+where
+    R: AsyncReadExt + Unpin + ?Sized,
+    W: AsyncWriteExt + Unpin,
+{
+    send_stream_from(options, input, stream, None, None).await
+}
+
+/// send_stream, continuing a resumable transfer from resume's offset if given, or sending input as one segment of a
+/// parallel transfer. input must already be at the offset either starts at
+async fn send_stream_from<R, W>(
+    options: &QcatOptions,
+    input: &mut R,
+    stream: &mut W,
+    resume: Option<ResumePoint>,
+    segment: Option<Segment>,
+) -> std::io::Result<u64>
+where
+    R: AsyncReadExt + Unpin + ?Sized,
+    W: AsyncWriteExt + Unpin,
+{
+    let mut input = options.limit_send(input);
+    // only wait on a sample when we need one to decide, so interactive input goes out as it's typed
+    let mut sample = Vec::new();
+    if options.compression == CompressionMode::Auto {
+        let sample_size = options.buffer_size(compression::SAMPLE_SIZE);
+        (&mut input)
+            .take(sample_size as u64)
+            .read_to_end(&mut sample)
+            .await?;
+    }
+    let compress = options.compression.should_compress(&sample);
+
+    let mut features = 0;
+    if compress {
+        debug!("Compressing stream with zstd");
+        features |= FEATURE_ZSTD;
+    }
+    if options.file_name.is_some() {
+        features |= FEATURE_FILE_NAME;
+    }
+    if options.file_name.is_some() && options.file_metadata.is_some() {
+        features |= FEATURE_METADATA;
+    }
+    if resume.is_some() {
+        features |= FEATURE_RESUME;
+    }
+    if segment.is_some() {
+        features |= FEATURE_SEGMENT;
+    }
+    let dictionary = options.zstd_dictionary.as_ref().filter(|_| compress);
+    if dictionary.is_some() {
+        features |= FEATURE_ZSTD_DICT;
+    }
+    Handshake::new(features)
+        .with_expiry(options.expires_at)
+        .write(stream)
+        .await?;
+
+    if let Some(file_name) = &options.file_name {
+        write_file_name(stream, file_name).await?;
+        if let Some(metadata) = &options.file_metadata {
+            metadata.write(stream).await?;
+        }
+    }
+    if let Some(resume) = resume {
+        stream.write_u64(resume.transfer_id).await?;
+        stream.write_u64(resume.offset).await?;
+    }
+    if let Some(segment) = segment {
+        segment.write(stream).await?;
+    }
+    if let Some(dictionary) = dictionary {
+        stream.write_u64(dictionary.id()).await?;
+    }
+
+    let mut input = sample.as_slice().chain(input);
+    if compress {
+        let input = BufReader::with_capacity(options.buffer_size(RECEIVE_BUFFER_SIZE), input);
+        let mut encoder = match dictionary {
+            Some(dictionary) => ZstdEncoder::with_dict(input, Level::Default, dictionary.data())?,
+            None => ZstdEncoder::new(input),
+        };
+        send_input(options, &mut encoder, stream).await
+    } else {
+        send_input(options, &mut input, stream).await
+    }
+}
+
+/// Runs a whole transfer over an in-memory pipe rather than QUIC, sending input with send_stream and receiving it
+/// into output with receive_stream. Everything above the QUIC layer (handshake, file names, compression) runs as it
+/// would over the network, deterministically and without any sockets, which makes it handy for testing. Returns the
+/// number of bytes received
+pub async fn transfer_in_memory<R, T>(
+    options: &QcatOptions,
+    input: &mut R,
+    output: &Arc<Mutex<T>>,
+) -> std::io::Result<u64>
+where
+    R: AsyncReadExt + Unpin + ?Sized,
+    T: AsyncWriteExt + Unpin + Send,
+{
+    let (client_side, server_side) = tokio::io::duplex(IN_MEMORY_PIPE_SIZE);
+    let mut client_side = DelayedWriter::new(client_side, options.in_memory_write_delay);
+
+    let send = async {
+        send_stream(options, input, &mut client_side).await?;
+        // our stand in for closing the QUIC stream, the receiver sees EOF
+        client_side.shutdown().await
+    };
+    let (_, received) = tokio::try_join!(send, receive_stream(0, server_side, output, options))?;
+
+    Ok(received)
+}
+
+/// Sends input on the stream, honoring our no_delay option. Returns the number of bytes sent
+async fn send_input<R: AsyncReadExt + Unpin + ?Sized, W: AsyncWriteExt + Unpin>(
+    options: &QcatOptions,
+    input: &mut R,
+    stream: &mut W,
+) -> std::io::Result<u64> {
+    let input = &mut CheckpointReader::new(input, options.checkpoint_every);
+    match (&options.on_acknowledged, options.no_delay) {
+        (Some(on_acknowledged), no_delay) => {
+            let flush_every = if no_delay {
+                options.buffer_size(NO_DELAY_BUFFER_SIZE)
+            } else {
+                ACK_CALLBACK_INTERVAL
+            };
+            let buffer_size = options.buffer_size(RECEIVE_BUFFER_SIZE);
+            copy_acknowledged(input, stream, flush_every, buffer_size, on_acknowledged).await
+        }
+        (None, true) => {
+            copy_with_buffer(
+                input,
+                stream,
+                options.buffer_size(NO_DELAY_BUFFER_SIZE),
+                true,
+            )
+            .await
+        }
+        (None, false) => match options.buffer_size {
+            Some(buffer_size) => copy_with_buffer(input, stream, buffer_size, false).await,
+            None => tokio::io::copy(input, stream).await,
+        },
+    }
+}
+
+/// Copies input to output, flushing every flush_every bytes and reporting the running total once flushed. Flushing
+/// an s2n-quic stream waits for the peer to acknowledge everything written, so each report is delivered data
+async fn copy_acknowledged<R: AsyncReadExt + Unpin + ?Sized, W: AsyncWriteExt + Unpin>(
+    input: &mut R,
+    output: &mut W,
+    flush_every: usize,
+    buffer_size: usize,
+    on_acknowledged: &AckCallback,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; flush_every.min(buffer_size)];
+    let mut total = 0;
+    let mut unflushed = 0;
+
+    loop {
+        let read = input.read(&mut buf).await?;
+        if read > 0 {
+            output.write_all(&buf[..read]).await?;
+            total += read as u64;
+            unflushed += read;
+        }
+
+        if unflushed >= flush_every || (read == 0 && unflushed > 0) {
+            output.flush().await?;
+            unflushed = 0;
+            on_acknowledged.call(total);
+        }
+
+        if read == 0 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Copies input to output through a buffer of buffer_size. With flush_each_write, flushes after every read so small
+/// writes go out immediately rather than being coalesced
+async fn copy_with_buffer<R: AsyncReadExt + Unpin + ?Sized, W: AsyncWriteExt + Unpin>(
+    input: &mut R,
+    output: &mut W,
+    buffer_size: usize,
+    flush_each_write: bool,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0;
+
+    loop {
+        let read = input.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(total);
+        }
+
+        output.write_all(&buf[..read]).await?;
+        if flush_each_write {
+            output.flush().await?;
+        }
+        total += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn conservative_mtu_transfer() {
+        let options = QcatOptions {
+            max_mtu: Some(CONSERVATIVE_MTU),
+            ..Default::default()
+        };
+        let input = vec![7u8; 256 * 1024];
+
+        let received = test_utils::loopback_transfer(
+            options.clone(),
+            options,
+            &mut input.as_slice(),
+            input.len(),
+        )
+        .await;
+        assert_eq!(received, input);
+    }
+
+    #[tokio::test]
+    async fn cancelled_transfer_keeps_partial_data() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let server_cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(server_cancel.clone());
+        let client_cancel = CancellationToken::new();
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default())
+            .with_cancellation_token(client_cancel.clone());
+
+        let partial = vec![1u8; 64 * 1024];
+        let (mut writer, mut input) = tokio::io::duplex(partial.len());
+        writer.write_all(&partial).await.unwrap();
+
+        let mut output = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&output);
+        let send = async {
+            let cancel_once_received = async {
+                test_utils::wait_until(|| {
+                    received.try_lock().is_ok_and(|r| r.len() >= partial.len())
+                })
+                .await;
+                client_cancel.cancel();
+            };
+            let (result, ()) = tokio::join!(client.run(addr, &mut input), cancel_once_received);
+            server_cancel.cancel();
+            result
+        };
+        let (server_result, client_result) = tokio::join!(server.run(&mut output), send);
+
+        assert!(matches!(
+            client_result.unwrap_err().downcast_ref::<CoreError>(),
+            Some(CoreError::Cancelled)
+        ));
+        assert!(matches!(
+            server_result.unwrap_err().downcast_ref::<CoreError>(),
+            Some(CoreError::Cancelled)
+        ));
+        assert_eq!(*received.lock().await, partial);
+    }
+
+    #[tokio::test]
+    async fn max_duration_stops_a_trickling_transfer() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                max_duration: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+        );
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                no_delay: true,
+                ..Default::default()
+            },
+        );
+
+        let (mut writer, mut input) = tokio::io::duplex(1024);
+        let trickle = async move {
+            while writer.write_all(b"drip").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+
+        let mut output = Arc::new(Mutex::new(Vec::new()));
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = server.run(&mut output) => result,
+            _ = async { tokio::join!(client.run(addr, &mut input), trickle) } => {
+                unreachable!("the trickle never ends")
+            }
+        };
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CoreError>(),
+            Some(CoreError::MaxDurationExceeded)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let received = output.lock().await;
+        assert!(!received.is_empty());
+        assert!(b"drip".repeat(received.len()).starts_with(&received));
+    }
+
+    #[tokio::test]
+    async fn no_delay_delivers_small_writes_promptly() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let options = QcatOptions {
+            no_delay: true,
+            ..Default::default()
+        };
+        let (server, addr) = test_utils::server(material.crypto_config(), options.clone());
+        let mut client = test_utils::client(material.crypto_config(), options);
+        let (mut writer, mut input) = tokio::io::duplex(1024);
+
+        let (received, ()) = test_utils::with_receiving_server(server, |received| async move {
+            let type_two_lines = async {
+                // the first line also waits on the handshake, the second is what we time
+                writer.write_all(b"first\n").await.unwrap();
+                test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 6)).await;
+
+                writer.write_all(b"second\n").await.unwrap();
+                let written = Instant::now();
+                test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 13)).await;
+                assert!(written.elapsed() < Duration::from_millis(200));
+                drop(writer);
+            };
+            let (result, ()) = tokio::join!(client.run(addr, &mut input), type_two_lines);
+            result.unwrap();
+        })
+        .await;
+
+        assert_eq!(received, b"first\nsecond\n");
+    }
+
+    #[tokio::test]
+    async fn push_sends_file_to_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pushed");
+        let contents: Vec<u8> = (0..=255u8).cycle().take(300 * 1024).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let mut received = Vec::new();
+        let receive = async {
+            let result = client.run_receive(addr, &mut received).await;
+            cancel.cancel();
+            result
+        };
+        let (_, result) = tokio::join!(server.run_push(&path), receive);
+
+        result.unwrap();
+        assert_eq!(received, contents);
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_log_distinct_ids() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut first = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let mut second = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        test_utils::with_receiving_server(server, |received| async move {
+            let (mut one, mut two) = (&b"one"[..], &b"two"[..]);
+            let (first, second) =
+                tokio::join!(first.run(addr, &mut one), second.run(addr, &mut two));
+            first.unwrap();
+            second.unwrap();
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 6)).await;
+        })
+        .await;
+
+        let ids: std::collections::HashSet<String> = logs
+            .records()
+            .iter()
+            .filter_map(|record| {
+                let (id, _) = record
+                    .message
+                    .strip_prefix("Connection ")?
+                    .split_once(": accepted from")?;
+                Some(id.to_owned())
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn echo_returns_what_was_sent() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                keep_receiving: true,
+                ..Default::default()
+            },
+        );
+
+        // well past the flow control windows, so this deadlocks unless the echo is read while we're still sending
+        let input: Vec<u8> = (0..=255u8).cycle().take(16 * 1024 * 1024).collect();
+        let mut echoed = Vec::new();
+        let exchange = async {
+            let result = client
+                .run_duplex(addr, &mut input.as_slice(), &mut echoed)
+                .await;
+            cancel.cancel();
+            result
+        };
+        let (_, result) = tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::join!(server.run_echo(), exchange)
+        })
+        .await
+        .expect("echo deadlocked");
+
+        result.unwrap();
+        assert_eq!(echoed, input);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_peer_gets_no_data() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let expected_phrase =
+            crypto::cert_fingerprint_phrase(material.certificate_der_bytes()).unwrap();
+
+        for (answer, confirmed) in [("n\n", false), ("y\n", true)] {
+            let (server, addr) =
+                test_utils::server(material.crypto_config(), QcatOptions::default());
+            let cancel = CancellationToken::new();
+            let mut server = server.with_cancellation_token(cancel.clone());
+            let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let asked_by_callback = Arc::clone(&asked);
+            let mut client = test_utils::client(
+                material.crypto_config(),
+                QcatOptions {
+                    confirm_peer: Some(ConfirmCallback::new(move |phrase| {
+                        asked_by_callback.lock().unwrap().push(phrase.to_owned());
+                        crate::utils::confirm_peer(phrase, &mut answer.as_bytes())
+                    })),
+                    keep_receiving: true,
+                    ..Default::default()
+                },
+            );
+
+            let mut echoed = Vec::new();
+            let exchange = async {
+                let result = client
+                    .run_duplex(addr, &mut &b"secret"[..], &mut echoed)
+                    .await;
+                cancel.cancel();
+                result
+            };
+            let (_, result) = tokio::join!(server.run_echo(), exchange);
+
+            assert_eq!(
+                *asked.lock().unwrap(),
+                std::slice::from_ref(&expected_phrase)
+            );
+            if confirmed {
+                result.unwrap();
+                assert_eq!(echoed, b"secret");
+            } else {
+                let error = result.unwrap_err();
+                assert!(matches!(
+                    error.downcast_ref::<CoreError>(),
+                    Some(CoreError::PeerNotConfirmed)
+                ));
+                assert!(echoed.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_every_resolved_address() {
+        let resolved: Vec<SocketAddr> = tokio::net::lookup_host("localhost:0")
+            .await
+            .unwrap()
+            .collect();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let server =
+            QcatServer::with_addresses(&resolved, material.crypto_config(), QcatOptions::default())
+                .unwrap();
+        let listening = server.local_addrs().unwrap();
+        assert_eq!(listening.len(), resolved.len());
+
+        // our client only binds IPv4 for now, so that's all it can reach
+        let reachable: Vec<SocketAddr> =
+            listening.into_iter().filter(SocketAddr::is_ipv4).collect();
+        assert!(!reachable.is_empty());
+        let (received, ()) = test_utils::with_receiving_server(server, |received| async move {
+            for addr in &reachable {
+                test_utils::client(material.crypto_config(), QcatOptions::default())
+                    .run(*addr, &mut &b"hi"[..])
+                    .await
+                    .unwrap();
+            }
+            test_utils::wait_until(|| {
+                received
+                    .try_lock()
+                    .is_ok_and(|r| r.len() >= 2 * reachable.len())
+            })
+            .await;
+        })
+        .await;
+
+        assert_eq!(received, b"hi".repeat(received.len() / 2));
+    }
+
+    #[test]
+    fn peer_is_allowed_checks_networks() {
+        let peer = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        assert!(QcatOptions::default().peer_is_allowed(&peer("203.0.113.7:4433")));
+
+        let options = QcatOptions {
+            allowed_networks: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(options.peer_is_allowed(&peer("10.1.2.3:4433")));
+        assert!(options.peer_is_allowed(&peer("[fd12::1]:4433")));
+        // IPv4 peers on a dual stack socket show up mapped into IPv6
+        assert!(options.peer_is_allowed(&peer("[::ffff:10.1.2.3]:4433")));
+        assert!(!options.peer_is_allowed(&peer("192.168.1.1:4433")));
+        assert!(!options.peer_is_allowed(&peer("[2001:db8::1]:4433")));
+    }
+
+    #[tokio::test]
+    async fn peers_outside_allowed_networks_are_rejected() {
+        let allowed = QcatOptions {
+            allowed_networks: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let output =
+            test_utils::loopback_transfer(allowed, QcatOptions::default(), &mut &b"hi"[..], 2)
+                .await;
+        assert_eq!(output, b"hi");
+
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let denied = QcatOptions {
+            allowed_networks: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let (server, addr) = test_utils::server(material.crypto_config(), denied);
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let (output, _) = test_utils::with_receiving_server(server, |_| async move {
+            let _ = client.run(addr, &mut &b"hi"[..]).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        })
+        .await;
+        assert!(output.is_empty());
+    }
+
+    /// Input that notes whether the client logged the server being ready before it first read any data
+    struct ReadyCheckingInput<'a> {
+        data: &'a [u8],
+        logs: &'a test_utils::LogCapture,
+        ready_before_data: Option<bool>,
+    }
+
+    impl AsyncRead for ReadyCheckingInput<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if self.ready_before_data.is_none() {
+                let ready = self
+                    .logs
+                    .records()
+                    .iter()
+                    .any(|record| record.message.contains("server ready"));
+                self.ready_before_data = Some(ready);
+            }
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn ready_signal_comes_before_data() {
+        let logs = test_utils::capture_logs();
+        let mut input = ReadyCheckingInput {
+            data: b"data after ready",
+            logs: &logs,
+            ready_before_data: None,
+        };
+        let client_options = QcatOptions {
+            wait_for_ready: true,
+            ..Default::default()
+        };
+
+        let output =
+            test_utils::loopback_transfer(QcatOptions::default(), client_options, &mut input, 16)
+                .await;
+
+        assert_eq!(output, b"data after ready");
+        assert_eq!(input.ready_before_data, Some(true));
+    }
+
+    #[tokio::test]
+    async fn auto_compression_follows_the_data() {
+        let options = || QcatOptions {
+            compression: CompressionMode::Auto,
+            ..Default::default()
+        };
+        let mut random = vec![0u8; 256 * 1024];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut random);
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(4096);
+
+        for (input, should_compress) in [(random, false), (text, true)] {
+            let logs = test_utils::capture_logs();
+            let output =
+                test_utils::loopback_transfer(options(), options(), &mut &input[..], input.len())
+                    .await;
+
+            assert_eq!(output, input);
+            let compressed = logs
+                .records()
+                .iter()
+                .any(|record| record.message.contains("Compressing stream with zstd"));
+            assert_eq!(compressed, should_compress);
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_reports_round_trip_times() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                ping_interval: Some(Duration::from_millis(20)),
+                ..Default::default()
+            },
+        );
+        let rtts = || -> Vec<f64> {
+            logs.records()
+                .iter()
+                .filter_map(|record| {
+                    let (_, rtt) = record.message.split_once(": rtt ")?;
+                    rtt.strip_suffix("ms")?.parse().ok()
+                })
+                .collect()
+        };
+
+        // keep the session open until we've seen a ping come back
+        let (mut writer, mut input) = tokio::io::duplex(1024);
+        let (output, ()) = test_utils::with_receiving_server(server, |_| async {
+            let hold_open = async move {
+                writer.write_all(b"hi").await.unwrap();
+                test_utils::wait_until(|| !rtts().is_empty()).await;
+            };
+            let (result, ()) = tokio::join!(client.run(addr, &mut input), hold_open);
+            result.unwrap();
+        })
+        .await;
+
+        assert_eq!(output, b"hi");
+        let rtts = rtts();
+        assert!(!rtts.is_empty());
+        assert!(
+            rtts.iter().all(|rtt| (0.0..1000.0).contains(rtt)),
+            "{:?}",
+            rtts
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_receiving_outlasts_input() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                keep_receiving: true,
+                ..Default::default()
+            },
+        );
+
+        // a TCP service behind the server that only starts sending once our input has closed
+        let payload: Vec<u8> = (0..=255u8).cycle().take(1024 * 1024).collect();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let service = async {
+            let (mut tcp_stream, _) = listener.accept().await.unwrap();
+            let mut sent_to_us = Vec::new();
+            tcp_stream.read_to_end(&mut sent_to_us).await.unwrap();
+            assert!(sent_to_us.is_empty());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tcp_stream.write_all(&payload).await.unwrap();
+            tcp_stream.shutdown().await.unwrap();
+        };
+
+        let mut received = Vec::new();
+        let exchange = async {
+            let result = client
+                .run_duplex(addr, &mut tokio::io::empty(), &mut received)
+                .await;
+            cancel.cancel();
+            result
+        };
+        let (_, (), result) = tokio::join!(server.run_tcp_connect(&target), service, exchange);
+
+        result.unwrap();
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn matching_handshakes_negotiate() {
+        let ours = Handshake::new(FEATURE_ZSTD | FEATURE_FILE_NAME);
+        let mut bytes = Vec::new();
+        ours.write(&mut bytes).await.unwrap();
+        assert_eq!(bytes.len(), HANDSHAKE_LEN);
+
+        let theirs = Handshake::read(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(theirs.version, HANDSHAKE_VERSION);
+        assert_eq!(theirs.features, ours.features);
+        assert_eq!(theirs.timestamp, ours.timestamp);
+        assert_eq!(theirs.wordlist_hash, ours.wordlist_hash);
+        assert_eq!(
+            theirs
+                .negotiate(FEATURE_ZSTD | FEATURE_FILE_NAME | FEATURE_RESUME)
+                .unwrap(),
+            FEATURE_ZSTD | FEATURE_FILE_NAME
+        );
+        assert!(matches!(
+            theirs.negotiate(FEATURE_ZSTD),
+            Err(CoreError::UnsupportedFeatures(FEATURE_FILE_NAME))
+        ));
+    }
+
+    #[tokio::test]
+    async fn older_handshakes_read_only_their_fields() {
+        let current = Handshake::new(FEATURE_ZSTD).serialize();
+        for (version, len) in [(2, 4), (3, 12), (4, 20), (5, 28)] {
+            // no length byte before version 6
+            let mut bytes = vec![version];
+            bytes.extend_from_slice(&current[2..2 + len]);
+            bytes.extend_from_slice(b"data");
+
+            let mut stream = bytes.as_slice();
+            let handshake = Handshake::read(&mut stream).await.unwrap();
+            assert_eq!(stream, b"data");
+            assert_eq!(handshake.version, version);
+            assert_eq!(handshake.features, FEATURE_ZSTD);
+            assert_eq!(handshake.timestamp.is_some(), version >= 3);
+            assert_eq!(handshake.wordlist_hash.is_some(), version >= 4);
+            assert_eq!(handshake.negotiate(FEATURE_ZSTD).unwrap(), FEATURE_ZSTD);
+        }
+    }
+
+    #[tokio::test]
+    async fn newer_longer_handshake_skips_unknown_fields() {
+        let ours = Handshake::new(FEATURE_ZSTD)
+            .with_expiry(Some(UNIX_EPOCH + Duration::from_secs(1_900_000_000)));
+        let current = ours.serialize();
+        let mut fields = current[2..].to_vec();
+        fields.extend_from_slice(b"a field from the future");
+        let mut bytes = vec![HANDSHAKE_VERSION + 1, fields.len() as u8];
+        bytes.extend_from_slice(&fields);
+        bytes.extend_from_slice(b"data");
+
+        let mut stream = bytes.as_slice();
+        let theirs = Handshake::read(&mut stream).await.unwrap();
+
+        assert_eq!(stream, b"data");
+        assert_eq!(theirs.version, HANDSHAKE_VERSION + 1);
+        assert_eq!(
+            Handshake {
+                version: HANDSHAKE_VERSION,
+                ..theirs
+            },
+            ours
+        );
+        assert_eq!(theirs.negotiate(FEATURE_ZSTD).unwrap(), FEATURE_ZSTD);
+
+        // a length too short for even the features is refused rather than read past
+        let truncated = [HANDSHAKE_VERSION + 1, 2, 0, 0];
+        assert!(Handshake::read(&mut &truncated[..]).await.is_err());
+    }
+
+    #[test]
+    fn too_old_peer_is_incompatible() {
+        let handshake = Handshake::parse(1, &[0, 0, 0, 0]);
+        let err = handshake.negotiate(FEATURE_ZSTD).unwrap_err();
+        assert!(matches!(
+            err,
+            CoreError::IncompatibleVersion {
+                peer: 1,
+                minimum: MIN_HANDSHAKE_VERSION
+            }
+        ));
+        assert!(err.to_string().starts_with("Incompatible version"));
+    }
+
+    /// Start a TCP server echoing back whatever each connection sends, returning its address
+    async fn spawn_tcp_echo() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut tcp_stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tcp_stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await?;
+                    writer.shutdown().await
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn zero_rate_limits_are_rejected() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        for options in [
+            QcatOptions {
+                rate_up: Some(0),
+                ..Default::default()
+            },
+            QcatOptions {
+                rate_down: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(QcatClient::with_options(material.crypto_config(), options).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn duplex_directions_have_their_own_rate_limits() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        // each bucket starts with a second's worth, so these take about 2s up and 4s down
+        let (rate_up, rate_down) = (64 * 1024, 256 * 1024);
+        let upload = vec![b'u'; 3 * rate_up as usize];
+        let download = vec![b'd'; 5 * rate_down as usize];
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                rate_up: Some(rate_up),
+                rate_down: Some(rate_down),
+                keep_receiving: true,
+                ..Default::default()
+            },
+        );
+
+        // a service that sends the download while receiving the upload, timing when the upload ends
+        let service = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let service_addr = service.local_addr().unwrap().to_string();
+        let start = Instant::now();
+        let service_download = download.clone();
+        let service = tokio::spawn(async move {
+            let (mut tcp_stream, _) = service.accept().await.unwrap();
+            let (mut reader, mut writer) = tcp_stream.split();
+            let mut uploaded = Vec::new();
+            let receive = async {
+                reader.read_to_end(&mut uploaded).await.unwrap();
+                start.elapsed()
+            };
+            let send = async {
+                writer.write_all(&service_download).await.unwrap();
+                writer.shutdown().await.unwrap();
+            };
+            let (upload_time, ()) = tokio::join!(receive, send);
+            (uploaded, upload_time)
+        });
+
+        let mut downloaded = Vec::new();
+        let session = async {
+            client
+                .run_duplex(addr, &mut &upload[..], &mut downloaded)
+                .await
+                .unwrap();
+            let download_time = start.elapsed();
+            cancel.cancel();
+            download_time
+        };
+        let (served, download_time) = tokio::join!(server.run_tcp_connect(&service_addr), session);
+        test_utils::assert_cancelled(served);
+        let (uploaded, upload_time) = service.await.unwrap();
+
+        assert_eq!(uploaded, upload);
+        assert_eq!(downloaded, download);
+        assert!(
+            upload_time >= Duration::from_millis(1800),
+            "{:?}",
+            upload_time
+        );
+        assert!(
+            download_time >= Duration::from_millis(3600),
+            "{:?}",
+            download_time
+        );
+        // the slower download doesn't hold the upload back
+        assert!(upload_time + Duration::from_secs(1) < download_time);
+    }
+
+    #[tokio::test]
+    async fn tcp_bridge_reaches_echo_service() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                keep_receiving: true,
+                ..Default::default()
+            },
+        );
+        let echo_addr = spawn_tcp_echo().await.to_string();
+
+        // the local service the client bridges, sending a payload and reading back what comes over the tunnel
+        let payload: Vec<u8> = (0..=255u8).cycle().take(256 * 1024).collect();
+        let app_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let app_addr = app_listener.local_addr().unwrap();
+        let app = async {
+            let (mut tcp_stream, _) = app_listener.accept().await.unwrap();
+            let (mut reader, mut writer) = tcp_stream.split();
+            let send = async {
+                writer.write_all(&payload).await.unwrap();
+                writer.shutdown().await.unwrap();
+            };
+            let mut echoed = Vec::new();
+            let (_, read) = tokio::join!(send, reader.read_to_end(&mut echoed));
+            read.unwrap();
+            echoed
+        };
+        let bridge = async {
+            let (mut tcp_input, mut tcp_output) =
+                TcpStream::connect(app_addr).await.unwrap().into_split();
+            let result = client
+                .run_duplex(addr, &mut tcp_input, &mut tcp_output)
+                .await;
+            drop(tcp_output);
+            cancel.cancel();
+            result
+        };
+
+        let (_, echoed, result) = tokio::join!(server.run_tcp_connect(&echo_addr), app, bridge);
+
+        result.unwrap();
+        assert_eq!(echoed, payload);
+    }
+
+    #[tokio::test]
+    async fn reverse_tunnel_reaches_echo_service() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let client_cancel = CancellationToken::new();
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default())
+            .with_cancellation_token(client_cancel.clone());
+        let echo_addr = spawn_tcp_echo().await.to_string();
+
+        let payload = b"through the reverse tunnel".to_vec();
+        let tcp_client = async {
+            // the server logs the address it ended up listening on
+            let mut listen_addr = None;
+            test_utils::wait_until(|| {
+                listen_addr = logs.records().iter().find_map(|record| {
+                    record
+                        .message
+                        .strip_prefix("Forwarding TCP connections on ")?
+                        .parse::<SocketAddr>()
+                        .ok()
+                });
+                listen_addr.is_some()
+            })
+            .await;
+
+            let mut tcp_stream = TcpStream::connect(listen_addr.unwrap()).await.unwrap();
+            tcp_stream.write_all(&payload).await.unwrap();
+            tcp_stream.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            tcp_stream.read_to_end(&mut echoed).await.unwrap();
+
+            client_cancel.cancel();
+            cancel.cancel();
+            echoed
+        };
+
+        let (_, _, echoed) = tokio::join!(
+            server.run_tcp_listen("127.0.0.1:0"),
+            client.run_tcp_connect(addr, &echo_addr),
+            tcp_client
+        );
+
+        assert_eq!(echoed, payload);
+    }
+
+    #[tokio::test]
+    async fn transfer_completes_despite_loss() {
+        let client_options = QcatOptions {
+            network_impairment: Some(NetworkImpairment {
+                latency: Duration::from_millis(5),
+                loss_percent: 10.0,
+            }),
+            ..Default::default()
+        };
+        let input: Vec<u8> = (0..=255u8).cycle().take(256 * 1024).collect();
+
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            client_options,
+            &mut input.as_slice(),
+            input.len(),
+        )
+        .await;
+
+        assert_eq!(output, input);
+    }
+
+    #[tokio::test]
+    async fn connect_times_out_under_high_latency() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        // the server's reply takes longer than s2n-quic's 10s handshake limit to come back
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                network_impairment: Some(NetworkImpairment {
+                    latency: Duration::from_secs(6),
+                    loss_percent: 0.0,
+                }),
+                ..Default::default()
+            },
+        );
+
+        let started = Instant::now();
+        let (output, result) = test_utils::with_receiving_server(server, |_| async move {
+            client.run(addr, &mut &b"too slow"[..]).await
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert!(output.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(15));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dscp_sets_traffic_class() {
+        let socket = bind_udp_socket_with_dscp((Ipv4Addr::LOCALHOST, 0).into(), 46).unwrap();
+        assert_eq!(socket2::SockRef::from(&socket).tos().unwrap(), 46 << 2);
+    }
+
+    #[tokio::test]
+    async fn transfer_completes_with_dscp() {
+        let client_options = QcatOptions {
+            dscp: Some(46),
+            ..Default::default()
+        };
+
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            client_options,
+            &mut &b"expedited"[..],
+            9,
+        )
+        .await;
+
+        assert_eq!(output, b"expedited");
+    }
+
+    /// Receive a stream made of handshake followed by data, returning what was written to our output
+    async fn receive_with_handshake(
+        handshake: &Handshake,
+        data: &[u8],
+        options: &QcatOptions,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut stream = handshake.serialize().to_vec();
+        stream.extend_from_slice(data);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        receive_stream(0, std::io::Cursor::new(stream), &output, options).await?;
+        let received = output.lock().await.clone();
+        Ok(received)
+    }
+
+    #[tokio::test]
+    async fn connections_after_expiry_are_rejected() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let expires_at = SystemTime::now() + Duration::from_secs(1);
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                expires_at: Some(expires_at),
+                ..Default::default()
+            },
+        );
+        let client = || test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let (received, late) = test_utils::with_receiving_server(server, |received| async move {
+            client().run(addr, &mut &b"in time"[..]).await.unwrap();
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 7)).await;
+
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            let late = client().run(addr, &mut &b"too late"[..]).await;
+            test_utils::wait_until(|| {
+                logs.records()
+                    .iter()
+                    .any(|record| record.message.contains("passphrase expired"))
+            })
+            .await;
+            late
+        })
+        .await;
+
+        assert_eq!(received, b"in time");
+        assert!(late.is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_handshake_is_rejected() {
+        let options = QcatOptions::default();
+        let expired =
+            Handshake::new(0).with_expiry(Some(SystemTime::now() - Duration::from_secs(60)));
+        let err = receive_with_handshake(&expired, b"data", &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<CoreError>()),
+            Some(CoreError::PassphraseExpired)
+        ));
+
+        let unexpired =
+            Handshake::new(0).with_expiry(Some(SystemTime::now() + Duration::from_secs(60)));
+        let received = receive_with_handshake(&unexpired, b"data", &options)
+            .await
+            .unwrap();
+        assert_eq!(received, b"data");
+    }
+
+    #[tokio::test]
+    async fn skewed_peer_clock_is_warned_about() {
+        let options = QcatOptions {
+            max_clock_skew: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let skew_warnings = |logs: &test_utils::LogCapture| {
+            logs.records()
+                .iter()
+                .filter(|record| record.message.contains("peer's clock is"))
+                .count()
+        };
+
+        let logs = test_utils::capture_logs();
+        let in_sync = Handshake::new(0);
+        let received = receive_with_handshake(&in_sync, b"data", &options)
+            .await
+            .unwrap();
+        assert_eq!(received, b"data");
+        assert_eq!(skew_warnings(&logs), 0);
+
+        let skewed = Handshake {
+            timestamp: Some(unix_timestamp() - 3600),
+            ..Handshake::new(0)
+        };
+        let received = receive_with_handshake(&skewed, b"data", &options)
+            .await
+            .unwrap();
+        assert_eq!(received, b"data");
+        assert_eq!(skew_warnings(&logs), 1);
+    }
+
+    #[tokio::test]
+    async fn client_checks_the_servers_clock() {
+        let logs = test_utils::capture_logs();
+        let client_options = QcatOptions {
+            max_clock_skew: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            client_options,
+            &mut &b"data"[..],
+            4,
+        )
+        .await;
+
+        assert_eq!(output, b"data");
+        // the server's clock came with its ready signal, and matches ours
+        assert!(!logs.records().iter().any(|record| {
+            record.message.contains("didn't send its clock")
+                || record.message.contains("peer's clock is")
+        }));
+    }
+
+    #[test]
+    fn unsafe_file_names_are_rejected() {
+        assert_eq!(sanitize_file_name("report.pdf"), Some("report.pdf"));
+        assert_eq!(sanitize_file_name(".hidden"), Some(".hidden"));
+        for unsafe_name in [
+            "",
+            ".",
+            "..",
+            "../report.pdf",
+            "/etc/passwd",
+            "dir/report.pdf",
+            "..\\report.pdf",
+            "C:\\report.pdf",
+            "report\0.pdf",
+        ] {
+            assert_eq!(sanitize_file_name(unsafe_name), None, "{:?}", unsafe_name);
+        }
+    }
+
+    #[tokio::test]
+    async fn named_file_is_saved_to_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                save_named_files: Some(dir.path().to_owned()),
+                ..Default::default()
+            },
+        );
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                file_name: Some("report.pdf".to_owned()),
+                ..Default::default()
+            },
+        );
+        let contents = b"%PDF-1.7 not really".to_vec();
+        let path = dir.path().join("report.pdf");
+
+        let (output, ()) = test_utils::with_receiving_server(server, |_| async {
+            client.run(addr, &mut contents.as_slice()).await.unwrap();
+            test_utils::wait_until(|| {
+                std::fs::read(&path).is_ok_and(|saved| saved.len() == contents.len())
+            })
+            .await;
+        })
+        .await;
+
+        assert!(output.is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn preserved_mode_is_restored_on_the_copy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = source_dir.path().join("secret.txt");
+        std::fs::write(&source, b"for your eyes only").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                save_named_files: Some(dir.path().to_owned()),
+                ..Default::default()
+            },
+        );
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                file_name: Some("secret.txt".to_owned()),
+                file_metadata: Some(FileMetadata::from_metadata(
+                    &std::fs::metadata(&source).unwrap(),
+                )),
+                ..Default::default()
+            },
+        );
+        let path = dir.path().join("secret.txt");
+        let mode = |path: &Path| {
+            std::fs::metadata(path)
+                .map(|metadata| metadata.permissions().mode() & 0o7777)
+                .ok()
+        };
+
+        test_utils::with_receiving_server(server, |_| async {
+            let mut input = tokio::fs::File::open(&source).await.unwrap();
+            client.run(addr, &mut input).await.unwrap();
+            test_utils::wait_until(|| mode(&path) == Some(0o600)).await;
+        })
+        .await;
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"for your eyes only");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[tokio::test]
+    async fn parallel_transfer_is_reassembled() {
+        let logs = test_utils::capture_logs();
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = source_dir.path().join("big.bin");
+        // not a multiple of 4, so the last segment is shorter than the rest
+        let contents: Vec<u8> = (0..=255u8).cycle().take(5 * 1024 * 1024 + 3).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                save_named_files: Some(dir.path().to_owned()),
+                ..Default::default()
+            },
+        );
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                file_name: Some("big.bin".to_owned()),
+                ..Default::default()
+            },
+        );
+        let path = dir.path().join("big.bin");
+
+        test_utils::with_receiving_server(server, |_| async {
+            client.run_parallel(addr, &source, 4).await.unwrap();
+            test_utils::wait_until(|| {
+                logs.records()
+                    .iter()
+                    .any(|record| record.message.contains("reassembled"))
+            })
+            .await;
+        })
+        .await;
+
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert!(logs
+            .records()
+            .iter()
+            .any(|record| record.message.contains("as 4 segments")));
+    }
+
+    #[tokio::test]
+    async fn failed_handshakes_block_the_peer() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let wrong_material = test_utils::material("qcatothersaltsalt-some-other-words");
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                max_failed_attempts: Some(2),
+                block_duration: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+
+        let (output, ()) = test_utils::with_receiving_server(server, |_| async move {
+            for _ in 0..2 {
+                let mut client =
+                    test_utils::client(wrong_material.crypto_config(), QcatOptions::default());
+                assert!(client.run(addr, &mut &b"guess"[..]).await.is_err());
+            }
+
+            // now even the right passphrase gets nowhere, the server drops our packets until the block expires
+            let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+            assert!(client.run(addr, &mut &b"blocked"[..]).await.is_err());
+        })
+        .await;
+
+        assert!(output.is_empty());
+    }
+
+    /// Line endings are where text mode output (i.e. Windows' C runtime) would mangle binary data
+    #[tokio::test]
+    async fn line_ending_bytes_reach_a_file_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("received.bin");
+        let mut input = b"\r\n\n\r\x1a".to_vec();
+        input.extend(0..=255u8);
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let mut output = Arc::new(Mutex::new(File::create(&path).await.unwrap()));
+        let send = async {
+            client.run(addr, &mut input.as_slice()).await.unwrap();
+            test_utils::wait_until(|| {
+                std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= input.len() as u64)
+            })
+            .await;
+            cancel.cancel();
+        };
+        let _ = tokio::join!(server.run(&mut output), send);
+
+        assert_eq!(std::fs::read(&path).unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn incoming_connections_are_echoed() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut server, addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut incoming = server.incoming();
+
+        let serve = async {
+            for _ in 0..2 {
+                let mut conn = std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(conn.remote_addr().ip(), addr.ip());
+                let stream = conn.accept_bidirectional_stream().await.unwrap().unwrap();
+                let (mut receive_stream, mut send_stream) = stream.split();
+                tokio::io::copy(&mut receive_stream, &mut send_stream)
+                    .await
+                    .unwrap();
+                send_stream.close().await.unwrap();
+            }
+        };
+        let clients = async {
+            let mut echoed = Vec::new();
+            for message in [&b"first"[..], b"second"] {
+                let mut client = test_utils::client(
+                    material.crypto_config(),
+                    QcatOptions {
+                        keep_receiving: true,
+                        ..Default::default()
+                    },
+                );
+                let mut output = Vec::new();
+                client
+                    .run_duplex(addr, &mut &message[..], &mut output)
+                    .await
+                    .unwrap();
+                echoed.push(output);
+            }
+            echoed
+        };
+        let ((), echoed) = tokio::join!(serve, clients);
+
+        assert_eq!(echoed, [b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn peer_cert_matches_the_derived_one() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut server, addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut incoming = server.incoming();
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let accept = async {
+            std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        let (accepted, opened) = tokio::join!(accept, client.open(addr));
+        let opened = opened.unwrap();
+
+        for stream in [&opened, &accepted] {
+            let peer_cert = stream.peer_cert().unwrap();
+            assert_eq!(crypto::cert_fingerprint(&peer_cert), material.fingerprint());
+        }
+    }
+
+    #[tokio::test]
+    async fn wordlist_mismatch_warns_or_fails() {
+        let mismatched = Handshake {
+            wordlist_hash: Some(!crypto::wordlist_hash()),
+            ..Handshake::new(0)
+        };
+
+        let logs = test_utils::capture_logs();
+        let received = receive_with_handshake(&mismatched, b"data", &QcatOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(received, b"data");
+        assert!(logs
+            .records()
+            .iter()
+            .any(|record| record.message.contains("wordlist")));
+
+        let strict = QcatOptions {
+            strict_wordlist: true,
+            ..Default::default()
+        };
+        let err = receive_with_handshake(&mismatched, b"data", &strict)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<CoreError>()),
+            Some(CoreError::WordlistMismatch { .. })
+        ));
+
+        // matching wordlists are fine either way
+        let received = receive_with_handshake(&Handshake::new(0), b"data", &strict)
+            .await
+            .unwrap();
+        assert_eq!(received, b"data");
+    }
+
+    #[tokio::test]
+    async fn ack_callback_reaches_total_sent() {
+        let acknowledged = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reports = Arc::clone(&acknowledged);
+        let client_options = QcatOptions {
+            on_acknowledged: Some(AckCallback::new(move |total| {
+                reports.lock().unwrap().push(total)
+            })),
+            ..Default::default()
+        };
+        // a few ACK_CALLBACK_INTERVALs, and a bit
+        let input = vec![3u8; 3 * ACK_CALLBACK_INTERVAL + 100];
+
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            client_options,
+            &mut input.as_slice(),
+            input.len(),
+        )
+        .await;
+
+        assert_eq!(output, input);
+        let acknowledged = acknowledged.lock().unwrap();
+        assert!(acknowledged.len() >= 4, "{:?}", acknowledged);
+        assert!(acknowledged.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(acknowledged.last(), Some(&(input.len() as u64)));
+    }
+
+    #[tokio::test]
+    async fn sending_client_and_receiving_server_time_the_first_byte() {
+        let input = b"first".to_vec();
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            QcatOptions::default(),
+            &mut input.as_slice(),
+            input.len(),
+        )
+        .await;
+        assert_eq!(output, input);
+
+        let summary = TIMINGS.summary();
+        assert!(summary.contains("first byte acknowledged"), "{}", summary);
+        assert!(summary.contains("first byte received"), "{}", summary);
+    }
+
+    /// Input that records the largest buffer it was ever asked to fill
+    struct BufferSizeRecordingInput<'a> {
+        data: &'a [u8],
+        largest_read: usize,
+    }
+
+    impl AsyncRead for BufferSizeRecordingInput<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.largest_read = self.largest_read.max(buf.remaining());
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn buffer_size_bounds_reads() {
+        let buffer_size = 4096;
+        let data: Vec<u8> = (0..=255u8).cycle().take(4 * 1024 * 1024).collect();
+        let mut input = BufferSizeRecordingInput {
+            data: &data,
+            largest_read: 0,
+        };
+        let options = || QcatOptions {
+            buffer_size: Some(buffer_size),
+            ..Default::default()
+        };
+
+        let output =
+            test_utils::loopback_transfer(options(), options(), &mut input, data.len()).await;
+
+        assert_eq!(output, data);
+        assert!(input.largest_read > 0);
+        assert!(
+            input.largest_read <= buffer_size,
+            "read into a {} byte buffer",
+            input.largest_read
+        );
+    }
+
+    #[tokio::test]
+    async fn framed_transfer_over_in_memory_pipe() {
+        let data: Vec<u8> = b"in memory, no sockets\n".repeat(10_000);
+        for compression in [CompressionMode::Off, CompressionMode::On] {
+            let options = QcatOptions {
+                compression,
+                ..Default::default()
+            };
+            let output = Arc::new(Mutex::new(Vec::new()));
+
+            let received = transfer_in_memory(&options, &mut &data[..], &output)
+                .await
+                .unwrap();
+
+            assert_eq!(received, data.len() as u64);
+            assert_eq!(*output.lock().await, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_delay_delivers_a_line_despite_write_delay() {
+        let delay = Duration::from_millis(100);
+        let options = QcatOptions {
+            compression: CompressionMode::Off,
+            no_delay: true,
+            in_memory_write_delay: Some(delay),
+            ..Default::default()
+        };
+        let (mut writer, mut input) = tokio::io::duplex(1024);
+        let output = Arc::new(Mutex::new(Vec::new()));
+
+        let type_a_line = async {
+            let start = Instant::now();
+            writer.write_all(b"hello\n").await.unwrap();
+            // the input is still open, so the line can only arrive because it was sent as soon as it was read
+            test_utils::wait_until(|| {
+                output
+                    .try_lock()
+                    .is_ok_and(|received| received.as_slice() == b"hello\n")
+            })
+            .await;
+            let elapsed = start.elapsed();
+            drop(writer);
+            elapsed
+        };
+        let (received, elapsed) = tokio::join!(
+            transfer_in_memory(&options, &mut input, &output),
+            type_a_line
+        );
+
+        assert_eq!(received.unwrap(), 6);
+        assert!(elapsed >= delay, "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn trained_dictionary_round_trips_and_compresses_better() {
+        let record = |i: usize| {
+            format!(
+                "{{\"id\":{},\"user\":\"user{}\",\"status\":\"active\",\"region\":\"eu-west-1\",\"plan\":\"team\"}}\n",
+                i,
+                i % 97
+            )
+            .into_bytes()
+        };
+        let samples: Vec<Vec<u8>> = (0..2000).map(record).collect();
+        let dictionary = ZstdDictionary::new(zstd::dict::from_samples(&samples, 4096).unwrap());
+        let data: Vec<u8> = (5000..5020).flat_map(record).collect();
+
+        let send_with = |zstd_dictionary: Option<ZstdDictionary>| {
+            let options = QcatOptions {
+                compression: CompressionMode::On,
+                zstd_dictionary,
+                ..Default::default()
+            };
+            let data = &data;
+            async move {
+                let mut wire = Vec::new();
+                send_stream(&options, &mut data.as_slice(), &mut wire)
+                    .await
+                    .unwrap();
+                (options, wire)
+            }
+        };
+        let (_, plain_wire) = send_with(None).await;
+        let (options, dictionary_wire) = send_with(Some(dictionary)).await;
+        assert!(
+            dictionary_wire.len() < plain_wire.len(),
+            "{} bytes with the dictionary, {} without",
+            dictionary_wire.len(),
+            plain_wire.len()
+        );
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        receive_stream(
+            0,
+            std::io::Cursor::new(dictionary_wire.clone()),
+            &output,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(*output.lock().await, data);
+
+        // the peer used a different dictionary, which is refused before any of it is decompressed
+        let other = QcatOptions {
+            zstd_dictionary: Some(ZstdDictionary::new(b"some other dictionary".to_vec())),
+            ..Default::default()
+        };
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let error = receive_stream(0, std::io::Cursor::new(dictionary_wire), &output, &other)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(output.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_logged_at_each_milestone() {
+        let logs = test_utils::capture_logs();
+        let milestone = 1024 * 1024;
+        let options = QcatOptions {
+            compression: CompressionMode::Off,
+            // small reads, so each milestone gets a line of its own
+            buffer_size: Some(64 * 1024),
+            checkpoint_every: Some(milestone),
+            ..Default::default()
+        };
+        let data = vec![3u8; 5 * milestone as usize / 2];
+        let output = Arc::new(Mutex::new(Vec::new()));
+
+        transfer_in_memory(&options, &mut data.as_slice(), &output)
+            .await
+            .unwrap();
+
+        let checkpoints: Vec<u64> = logs
+            .records()
+            .iter()
+            .filter(|record| record.message.starts_with("Checkpoint:"))
+            .map(|record| record.field("bytes").unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(checkpoints.len(), 2, "{:?}", checkpoints);
+        assert!((milestone..2 * milestone).contains(&checkpoints[0]));
+        assert!((2 * milestone..3 * milestone).contains(&checkpoints[1]));
+        assert_eq!(output.lock().await.len(), data.len());
+    }
+
+    #[tokio::test]
+    async fn transfer_completes_with_large_and_tiny_windows() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(256 * 1024).collect();
+        for window in [64 * 1024 * 1024, 2048] {
+            let options = || QcatOptions {
+                receive_window: Some(window),
+                stream_window: Some(window),
+                ..Default::default()
+            };
+
+            let output =
+                test_utils::loopback_transfer(options(), options(), &mut &data[..], data.len())
+                    .await;
+
+            assert_eq!(output, data, "window of {} bytes", window);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_gets_computed_reply() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        // a service that only replies once it has the whole request, as an HTTP-like server would
+        let service = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let service_addr = service.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut tcp_stream, _) = service.accept().await?;
+            let mut request = Vec::new();
+            tcp_stream.read_to_end(&mut request).await?;
+            tcp_stream.write_all(&request.to_ascii_uppercase()).await?;
+            tcp_stream.shutdown().await
+        });
+
+        let mut reply = Vec::new();
+        let request = async {
+            let result = client
+                .run_request(addr, &mut &b"get /status\n"[..], &mut reply)
+                .await;
+            cancel.cancel();
+            result
+        };
+        let (served, requested) = tokio::join!(server.run_tcp_connect(&service_addr), request);
+        test_utils::assert_cancelled(served);
+        requested.unwrap();
+
+        assert_eq!(reply, b"GET /STATUS\n");
+    }
+
+    #[tokio::test]
+    async fn waiting_client_connects_once_server_appears() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let stranger = test_utils::material("qcatstrangersalt-not-the-server-yet");
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                wait_for_server: true,
+                retry_delay: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+
+        // until our server appears, something else answers on its port and fails every handshake
+        let (socket, addr) = test_utils::loopback_socket();
+        let stranger_cancel = CancellationToken::new();
+        let mut stranger_server = QcatServer::with_sockets(
+            vec![socket],
+            stranger.crypto_config(),
+            QcatOptions::default(),
+        )
+        .unwrap()
+        .with_cancellation_token(stranger_cancel.clone());
+        let waiting_attempts = || {
+            logs.records()
+                .iter()
+                .filter(|record| record.message.starts_with("Waiting for a server at"))
+                .count()
+        };
+
+        let cancel = CancellationToken::new();
+        let mut output = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&output);
+        let servers = async {
+            let stranger_output = &mut Arc::new(Mutex::new(Vec::new()));
+            let (stranger_result, ()) = tokio::join!(stranger_server.run(stranger_output), async {
+                test_utils::wait_until(|| waiting_attempts() >= 3).await;
+                stranger_cancel.cancel();
+            });
+            test_utils::assert_cancelled(stranger_result);
+            drop(stranger_server);
+
+            // the stranger's endpoint lets go of the port in the background
+            let socket = loop {
+                match std::net::UdpSocket::bind(addr) {
+                    Ok(socket) => break socket,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            socket.set_nonblocking(true).unwrap();
+            let mut server = QcatServer::with_sockets(
+                vec![socket],
+                material.crypto_config(),
+                QcatOptions::default(),
+            )
+            .unwrap()
+            .with_cancellation_token(cancel.clone());
+            test_utils::assert_cancelled(server.run(&mut output).await);
+        };
+        let send = async {
+            client.run(addr, &mut &b"finally"[..]).await.unwrap();
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 7)).await;
+            cancel.cancel();
+        };
+        tokio::join!(servers, send);
+
+        assert!(waiting_attempts() >= 3);
+        assert_eq!(*output.lock().await, b"finally");
+    }
+
+    #[tokio::test]
+    async fn relay_forwards_to_the_next_server() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(256 * 1024).collect();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (relay_server, relay_addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let (last_server, last_addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let relay_cancel = CancellationToken::new();
+        let last_cancel = CancellationToken::new();
+        let mut relay_server = relay_server.with_cancellation_token(relay_cancel.clone());
+        let mut last_server = last_server.with_cancellation_token(last_cancel.clone());
+        let mut sender = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        // A sends to relay B, which forwards everything it receives on to C
+        let (forward_output, forward) =
+            test_utils::client(material.crypto_config(), QcatOptions::default())
+                .forward(last_addr, 64 * 1024);
+        let mut relay_output = Arc::new(Mutex::new(forward_output));
+        let mut last_output = Arc::new(Mutex::new(Vec::new()));
+        let last_received = Arc::clone(&last_output);
+
+        let relay = async {
+            test_utils::assert_cancelled(relay_server.run(&mut relay_output).await);
+            relay_output.lock().await.shutdown().await.unwrap();
+        };
+        let forward = async {
+            forward.await.unwrap();
+            last_cancel.cancel();
+        };
+        let send = async {
+            sender.run(relay_addr, &mut &data[..]).await.unwrap();
+            test_utils::wait_until(|| {
+                last_received
+                    .try_lock()
+                    .is_ok_and(|r| r.len() >= data.len())
+            })
+            .await;
+            relay_cancel.cancel();
+        };
+        let (last_result, (), (), ()) =
+            tokio::join!(last_server.run(&mut last_output), relay, forward, send);
+        test_utils::assert_cancelled(last_result);
+
+        assert_eq!(*last_output.lock().await, data);
+    }
+
+    #[tokio::test]
+    async fn relay_stopping_ends_its_forward() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut relay_server, _) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                max_duration: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+        let (last_server, last_addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let last_cancel = CancellationToken::new();
+        let mut last_server = last_server.with_cancellation_token(last_cancel.clone());
+
+        // the relay's forward only finishes once the relay shuts its output down, which it must even when stopped
+        let (forward_output, forward) =
+            test_utils::client(material.crypto_config(), QcatOptions::default())
+                .forward(last_addr, 64 * 1024);
+        let mut relay_output = Arc::new(Mutex::new(forward_output));
+        let relay = async {
+            let (served, forwarded) = tokio::time::timeout(Duration::from_secs(10), async {
+                tokio::join!(relay_server.run_and_shut_down(&mut relay_output), forward)
+            })
+            .await
+            .expect("forward outlived the relay's server");
+            last_cancel.cancel();
+            forwarded.unwrap();
+            served
+        };
+        let mut last_output = Arc::new(Mutex::new(Vec::new()));
+        let (last_result, served) = tokio::join!(last_server.run(&mut last_output), relay);
+        test_utils::assert_cancelled(last_result);
+
+        assert!(matches!(
+            served.unwrap_err().downcast_ref::<CoreError>(),
+            Some(CoreError::MaxDurationExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_and_receive_caps_stop_at_the_limit() {
+        let long = b"0123456789abcdefghij";
+        let short = b"01234";
+        for (cap, options) in [
+            (
+                "send",
+                QcatOptions {
+                    send_limit: Some(10),
+                    ..Default::default()
+                },
+            ),
+            (
+                "receive",
+                QcatOptions {
+                    receive_limit: Some(10),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            for (input, expected) in [(&long[..], &long[..10]), (&short[..], &short[..])] {
+                let output = Arc::new(Mutex::new(Vec::new()));
+                let received = transfer_in_memory(&options, &mut &input[..], &output)
+                    .await
+                    .unwrap();
+
+                assert_eq!(received, expected.len() as u64, "{} cap", cap);
+                assert_eq!(*output.lock().await, expected, "{} cap", cap);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn close_log_carries_transfer_fields() {
+        let logs = test_utils::capture_logs();
+        let data = vec![b'x'; 12345];
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let closed = || {
+            logs.records()
+                .into_iter()
+                .find(|record| record.message.contains("closed after transferring"))
+        };
+
+        let (received, ()) = test_utils::with_receiving_server(server, |_| async {
+            client.run(addr, &mut &data[..]).await.unwrap();
+            test_utils::wait_until(|| closed().is_some()).await;
+        })
+        .await;
+        assert_eq!(received, data);
+
+        let record = closed().unwrap();
+        assert_eq!(record.field("bytes"), Some("12345"));
+        assert_eq!(record.field("direction"), Some("received"));
+        assert!(record.field("peer").is_some());
+        assert!(record.field("connection").is_some());
+        assert!(record
+            .field("duration_ms")
+            .is_some_and(|duration| duration.parse::<u64>().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_reaped_while_active_one_survives() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                reap_idle: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+        );
+        let mut idle_client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let mut active_client =
+            test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        // the idle client sends a byte then stalls, the active one a byte every 100ms for 2s
+        let (mut idle_writer, mut idle_input) = tokio::io::duplex(64);
+        idle_writer.write_all(b"i").await.unwrap();
+        let (mut active_writer, mut active_input) = tokio::io::duplex(64);
+        let trickle = async move {
+            for _ in 0..20 {
+                active_writer.write_all(b"a").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        let (received, ()) = test_utils::with_receiving_server(server, |received| async move {
+            let idle = async {
+                let _ = idle_client.run(addr, &mut idle_input).await;
+                std::future::pending::<()>().await
+            };
+            let active = async {
+                let (result, ()) =
+                    tokio::join!(active_client.run(addr, &mut active_input), trickle);
+                result
+            };
+            tokio::select! {
+                _ = idle => unreachable!(),
+                result = active => result.unwrap(),
+            }
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 21)).await;
+        })
+        .await;
+        drop(idle_writer);
+
+        assert_eq!(received.iter().filter(|&&byte| byte == b'a').count(), 20);
+        assert_eq!(received.iter().filter(|&&byte| byte == b'i').count(), 1);
+        let reaped = logs
+            .records()
+            .iter()
+            .filter(|record| record.message.contains("reaping after"))
+            .count();
+        assert_eq!(reaped, 1);
+    }
+
+    /// Seekable input that stalls once partway through, long enough for the server to reap the connection
+    struct StallingInput {
+        inner: std::io::Cursor<Vec<u8>>,
+        stall_at: u64,
+        stall: Option<Pin<Box<tokio::time::Sleep>>>,
+        stalled: bool,
+    }
+
+    impl AsyncRead for StallingInput {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = &mut *self;
+            let mut max = buf.remaining();
+            if !this.stalled {
+                let before_stall = this.stall_at.saturating_sub(this.inner.position());
+                if before_stall == 0 {
+                    let stall = this.stall.get_or_insert_with(|| {
+                        Box::pin(tokio::time::sleep(Duration::from_secs(1)))
+                    });
+                    ready!(stall.as_mut().poll(cx));
+                    this.stalled = true;
+                } else {
+                    max = max.min(before_stall as usize);
+                }
+            }
+
+            let mut limited = vec![0u8; max];
+            let mut limited_buf = tokio::io::ReadBuf::new(&mut limited);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited_buf))?;
+            buf.put_slice(limited_buf.filled());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncSeek for StallingInput {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+
+        fn poll_complete(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn resumable_transfer_survives_a_dropped_connection() {
+        let logs = test_utils::capture_logs();
+        let data: Vec<u8> = (0..=255u8).cycle().take(1024 * 1024).collect();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        // the server drops the connection while the input stalls
+        let (server, addr) = test_utils::server(
+            material.crypto_config(),
+            QcatOptions {
+                reap_idle: Some(Duration::from_millis(300)),
+                ..Default::default()
+            },
+        );
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                reconnect_attempts: 1,
+                ..Default::default()
+            },
+        );
+        let mut input = StallingInput {
+            inner: std::io::Cursor::new(data.clone()),
+            stall_at: data.len() as u64 / 2,
+            stall: None,
+            stalled: false,
+        };
+
+        let expected = data.len();
+
+        let (received, ()) = test_utils::with_receiving_server(server, |received| async move {
+            client.run_resumable(addr, &mut input).await.unwrap();
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= expected)).await;
+        })
+        .await;
+
+        assert_eq!(received, data);
+        let logged = |message: &str| {
+            logs.records()
+                .iter()
+                .any(|record| record.message.contains(message))
+        };
+        assert!(logged("reaping after"));
+        assert!(logged("reconnecting (1/1)"));
+        assert!(logged("resuming transfer"));
+    }
+
+    #[tokio::test]
+    async fn mtu_below_quic_minimum_is_rejected() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (socket, _) = test_utils::loopback_socket();
+        let options = QcatOptions {
+            max_mtu: Some(576),
+            ..Default::default()
+        };
+
+        assert!(QcatServer::with_sockets(vec![socket], material.crypto_config(), options).is_err());
     }
 }
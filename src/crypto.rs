@@ -1,18 +1,38 @@
-use argon2::{Argon2, RECOMMENDED_SALT_LEN};
+use argon2::{Algorithm, Argon2, Params, Version, RECOMMENDED_SALT_LEN};
+use clap::ValueEnum;
 use core::fmt;
 use ed25519_dalek::{pkcs8::EncodePrivateKey, SigningKey};
-use rand::{rngs::OsRng, RngCore};
-use rcgen::{CertificateParams, KeyPair, PKCS_ED25519};
+use log::{debug, warn};
+use rand::{rngs::OsRng, Rng, RngCore};
+pub use rcgen::CertificateParams;
+use rcgen::{
+    date_time_ymd, DistinguishedName as RcgenDistinguishedName, DnType, KeyPair, SerialNumber,
+    PKCS_ED25519,
+};
 use s2n_quic::provider::tls::rustls::rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
     crypto::{CryptoProvider, WebPkiSupportedAlgorithms},
-    server::danger::{ClientCertVerified, ClientCertVerifier},
-    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, Error as RustlsError,
-    PeerIncompatible, PeerMisbehaved, ServerConfig, SignatureScheme,
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        ClientHello, ResolvesServerCert,
+    },
+    sign::CertifiedKey,
+    CertificateError, CipherSuite, ClientConfig, DigitallySignedStruct, DistinguishedName,
+    Error as RustlsError, KeyLog, PeerIncompatible, PeerMisbehaved, ServerConfig, SignatureScheme,
+    SupportedCipherSuite,
 };
 use s2n_quic_rustls::rustls::{crypto::aws_lc_rs, version::TLS13, SupportedProtocolVersion};
-use std::{str::FromStr, sync::Arc};
-use subtle::ConstantTimeEq;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, Once, OnceLock},
+    time::{Duration, Instant},
+};
+use subtle::{Choice, ConstantTimeEq};
 use thiserror::Error;
 use webpki::{
     types::{
@@ -22,6 +42,8 @@ use webpki::{
     EndEntityCert,
 };
 
+use crate::timings::TIMINGS;
+
 const QCAT_ALPN: &[u8; 4] = b"qcat";
 
 const PASSPHRASE_WORD_COUNT: u8 = 3;
@@ -29,12 +51,94 @@ const PASSPHRASE_WORD_DELIM: char = '-';
 
 const DERIVED_KEY_SIZE: usize = 32;
 
+/// Fixed passphrase used when benchmarking the KDF
+const KDF_BENCHMARK_PASSPHRASE: &str = "benchmark-correct-horse-battery";
+
+/// Length in bytes of our derived cert serial numbers
+const SERIAL_NUMBER_LEN: usize = 16;
+
+/// Fixed validity period of our derived certs, matching rcgen's defaults
+const CERT_NOT_BEFORE_YEAR: i32 = 1975;
+const CERT_NOT_AFTER_YEAR: i32 = 4096;
+
+/// Fewest words we expect in the embedded wordlist, so a default passphrase has at least ~46 bits of entropy
+const MIN_WORDLIST_LEN: usize = 50_000;
+
+/// Word lengths select_easy_words keeps, short enough to type comfortably between devices
+const EASY_WORD_LENS: RangeInclusive<usize> = 4..=7;
+
+/// Joins the words of a multi-word salt. Anything but PASSPHRASE_WORD_DELIM, as the salt ends at the first one
+const SALT_WORD_DELIM: char = '.';
+
+/// Bytes of randomness in a random (rather than word based) salt
+const RANDOM_SALT_LEN: usize = 16;
+
+/// Number of words in the cert fingerprint phrase
+const FINGERPRINT_PHRASE_WORD_COUNT: usize = 3;
+
+/// Number of bytes of the public key digest used in the server name hint
+const SERVER_NAME_HINT_LEN: usize = 8;
+
 static SUPPORTED_TLS_VERSIONS: &[&SupportedProtocolVersion] = &[&TLS13];
 
+/// Environment variable naming a file to write TLS secrets to, in the NSS key log format Wireshark reads. Only the
+/// qcat binary reads it, library users opt in with QcatCryptoConfig::with_key_log_file
+pub const KEY_LOG_FILE_ENV: &str = "SSLKEYLOGFILE";
+
+/// Whether we've warned that TLS secrets are being logged, so it's only said once however many configs we build
+static KEY_LOG_WARNED: Once = Once::new();
+
 #[derive(Debug, Error)]
 pub enum CryptoError {
     #[error("Unable to parse salt and passphrase given")]
     SaltedPassphraseParseError,
+    #[error("Unknown TLS1.3 cipher suite: {0} (supported: {1})")]
+    UnknownCipherSuite(String, String),
+    #[error("Unknown signature scheme: {0} (supported: {1})")]
+    UnknownSignatureScheme(String, String),
+    #[error("Only {words} easy words, giving ~{bits:.1} bit passphrases (need at least ~{minimum_bits:.1})")]
+    NotEnoughEasyWords {
+        words: usize,
+        bits: f64,
+        minimum_bits: f64,
+    },
+    #[error("None of the {0} allowed are permitted by --security-level high")]
+    BelowSecurityLevel(&'static str),
+}
+
+/// Logs our TLS secrets to the file at path (i.e. from SSLKEYLOGFILE), so Wireshark can decrypt captured traffic. This
+/// is for debugging only: anyone holding the file can read everything sent over connections made while it's set
+fn key_log(path: &Path) -> Option<Arc<dyn KeyLog>> {
+    let file = match OpenOptions::new().append(true).create(true).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Unable to open key log file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    KEY_LOG_WARNED.call_once(|| {
+        warn!(
+            "Writing TLS secrets to {}. Anyone with this file can decrypt captured traffic, only use it for debugging",
+            path.display()
+        );
+    });
+
+    Some(Arc::new(KeyLogWriter(Mutex::new(file))))
+}
+
+/// Writes TLS secrets in the NSS key log format, one "label client_random secret" line (both hex) per secret
+#[derive(Debug)]
+struct KeyLogWriter(Mutex<File>);
+
+impl KeyLog for KeyLogWriter {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Unable to write to key log file: {}", e);
+        }
+    }
 }
 
 /// Our custom ALPN protocol. Not really a protocol per se as the client is just sending raw bytes
@@ -55,6 +159,35 @@ pub struct SaltedPassphrase {
 }
 
 impl SaltedPassphrase {
+    /// Combine a salt distributed out-of-band with a passphrase
+    pub fn new(salt: &str, passphrase: &str) -> Self {
+        Self {
+            salt: salt.to_owned(),
+            passphrase: passphrase.to_owned(),
+        }
+    }
+
+    /// Parse a salted passphrase, or when we were given a salt out-of-band, just the passphrase
+    pub fn parse_with_salt(s: &str, salt: Option<&str>) -> Result<Self, CryptoError> {
+        match salt {
+            Some(salt) => Ok(Self::new(salt, s)),
+            None => Self::from_str(s),
+        }
+    }
+
+    pub fn salt(&self) -> &str {
+        &self.salt
+    }
+
+    pub fn passphrase(&self) -> &str {
+        &self.passphrase
+    }
+
+    /// Whether the salt is shorter than argon2 recommends, which weakens the key derived from it
+    pub fn salt_is_short(&self) -> bool {
+        self.salt.len() < RECOMMENDED_SALT_LEN
+    }
+
     fn passphrase_as_bytes(&self) -> &[u8] {
         self.passphrase.as_bytes()
     }
@@ -85,26 +218,70 @@ impl fmt::Display for SaltedPassphrase {
     }
 }
 
+/// What of the peer's cert we pin
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PinMode {
+    /// The whole cert must match byte for byte
+    #[default]
+    Cert,
+    /// Only the cert's public key (SubjectPublicKeyInfo) must match, so other cert fields like the serial or validity
+    /// can differ as long as the key derived from the passphrase is the same
+    Spki,
+}
+
 /// Our cert verifier. This can verify both client and server certs, it simply checks if the certs are the same and
 /// verifies the other party holds the certificate's private key material
 #[derive(Debug)]
 struct PinnedCertVerifier {
-    pinned_cert: CertificateDer<'static>,
+    /// Any of these certs are accepted, more than one is pinned during a passphrase rotation window
+    pinned_certs: Vec<CertificateDer<'static>>,
+    pin_mode: PinMode,
+    /// Log how a mismatched cert differs from the one we pinned, see explain_cert_mismatch
+    explain_cert: bool,
     supported_algs: WebPkiSupportedAlgorithms,
+    /// Signature schemes we accept from the peer, a subset of SUPPORTED_SIGNATURE_SCHEMES
+    signature_schemes: Vec<SignatureScheme>,
     /// We need to return a &[DistinguishedName] in our ClientVerifier for root_hint_subjects. We don't care about
     /// the root hints so just leave it as an empty array
     root_hints: [DistinguishedName; 0],
 }
 
 impl PinnedCertVerifier {
-    fn new(pinned_cert: CertificateDer<'_>, supported_algs: WebPkiSupportedAlgorithms) -> Self {
-        let pinned_cert = pinned_cert.into_owned();
+    fn new(
+        pinned_certs: Vec<CertificateDer<'static>>,
+        pin_mode: PinMode,
+        explain_cert: bool,
+        supported_algs: WebPkiSupportedAlgorithms,
+        signature_schemes: Vec<SignatureScheme>,
+    ) -> Self {
         Self {
-            pinned_cert,
+            pinned_certs,
+            pin_mode,
+            explain_cert,
             supported_algs,
+            signature_schemes,
             root_hints: [],
         }
     }
+
+    /// Check the peer's cert against our pinned ones, explaining a mismatch if asked to. Not called for resumed
+    /// sessions, which skip sending certs
+    fn verify(&self, end_entity: &CertificateDer<'_>) -> Result<(), RustlsError> {
+        if pinned_cert_is_valid(&self.pinned_certs, end_entity, self.pin_mode) {
+            debug!("Peer's cert matches our pin");
+            return Ok(());
+        }
+
+        if self.explain_cert {
+            warn!(
+                "{}",
+                explain_cert_mismatch(&self.pinned_certs[0], end_entity)
+            );
+        }
+        Err(RustlsError::InvalidCertificate(
+            CertificateError::InvalidPurpose,
+        ))
+    }
 }
 
 impl ServerCertVerifier for PinnedCertVerifier {
@@ -112,17 +289,13 @@ impl ServerCertVerifier for PinnedCertVerifier {
         &self,
         end_entity: &CertificateDer<'_>,
         _intermediates: &[CertificateDer<'_>],
+        // we pin the cert, so the name we asked for (i.e. --sni, for routing) has no say in whether it's valid
         _server_name: &ServerName<'_>,
         _ocsp_response: &[u8],
         _now: UnixTime,
     ) -> Result<ServerCertVerified, RustlsError> {
-        if pinned_cert_is_valid(&self.pinned_cert, end_entity) {
-            Ok(ServerCertVerified::assertion())
-        } else {
-            Err(RustlsError::InvalidCertificate(
-                CertificateError::InvalidPurpose,
-            ))
-        }
+        self.verify(end_entity)
+            .map(|_| ServerCertVerified::assertion())
     }
 
     /// Since we are using quic only, we don't support tls1.2
@@ -143,11 +316,21 @@ impl ServerCertVerifier for PinnedCertVerifier {
         cert: &CertificateDer<'_>,
         dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, RustlsError> {
-        verify_tls13_signature(message, cert, dss, &self.supported_algs)
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.supported_algs,
+            &self.signature_schemes,
+        )
     }
 
     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        self.supported_algs.supported_schemes()
+        self.supported_algs
+            .supported_schemes()
+            .into_iter()
+            .filter(|scheme| self.signature_schemes.contains(scheme))
+            .collect()
     }
 }
 
@@ -162,13 +345,8 @@ impl ClientCertVerifier for PinnedCertVerifier {
         _intermediates: &[CertificateDer<'_>],
         _now: UnixTime,
     ) -> Result<ClientCertVerified, RustlsError> {
-        if pinned_cert_is_valid(&self.pinned_cert, end_entity) {
-            Ok(ClientCertVerified::assertion())
-        } else {
-            Err(RustlsError::InvalidCertificate(
-                CertificateError::InvalidPurpose,
-            ))
-        }
+        self.verify(end_entity)
+            .map(|_| ClientCertVerified::assertion())
     }
 
     /// Since we are using quic only, we don't support tls1.2
@@ -189,26 +367,75 @@ impl ClientCertVerifier for PinnedCertVerifier {
         cert: &CertificateDer<'_>,
         dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, RustlsError> {
-        verify_tls13_signature(message, cert, dss, &self.supported_algs)
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.supported_algs,
+            &self.signature_schemes,
+        )
     }
 
     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        self.supported_algs.supported_schemes()
+        self.supported_algs
+            .supported_schemes()
+            .into_iter()
+            .filter(|scheme| self.signature_schemes.contains(scheme))
+            .collect()
     }
 }
 
-/// Verifies a given signature scheme is supported
-fn signature_scheme_is_supported(scheme: &SignatureScheme) -> bool {
-    matches!(
-        scheme,
-        SignatureScheme::ECDSA_NISTP256_SHA256
-            | SignatureScheme::ECDSA_NISTP384_SHA384
-            | SignatureScheme::ECDSA_NISTP521_SHA512
-            | SignatureScheme::ED25519
-            | SignatureScheme::ED448
-            // TODO: clean up rsa
-            | SignatureScheme::RSA_PSS_SHA512
-    )
+/// Signature schemes we support, operators can restrict these further at runtime
+const SUPPORTED_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP521_SHA512,
+    SignatureScheme::ED25519,
+    SignatureScheme::ED448,
+    // TODO: clean up rsa
+    SignatureScheme::RSA_PSS_SHA512,
+];
+
+/// How strict we are about the TLS parameters we'll negotiate, on top of any --ciphers/--signature-schemes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SecurityLevel {
+    /// Everything we support
+    #[default]
+    Std,
+    /// Only 256 bit cipher suites (TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256), only Ed25519, Ed448 and
+    /// ECDSA P-384/P-521 signatures, and only the X25519MLKEM768 post-quantum hybrid key exchange. A peer that can't
+    /// do all of these fails the handshake
+    High,
+}
+
+/// Cipher suites allowed at SecurityLevel::High
+const HIGH_SECURITY_CIPHER_SUITES: &[CipherSuite] = &[
+    CipherSuite::TLS13_AES_256_GCM_SHA384,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+/// Signature schemes allowed at SecurityLevel::High
+const HIGH_SECURITY_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP521_SHA512,
+    SignatureScheme::ED25519,
+    SignatureScheme::ED448,
+];
+
+/// Names of the signature schemes we support
+pub fn signature_scheme_names() -> Vec<String> {
+    SUPPORTED_SIGNATURE_SCHEMES
+        .iter()
+        .map(|scheme| format!("{:?}", scheme))
+        .collect()
+}
+
+/// Looks up a signature scheme we support by name, case insensitive
+fn signature_scheme_from_name(name: &str) -> Option<SignatureScheme> {
+    SUPPORTED_SIGNATURE_SCHEMES
+        .iter()
+        .find(|scheme| format!("{:?}", scheme).eq_ignore_ascii_case(name))
+        .copied()
 }
 
 /// Matches a SignatureScheme to a SignatureVerificationAlgorithm
@@ -230,13 +457,137 @@ fn convert_scheme(
         .ok_or_else(|| PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme.into())
 }
 
-/// Verifies two certificates are the same. Uses best-effort constant time comparison from subtle
+/// Verifies a certificate is the same as any of our pinned certificates. Uses best-effort constant time comparison
+/// from subtle, comparing against every pinned cert rather than stopping at the first match
 fn pinned_cert_is_valid(
-    expected_pinned_cert: &CertificateDer<'_>,
+    expected_pinned_certs: &[CertificateDer<'_>],
     end_entity_cert: &CertificateDer<'_>,
+    pin_mode: PinMode,
 ) -> bool {
     // TODO: add more info here, like cert fingerprint
-    expected_pinned_cert.ct_eq(end_entity_cert).into()
+    match pin_mode {
+        PinMode::Cert => expected_pinned_certs
+            .iter()
+            .fold(Choice::from(0), |matched, pinned_cert| {
+                matched | pinned_cert.ct_eq(end_entity_cert)
+            })
+            .into(),
+        PinMode::Spki => {
+            let Some(end_entity_spki_hash) = spki_hash(end_entity_cert) else {
+                return false;
+            };
+            expected_pinned_certs
+                .iter()
+                .filter_map(spki_hash)
+                .fold(Choice::from(0), |matched, pinned_spki_hash| {
+                    matched | pinned_spki_hash[..].ct_eq(&end_entity_spki_hash[..])
+                })
+                .into()
+        }
+    }
+}
+
+/// SHA-256 of a cert's SubjectPublicKeyInfo, or None if the cert doesn't parse
+fn spki_hash(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info()).into())
+}
+
+/// The fields of a cert that tell why it doesn't match the one we pinned
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CertSummary {
+    subject: String,
+    spki_algorithm: String,
+    /// SHA-256 of the raw public key, in hex
+    public_key_hash: String,
+    not_before: String,
+    not_after: String,
+}
+
+impl CertSummary {
+    fn parse(cert_der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert_der).ok()?;
+        let public_key = cert.public_key();
+        let algorithm = &public_key.algorithm.algorithm;
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            spki_algorithm: match spki_algorithm_name(&algorithm.to_id_string()) {
+                Some(name) => format!("{} ({})", name, algorithm),
+                None => algorithm.to_id_string(),
+            },
+            public_key_hash: Sha256::digest(&public_key.subject_public_key.data)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            not_before: cert.validity().not_before.to_string(),
+            not_after: cert.validity().not_after.to_string(),
+        })
+    }
+
+    fn fields(&self) -> [(&'static str, &str); 5] {
+        [
+            ("subject", &self.subject),
+            ("SPKI algorithm", &self.spki_algorithm),
+            ("public key SHA-256", &self.public_key_hash),
+            ("not before", &self.not_before),
+            ("not after", &self.not_after),
+        ]
+    }
+}
+
+/// Names of the public key algorithms a peer's cert is likely to use, by OID
+fn spki_algorithm_name(oid: &str) -> Option<&'static str> {
+    match oid {
+        "1.3.101.112" => Some("Ed25519"),
+        "1.3.101.113" => Some("Ed448"),
+        "1.2.840.10045.2.1" => Some("ECDSA"),
+        "1.2.840.113549.1.1.1" => Some("RSA"),
+        _ => None,
+    }
+}
+
+/// Describe how the cert we were presented differs from the one we expected, field by field, marking those that
+/// differ. A different public key of the same type usually means a different passphrase, a different SPKI algorithm
+/// means the peer isn't using a passphrase derived cert at all
+pub fn explain_cert_mismatch(expected: &[u8], received: &[u8]) -> String {
+    let (Some(expected), Some(received)) =
+        (CertSummary::parse(expected), CertSummary::parse(received))
+    else {
+        return "Certificate mismatch, and one of the certs couldn't be parsed to explain why"
+            .to_owned();
+    };
+
+    let mut explanation = String::from("Certificate mismatch (expected / received):");
+    for ((name, expected), (_, received)) in expected.fields().into_iter().zip(received.fields()) {
+        let marker = if expected == received { ' ' } else { '*' };
+        explanation.push_str(&format!(
+            "\n  {} {:<18} {} / {}",
+            marker, name, expected, received
+        ));
+    }
+    explanation
+}
+
+/// Server cert resolver used when we hold multiple identities. Clients send a hint derived from their passphrase's
+/// public key as the server name so we can present the cert matching their passphrase
+#[derive(Debug)]
+struct PinnedCertResolver {
+    default_key: Arc<CertifiedKey>,
+    keys_by_server_name: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl ResolvesServerCert for PinnedCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello.server_name().and_then(|server_name| {
+            self.keys_by_server_name
+                .iter()
+                .find(|(name, _)| name == server_name)
+                .map(|(_, key)| key.clone())
+        });
+
+        Some(key.unwrap_or_else(|| self.default_key.clone()))
+    }
 }
 
 /// Verifies a tls13 signature
@@ -245,8 +596,9 @@ fn verify_tls13_signature(
     cert: &CertificateDer<'_>,
     dss: &DigitallySignedStruct,
     supported_algs: &WebPkiSupportedAlgorithms,
+    signature_schemes: &[SignatureScheme],
 ) -> Result<HandshakeSignatureValid, RustlsError> {
-    if !signature_scheme_is_supported(&dss.scheme) {
+    if !signature_schemes.contains(&dss.scheme) {
         Err(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme.into())
     } else {
         let alg = convert_scheme(*supported_algs, &dss.scheme)?[0];
@@ -260,13 +612,46 @@ fn verify_tls13_signature(
     }
 }
 
+/// Names of the TLS1.3 cipher suites offered by aws-lc-rs
+pub fn tls13_cipher_suite_names() -> Vec<String> {
+    aws_lc_rs::ALL_CIPHER_SUITES
+        .iter()
+        .filter(|suite| suite.tls13().is_some())
+        .map(|suite| format!("{:?}", suite.suite()))
+        .collect()
+}
+
+/// Looks up a TLS1.3 cipher suite offered by aws-lc-rs by name, case insensitive
+fn tls13_cipher_suite_from_name(name: &str) -> Option<SupportedCipherSuite> {
+    aws_lc_rs::ALL_CIPHER_SUITES
+        .iter()
+        .filter(|suite| suite.tls13().is_some())
+        .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+        .copied()
+}
+
 /// Crypto configuration for Qcat client/server
 #[derive(Debug)]
 pub struct QcatCryptoConfig<'a> {
     provider: Arc<CryptoProvider>,
     pinned_cert: &'a CertificateDer<'a>,
     pinned_cert_private_key: &'a PrivateKeyDer<'a>,
+    additional_identities: Vec<PinnedIdentity<'a>>,
     alpn_protocol: QcatAlpnProtocol,
+    early_data: bool,
+    pin_mode: PinMode,
+    explain_cert: bool,
+    signature_schemes: Vec<SignatureScheme>,
+    /// Where to write our TLS secrets, see key_log
+    key_log_file: Option<PathBuf>,
+}
+
+/// An extra identity the server accepts, i.e. the old passphrase during a rotation window
+#[derive(Debug)]
+struct PinnedIdentity<'a> {
+    server_name_hint: String,
+    cert: &'a CertificateDer<'a>,
+    private_key: &'a PrivateKeyDer<'a>,
 }
 
 impl<'a> QcatCryptoConfig<'a> {
@@ -280,8 +665,142 @@ impl<'a> QcatCryptoConfig<'a> {
             provider,
             pinned_cert,
             pinned_cert_private_key,
+            additional_identities: Vec::new(),
             alpn_protocol,
+            early_data: false,
+            pin_mode: PinMode::default(),
+            explain_cert: false,
+            signature_schemes: SUPPORTED_SIGNATURE_SCHEMES.to_vec(),
+            key_log_file: None,
+        }
+    }
+
+    /// Accept an additional pinned identity (cert + private key), used by the server to accept multiple passphrases
+    /// while rotating. Clients sending the given server name hint are presented this identity's cert
+    pub fn with_additional_identity(
+        mut self,
+        server_name_hint: String,
+        cert: &'a CertificateDer,
+        private_key: &'a PrivateKeyDer,
+    ) -> Self {
+        self.additional_identities.push(PinnedIdentity {
+            server_name_hint,
+            cert,
+            private_key,
+        });
+        self
+    }
+
+    /// Restrict the cipher suites offered/accepted to the given TLS1.3 suite names (i.e. TLS13_AES_256_GCM_SHA384).
+    /// Both peers need to share at least one suite or the handshake will fail
+    pub fn with_cipher_suites(mut self, suite_names: &[String]) -> Result<Self, CryptoError> {
+        if suite_names.is_empty() {
+            return Ok(self);
+        }
+
+        let mut cipher_suites = Vec::with_capacity(suite_names.len());
+        for name in suite_names {
+            let suite = tls13_cipher_suite_from_name(name).ok_or_else(|| {
+                CryptoError::UnknownCipherSuite(
+                    name.to_owned(),
+                    tls13_cipher_suite_names().join(", "),
+                )
+            })?;
+            cipher_suites.push(suite);
+        }
+
+        let mut provider = (*self.provider).clone();
+        provider.cipher_suites = cipher_suites;
+        self.provider = Arc::new(provider);
+
+        Ok(self)
+    }
+
+    /// Restrict the signature schemes we accept from the peer to the named ones (i.e. ED25519), case insensitive. Our
+    /// derived certs are Ed25519, so leaving ED25519 out means no qcat peer can connect
+    pub fn with_signature_schemes(mut self, scheme_names: &[String]) -> Result<Self, CryptoError> {
+        if scheme_names.is_empty() {
+            return Ok(self);
         }
+
+        self.signature_schemes = scheme_names
+            .iter()
+            .map(|name| {
+                signature_scheme_from_name(name).ok_or_else(|| {
+                    CryptoError::UnknownSignatureScheme(
+                        name.to_owned(),
+                        signature_scheme_names().join(", "),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(self)
+    }
+
+    /// Only use the X25519MLKEM768 post-quantum hybrid key exchange, so a future quantum computer can't decrypt a
+    /// recorded session. Both peers need this (and rustls 0.23.18+) or the handshake fails
+    pub fn with_post_quantum_key_exchange(mut self, post_quantum: bool) -> Self {
+        if post_quantum {
+            let mut provider = (*self.provider).clone();
+            provider.kx_groups = vec![aws_lc_rs::kx_group::X25519MLKEM768];
+            self.provider = Arc::new(provider);
+        }
+        self
+    }
+
+    /// Allow 0-RTT early data on resumed sessions. Our certs are static per passphrase so resuming is safe, but early
+    /// data can be replayed by an attacker so this is opt in. Sessions are cached in memory, so only reconnects from
+    /// the same client (i.e. repeated QcatClient runs in one process) resume. Note s2n-quic doesn't send application
+    /// data in 0-RTT yet, until it does this saves the resumed handshake's cert verification rather than a round trip
+    pub fn with_early_data(mut self, early_data: bool) -> Self {
+        self.early_data = early_data;
+        self
+    }
+
+    /// Apply a security level, narrowing whatever cipher suites and signature schemes are already configured to those
+    /// it allows. Call after with_cipher_suites/with_signature_schemes, fails if that leaves none of either
+    pub fn with_security_level(mut self, level: SecurityLevel) -> Result<Self, CryptoError> {
+        if level == SecurityLevel::Std {
+            return Ok(self);
+        }
+
+        let mut provider = (*self.provider).clone();
+        provider
+            .cipher_suites
+            .retain(|suite| HIGH_SECURITY_CIPHER_SUITES.contains(&suite.suite()));
+        if provider.cipher_suites.is_empty() {
+            return Err(CryptoError::BelowSecurityLevel("cipher suites"));
+        }
+        self.provider = Arc::new(provider);
+
+        self.signature_schemes
+            .retain(|scheme| HIGH_SECURITY_SIGNATURE_SCHEMES.contains(scheme));
+        if self.signature_schemes.is_empty() {
+            return Err(CryptoError::BelowSecurityLevel("signature schemes"));
+        }
+
+        Ok(self.with_post_quantum_key_exchange(true))
+    }
+
+    /// Pin just the peer's public key rather than its whole cert. Each side applies its own mode to the cert it's
+    /// presented, so this only needs setting where certs may differ in more than their key
+    pub fn with_pin_mode(mut self, pin_mode: PinMode) -> Self {
+        self.pin_mode = pin_mode;
+        self
+    }
+
+    /// On a cert mismatch, log how the peer's cert differs from ours (subject, key type, key, validity) to tell a
+    /// wrong passphrase apart from a peer that isn't qcat at all
+    pub fn with_explain_cert(mut self, explain_cert: bool) -> Self {
+        self.explain_cert = explain_cert;
+        self
+    }
+
+    /// Write our TLS secrets to key_log_file for Wireshark, see key_log. Off (None) unless asked for
+    pub fn with_key_log_file(mut self, key_log_file: Option<PathBuf>) -> Self {
+        self.key_log_file = key_log_file;
+        self
     }
 
     /// Build our rustls client config. This is what specifies our TLS configuration/certificate verification
@@ -298,34 +817,155 @@ impl<'a> QcatCryptoConfig<'a> {
         client_config
             .alpn_protocols
             .clone_from(&self.alpn_protocol.0);
+        client_config.enable_early_data = self.early_data;
+        if let Some(key_log) = self.key_log_file.as_deref().and_then(key_log) {
+            client_config.key_log = key_log;
+        }
 
         Ok(client_config)
     }
 
     /// Build our rustls server config. This is what specifies our TLS configuration/certificate verification
     pub fn build_server_config(&self) -> Result<ServerConfig, Box<dyn std::error::Error>> {
-        let mut server_config = ServerConfig::builder_with_provider(self.provider.clone())
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
             .with_protocol_versions(SUPPORTED_TLS_VERSIONS)?
-            .with_client_cert_verifier(Arc::new(self.build_verifier()))
-            .with_single_cert(
+            .with_client_cert_verifier(Arc::new(self.build_verifier()));
+
+        let mut server_config = if self.additional_identities.is_empty() {
+            builder.with_single_cert(
                 vec![self.pinned_cert.clone().into_owned()],
                 self.pinned_cert_private_key.clone_key(),
-            )?;
+            )?
+        } else {
+            builder.with_cert_resolver(Arc::new(self.build_cert_resolver()?))
+        };
 
         server_config
             .alpn_protocols
             .clone_from(&self.alpn_protocol.0);
+        if self.early_data {
+            // QUIC requires either no early data or an unlimited amount
+            server_config.max_early_data_size = u32::MAX;
+        }
+        if let Some(key_log) = self.key_log_file.as_deref().and_then(key_log) {
+            server_config.key_log = key_log;
+        }
 
         Ok(server_config)
     }
 
     /// Our certificate verifier, used by both client and server
     fn build_verifier(&self) -> PinnedCertVerifier {
+        let pinned_certs = std::iter::once(self.pinned_cert)
+            .chain(
+                self.additional_identities
+                    .iter()
+                    .map(|identity| identity.cert),
+            )
+            .map(|cert| cert.clone().into_owned())
+            .collect();
+
         PinnedCertVerifier::new(
-            self.pinned_cert.clone().into_owned(),
+            pinned_certs,
+            self.pin_mode,
+            self.explain_cert,
             self.provider.signature_verification_algorithms,
+            self.signature_schemes.clone(),
         )
     }
+
+    /// Our server cert resolver, used when the server holds more than one identity
+    fn build_cert_resolver(&self) -> Result<PinnedCertResolver, RustlsError> {
+        let default_key =
+            self.build_certified_key(self.pinned_cert, self.pinned_cert_private_key)?;
+
+        let keys_by_server_name = self
+            .additional_identities
+            .iter()
+            .map(|identity| {
+                self.build_certified_key(identity.cert, identity.private_key)
+                    .map(|key| (identity.server_name_hint.clone(), key))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(PinnedCertResolver {
+            default_key,
+            keys_by_server_name,
+        })
+    }
+
+    fn build_certified_key(
+        &self,
+        cert: &CertificateDer,
+        private_key: &PrivateKeyDer,
+    ) -> Result<Arc<CertifiedKey>, RustlsError> {
+        let signing_key = self
+            .provider
+            .key_provider
+            .load_private_key(private_key.clone_key())?;
+
+        Ok(Arc::new(CertifiedKey::new(
+            vec![cert.clone().into_owned()],
+            signing_key,
+        )))
+    }
+}
+
+/// Argon2id cost parameters used to derive our private key. Both sides must use the same parameters to derive the same
+/// key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+}
+
+impl Default for KdfParams {
+    /// argon2's defaults, which is what we've always used
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    pub(crate) fn argon2(&self) -> Result<Argon2<'static>, argon2::Error> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            Params::DEFAULT_P_COST,
+            None,
+        )?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// Time a single key derivation with these parameters
+    pub fn benchmark(&self) -> Result<Duration, Box<dyn std::error::Error>> {
+        let passphrase = SaltedPassphrase::from_str(KDF_BENCHMARK_PASSPHRASE)?;
+
+        let start = Instant::now();
+        CryptoMaterial::derive_private_key(&passphrase, *self)?;
+        Ok(start.elapsed())
+    }
+}
+
+/// What the salt of a generated passphrase is made of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaltKind {
+    /// This many words from our wordlist, joined by '.'
+    Words(usize),
+    /// Random bytes, hex encoded
+    Random,
+    /// A salt distributed out-of-band, i.e. baked into a device
+    Fixed(String),
+}
+
+impl Default for SaltKind {
+    fn default() -> Self {
+        Self::Words(1)
+    }
 }
 
 /// Creates and stores our crypto materials (passphrase, private key, cert)
@@ -333,58 +973,157 @@ impl<'a> QcatCryptoConfig<'a> {
 pub struct CryptoMaterial {
     passphrase: SaltedPassphrase,
     private_key: PrivatePkcs8KeyDer<'static>,
+    // the same key in the form QcatCryptoConfig borrows, kept here so configs can borrow it from us
+    private_key_der: PrivateKeyDer<'static>,
     certificate: CertificateDer<'static>,
 }
 
 impl CryptoMaterial {
+    fn new(
+        passphrase: SaltedPassphrase,
+        private_key: PrivatePkcs8KeyDer<'static>,
+        certificate: CertificateDer<'static>,
+    ) -> Self {
+        let private_key_der = PrivateKeyDer::Pkcs8(private_key.clone_key());
+
+        Self {
+            passphrase,
+            private_key,
+            private_key_der,
+            certificate,
+        }
+    }
+
+    /// Crypto config using our cert and private key. Use QcatCryptoConfig::new directly for anything more custom
+    pub fn crypto_config(&self) -> QcatCryptoConfig<'_> {
+        QcatCryptoConfig::new(&self.certificate, &self.private_key_der)
+    }
+
     pub fn private_key(&self) -> &PrivatePkcs8KeyDer<'static> {
         &self.private_key
     }
 
+    pub fn private_key_der(&self) -> &PrivateKeyDer<'static> {
+        &self.private_key_der
+    }
+
     pub fn certificate(&self) -> &CertificateDer<'static> {
         &self.certificate
     }
 
+    /// Raw DER bytes of our cert
+    pub fn certificate_der_bytes(&self) -> &[u8] {
+        self.certificate.as_ref()
+    }
+
     pub fn passphrase(&self) -> &SaltedPassphrase {
         &self.passphrase
     }
 
+    /// A short hint derived from our public key, sent by the client as the TLS server name so a server holding
+    /// multiple passphrases can present the matching cert. The key depends on both salt and passphrase, so passphrases
+    /// sharing a salt (i.e. with --salt) get hints of their own, and going through the KDF means the hint is no
+    /// cheaper to guess passphrases against than the cert itself
+    pub fn server_name_hint(&self) -> String {
+        let digest: String = spki_hash(&self.certificate)
+            .expect("our own cert parses")
+            .iter()
+            .take(SERVER_NAME_HINT_LEN)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        format!("qcat-{}", digest)
+    }
+
+    /// Fingerprint of our cert, see [`cert_fingerprint`]
+    pub fn fingerprint(&self) -> String {
+        cert_fingerprint(self.certificate_der_bytes())
+    }
+
     /// Generate a cert and private key from a passphrase. Intended to be used by the client with a passphrase generated by the server
     pub fn generate_from_passphrase(
         passphrase: SaltedPassphrase,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let private_key = CryptoMaterial::derive_private_key(&passphrase)?.clone_key();
-        let certificate = CryptoMaterial::generate_certificate(&private_key)?.into_owned();
+        Self::generate_from_passphrase_with_kdf(passphrase, KdfParams::default())
+    }
 
-        Ok(Self {
-            passphrase,
-            private_key,
-            certificate,
-        })
+    /// Same as generate_from_passphrase_with_kdf, with a salt provided separately from the passphrase
+    pub fn generate_from_salt_and_passphrase(
+        salt: &str,
+        passphrase: &str,
+        kdf_params: KdfParams,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::generate_from_passphrase_with_kdf(SaltedPassphrase::new(salt, passphrase), kdf_params)
+    }
+
+    /// Same as generate_from_passphrase, with non-default KDF parameters
+    pub fn generate_from_passphrase_with_kdf(
+        passphrase: SaltedPassphrase,
+        kdf_params: KdfParams,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::generate_from_passphrase_with(passphrase, kdf_params, |_| {})
+    }
+
+    /// Same as generate_from_passphrase_with_kdf, letting customize adjust the cert params (i.e. add names or
+    /// extensions) before the cert is signed. Peers derive and pin the same cert from the passphrase, so customize
+    /// must be deterministic (no clocks, randomness or per-host values) and both sides must apply the same one
+    pub fn generate_from_passphrase_with(
+        passphrase: SaltedPassphrase,
+        kdf_params: KdfParams,
+        customize: impl FnOnce(&mut CertificateParams),
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let private_key = CryptoMaterial::derive_private_key(&passphrase, kdf_params)?.clone_key();
+        let certificate = TIMINGS
+            .time("certificate generation", || {
+                CryptoMaterial::generate_certificate(&private_key, customize)
+            })?
+            .into_owned();
+
+        Ok(Self::new(passphrase, private_key, certificate))
     }
 
     /// Generates all crypto material by itself. Intended to be used the the server component
     pub fn generate() -> Result<CryptoMaterial, Box<dyn std::error::Error>> {
-        let passphrase = CryptoMaterial::generate_passphrase();
-        let private_key = CryptoMaterial::derive_private_key(&passphrase)?.clone_key();
-        let certificate = CryptoMaterial::generate_certificate(&private_key)?.into_owned();
+        Self::generate_with_kdf(KdfParams::default())
+    }
 
-        Ok(Self {
-            passphrase,
-            private_key,
-            certificate,
-        })
+    /// Same as generate, with non-default KDF parameters
+    pub fn generate_with_kdf(
+        kdf_params: KdfParams,
+    ) -> Result<CryptoMaterial, Box<dyn std::error::Error>> {
+        Self::generate_with_salt(kdf_params, SaltKind::default())
     }
 
-    /// Generate a passphrase to be used in our kdf for deriving private keys
-    fn generate_passphrase() -> SaltedPassphrase {
-        let word_list = Wordlist::default();
+    /// Same as generate_with_kdf, with a non-default kind of salt
+    pub fn generate_with_salt(
+        kdf_params: KdfParams,
+        salt_kind: SaltKind,
+    ) -> Result<CryptoMaterial, Box<dyn std::error::Error>> {
+        let passphrase = CryptoMaterial::generate_passphrase(&Wordlist::default(), salt_kind);
+        let private_key = CryptoMaterial::derive_private_key(&passphrase, kdf_params)?.clone_key();
+        let certificate = TIMINGS
+            .time("certificate generation", || {
+                CryptoMaterial::generate_certificate(&private_key, |_| {})
+            })?
+            .into_owned();
 
-        let salt = word_list.get_salt().to_owned();
+        Ok(Self::new(passphrase, private_key, certificate))
+    }
+
+    /// Generate a passphrase from word_list to be used in our kdf for deriving private keys. Words are drawn from the
+    /// OS CSPRNG
+    fn generate_passphrase(word_list: &Wordlist, salt_kind: SaltKind) -> SaltedPassphrase {
+        let rng = &mut OsRng;
+
+        let salt = match salt_kind {
+            SaltKind::Words(count) => word_list.get_salt(count, rng),
+            SaltKind::Random => random_salt(),
+            SaltKind::Fixed(salt) => salt,
+        };
         let mut passphrase = String::new();
 
         (0..PASSPHRASE_WORD_COUNT).for_each(|i| {
-            passphrase.push_str(word_list.get_word());
+            passphrase.push_str(word_list.get_word(rng));
 
             // push our delimiter unless we are on the last word
             if i != PASSPHRASE_WORD_COUNT - 1 {
@@ -398,60 +1137,1067 @@ impl CryptoMaterial {
     /// Derive a private key from our generated passphrase
     fn derive_private_key(
         passphrase: &SaltedPassphrase,
+        kdf_params: KdfParams,
     ) -> Result<PrivatePkcs8KeyDer<'static>, Box<dyn std::error::Error>> {
         let mut derived_key_material = [0u8; DERIVED_KEY_SIZE];
-        Argon2::default().hash_password_into(
-            passphrase.passphrase_as_bytes(),
-            passphrase.salt_as_bytes(),
-            &mut derived_key_material,
-        )?;
+        let argon2 = kdf_params.argon2()?;
+        TIMINGS.time("key derivation", || {
+            argon2.hash_password_into(
+                passphrase.passphrase_as_bytes(),
+                passphrase.salt_as_bytes(),
+                &mut derived_key_material,
+            )
+        })?;
 
         let pkcs8_der_key = SigningKey::from_bytes(&derived_key_material).to_pkcs8_der()?;
 
         Ok(PrivatePkcs8KeyDer::from(pkcs8_der_key.as_bytes()).clone_key())
     }
 
-    // Generate and sign a certificate
+    // Generate and sign a certificate, after customize has had its say on the params
     fn generate_certificate(
         private_key_der: &PrivatePkcs8KeyDer,
+        customize: impl FnOnce(&mut CertificateParams),
     ) -> Result<CertificateDer<'static>, Box<dyn std::error::Error>> {
         // TODO: update cert params from defaults
-        let cert_params = CertificateParams::new(vec![])?;
+        let mut cert_params = CertificateParams::new(vec![])?;
         let signing_keypair =
             KeyPair::from_pkcs8_der_and_sign_algo(private_key_der, &PKCS_ED25519)?;
 
+        // set the CN to a word phrase derived from our public key, so both sides' certs can be visually compared with
+        // standard tooling (i.e. openssl x509). This is derived from the key so the cert stays deterministic
+        cert_params.distinguished_name = RcgenDistinguishedName::new();
+        cert_params.distinguished_name.push(
+            DnType::CommonName,
+            fingerprint_phrase(signing_keypair.public_key_raw()),
+        );
+
+        // pin every field rcgen could otherwise randomize or take from the clock, so certs derived from the same
+        // passphrase are byte for byte identical
+        cert_params.serial_number = Some(deterministic_serial_number(
+            signing_keypair.public_key_raw(),
+        ));
+        cert_params.not_before = date_time_ymd(CERT_NOT_BEFORE_YEAR, 1, 1);
+        cert_params.not_after = date_time_ymd(CERT_NOT_AFTER_YEAR, 1, 1);
+
+        customize(&mut cert_params);
+
         Ok(cert_params.self_signed(&signing_keypair)?.der().clone())
     }
 }
 
-/// Holds our hardcoded wordlist for generating salts/passphrases
+/// Derive the cert serial number from the public key
+fn deterministic_serial_number(public_key: &[u8]) -> SerialNumber {
+    let digest = Sha256::digest(public_key);
+    let mut serial = [0u8; SERIAL_NUMBER_LEN];
+    serial.copy_from_slice(&digest[..SERIAL_NUMBER_LEN]);
+    // serial numbers must be positive
+    serial[0] &= 0x7f;
+
+    SerialNumber::from_slice(&serial)
+}
+
+/// SHA-256 of a DER encoded cert as colon separated hex, matching `openssl x509 -fingerprint -sha256`
+pub fn cert_fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The fingerprint phrase of a DER encoded cert's public key, or None if it doesn't parse. Taken from the key rather
+/// than the CN, which a peer could set to anything
+pub fn cert_fingerprint_phrase(cert_der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der).ok()?;
+    Some(fingerprint_phrase(
+        &cert.public_key().subject_public_key.data,
+    ))
+}
+
+/// Derive a short, recognizable word phrase from a public key
+fn fingerprint_phrase(public_key: &[u8]) -> String {
+    let word_list = Wordlist::new(WordlistLang::English);
+    let digest = Sha256::digest(public_key);
+
+    digest
+        .chunks_exact(4)
+        .take(FINGERPRINT_PHRASE_WORD_COUNT)
+        .map(|chunk| {
+            let index = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+            word_list.words[index % word_list.words.len()]
+        })
+        .collect::<Vec<_>>()
+        .join(&PASSPHRASE_WORD_DELIM.to_string())
+}
+
+/// Languages we have an embedded wordlist for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WordlistLang {
+    #[default]
+    English,
+    Spanish,
+}
+
+impl WordlistLang {
+    /// Language for an ISO 639-1 code, i.e. "en" or "es". Falls back to English for codes we don't have a wordlist for
+    pub fn from_code(code: &str) -> Self {
+        match code.to_ascii_lowercase().as_str() {
+            "en" => Self::English,
+            "es" => Self::Spanish,
+            _ => {
+                warn!("No wordlist for language \"{}\", using English", code);
+                Self::English
+            }
+        }
+    }
+
+    fn words(&self) -> &'static str {
+        match self {
+            // pw file taken from https://github.com/dwyl/english-words
+            // TODO: maybe gzip this to decrease binary size
+            Self::English => include_str!("words_alpha.txt"),
+            // words from Spanish gettext translations, only the plain a-z ones so they're typed and parsed like English
+            Self::Spanish => include_str!("words_es.txt"),
+        }
+    }
+}
+
+/// Wordlist language generated passphrases and salts use, see select_wordlist_lang
+static WORDLIST_LANG: OnceLock<WordlistLang> = OnceLock::new();
+
+/// Select the wordlist language generated passphrases and salts use for the rest of the process. Only the first call
+/// takes effect. Cert fingerprint phrases and the wordlist hash always use English, since both peers must agree on
+/// them, whereas passphrases are only ever generated by the server
+pub fn select_wordlist_lang(lang: WordlistLang) {
+    if WORDLIST_LANG.set(lang).is_err() {
+        warn!("Wordlist language already selected, ignoring {:?}", lang);
+    }
+}
+
+/// Whether generated passphrases and salts only use words in EASY_WORD_LENS, see select_easy_words
+static EASY_WORDS: OnceLock<()> = OnceLock::new();
+
+/// Restrict the words generated passphrases and salts use for the rest of the process to the easily typed ones
+/// (EASY_WORD_LENS letters). This trades some entropy for usability, so fails if the remaining words can't give
+/// passphrases as strong as a full wordlist of MIN_WORDLIST_LEN words would
+pub fn select_easy_words() -> Result<(), CryptoError> {
+    let words = Wordlist::new(WORDLIST_LANG.get().copied().unwrap_or_default())
+        .easy_words()
+        .words
+        .len();
+    let bits = entropy_bits(words);
+    let minimum_bits = entropy_bits(MIN_WORDLIST_LEN);
+    if bits < minimum_bits {
+        return Err(CryptoError::NotEnoughEasyWords {
+            words,
+            bits,
+            minimum_bits,
+        });
+    }
+
+    let _ = EASY_WORDS.set(());
+    Ok(())
+}
+
+/// Number of words in the selected wordlist, after any easy words filter
+pub fn wordlist_len() -> usize {
+    Wordlist::default().words.len()
+}
+
+/// Hash of the English wordlist (the first 8 bytes of its SHA-256), to check peers use the same words
+pub fn wordlist_hash() -> u64 {
+    static WORDLIST_HASH: OnceLock<u64> = OnceLock::new();
+
+    *WORDLIST_HASH.get_or_init(|| Wordlist::new(WordlistLang::English).hash())
+}
+
+/// Entropy of a generated passphrase (excluding the salt) in bits, given the size of the embedded wordlist
+pub fn passphrase_entropy_bits() -> f64 {
+    entropy_bits(wordlist_len())
+}
+
+/// Entropy of a generated passphrase (excluding the salt) in bits, drawing from a wordlist of word_count words
+fn entropy_bits(word_count: usize) -> f64 {
+    f64::from(PASSPHRASE_WORD_COUNT) * (word_count as f64).log2()
+}
+
+/// Rough entropy of any passphrase in bits. One made of words from our wordlist counts each word as a pick from the
+/// list, anything else counts each character as a pick from the classes of characters it uses
+pub fn estimate_entropy_bits(passphrase: &str) -> f64 {
+    let wordlist = Wordlist::default();
+    let words: Vec<&str> = passphrase.split(PASSPHRASE_WORD_DELIM).collect();
+    if words.iter().all(|word| wordlist.words.contains(word)) {
+        return words.len() as f64 * (wordlist.words.len() as f64).log2();
+    }
+
+    let classes = [
+        (char::is_ascii_lowercase as fn(&char) -> bool, 26u32),
+        (char::is_ascii_uppercase, 26),
+        (char::is_ascii_digit, 10),
+    ];
+    let mut alphabet_len: u32 = classes
+        .iter()
+        .filter(|(in_class, _)| passphrase.chars().any(|c| in_class(&c)))
+        .map(|(_, len)| len)
+        .sum();
+    if passphrase.chars().any(|c| !c.is_ascii_alphanumeric()) {
+        // printable ASCII punctuation, plus whatever else they've used
+        alphabet_len += 33;
+    }
+
+    passphrase.chars().count() as f64 * f64::from(alphabet_len.max(1)).log2()
+}
+
+/// A salt of RANDOM_SALT_LEN random bytes, hex encoded. Not base64, as base64url's '-' is our salt/passphrase
+/// delimiter and the standard alphabet's '/' and '+' are awkward to type
+fn random_salt() -> String {
+    let mut salt = [0u8; RANDOM_SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    salt.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Holds one of our hardcoded wordlists for generating salts/passphrases
 #[derive(Debug)]
 struct Wordlist<'a> {
     words: Vec<&'a str>,
 }
 
 impl<'a> Default for Wordlist<'a> {
+    /// The wordlist for the selected language, English unless select_wordlist_lang says otherwise, only keeping easy
+    /// words if select_easy_words was called
     fn default() -> Self {
-        // pw file taken from https://github.com/dwyl/english-words
-        // TODO: maybe gzip this to decrease binary size
-        let words: Vec<&str> = include_str!("words_alpha.txt").split('\n').collect();
-        Self { words }
+        let wordlist = Self::new(WORDLIST_LANG.get().copied().unwrap_or_default());
+        match EASY_WORDS.get() {
+            Some(()) => wordlist.easy_words(),
+            None => wordlist,
+        }
     }
 }
 
 impl<'a> Wordlist<'a> {
+    fn new(lang: WordlistLang) -> Self {
+        let words: Vec<&str> = lang.words().lines().collect();
+        if words.len() < MIN_WORDLIST_LEN {
+            warn!(
+                "Wordlist only has {} words (expected at least {}), passphrases are weaker than documented",
+                words.len(),
+                MIN_WORDLIST_LEN
+            );
+        }
+
+        Self { words }
+    }
+
+    /// Only the words with a length in EASY_WORD_LENS
+    fn easy_words(self) -> Self {
+        Self {
+            words: self
+                .words
+                .into_iter()
+                .filter(|word| EASY_WORD_LENS.contains(&word.chars().count()))
+                .collect(),
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        let mut hasher = Sha256::new();
+        for word in &self.words {
+            hasher.update(word.as_bytes());
+            hasher.update(b"\n");
+        }
+
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&hasher.finalize()[..8]);
+        u64::from_be_bytes(hash)
+    }
+
     // TODO: maybe wrap these in newtypes
-    fn get_word(&self) -> &str {
-        let offset = OsRng.next_u64() as usize % self.words.len();
-        self.words[offset]
+    /// A uniformly random word, drawn from rng. Takes the rng rather than using OsRng itself so it's clear at the
+    /// call site where our randomness comes from. gen_range samples without modulo bias, whatever our word count and
+    /// the target's pointer width
+    fn get_word(&self, rng: &mut dyn RngCore) -> &str {
+        self.words[rng.gen_range(0..self.words.len())]
     }
 
-    fn get_salt(&self) -> &str {
+    /// A salt of count words joined by SALT_WORD_DELIM, at least as long as argon2 recommends
+    fn get_salt(&self, count: usize, rng: &mut dyn RngCore) -> String {
         loop {
-            let possible_salt = self.get_word();
-            if possible_salt.as_bytes().len() >= RECOMMENDED_SALT_LEN {
+            let possible_salt = (0..count.max(1))
+                .map(|_| self.get_word(rng))
+                .collect::<Vec<_>>()
+                .join(&SALT_WORD_DELIM.to_string());
+            if possible_salt.len() >= RECOMMENDED_SALT_LEN {
                 return possible_salt;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use rustls::internal::msgs::codec::{Codec, Reader};
+
+    #[tokio::test]
+    async fn single_cipher_suite_handshake() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let suites = ["TLS13_CHACHA20_POLY1305_SHA256".to_owned()];
+        let (server, addr) = test_utils::server(
+            material
+                .crypto_config()
+                .with_cipher_suites(&suites)
+                .unwrap(),
+            QcatOptions::default(),
+        );
+        let client = test_utils::client(
+            material
+                .crypto_config()
+                .with_cipher_suites(&suites)
+                .unwrap(),
+            QcatOptions::default(),
+        );
+
+        let received = test_utils::transfer(server, addr, client, &mut &b"hello"[..], 5).await;
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rotation_accepts_old_and_new_passphrases() {
+        let new = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let old = test_utils::material("qcatoldsaltsalt-battery-staple-horse");
+        let unknown = test_utils::material("qcatunknownsalt-neither-of-these");
+        let (server, addr) = test_utils::server(
+            new.crypto_config().with_additional_identity(
+                old.server_name_hint(),
+                old.certificate(),
+                old.private_key_der(),
+            ),
+            QcatOptions::default(),
+        );
+        let client_for = |material: &CryptoMaterial| {
+            test_utils::client(
+                material.crypto_config(),
+                QcatOptions {
+                    server_name: Some(material.server_name_hint()),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let (received, rejected) =
+            test_utils::with_receiving_server(server, |received| async move {
+                for material in [&new, &old] {
+                    client_for(material)
+                        .run(addr, &mut &b"rotate"[..])
+                        .await
+                        .unwrap();
+                }
+                test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 12)).await;
+                client_for(&unknown).run(addr, &mut &b"intruder"[..]).await
+            })
+            .await;
+
+        assert_eq!(received, b"rotaterotate");
+        assert!(rejected.is_err());
+    }
+
+    #[tokio::test]
+    async fn rotation_works_with_a_shared_salt() {
+        // as with --salt, which every --passphrase shares
+        let new = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let old = test_utils::material("qcattestsaltsalt-battery-staple-horse");
+        assert_eq!(new.passphrase().salt(), old.passphrase().salt());
+        assert_ne!(new.server_name_hint(), old.server_name_hint());
+
+        let (server, addr) = test_utils::server(
+            new.crypto_config().with_additional_identity(
+                old.server_name_hint(),
+                old.certificate(),
+                old.private_key_der(),
+            ),
+            QcatOptions::default(),
+        );
+        let received = test_utils::with_receiving_server(server, |received| async move {
+            for material in [&old, &new] {
+                test_utils::client(
+                    material.crypto_config(),
+                    QcatOptions {
+                        server_name: Some(material.server_name_hint()),
+                        ..Default::default()
+                    },
+                )
+                .run(addr, &mut &b"rotate"[..])
+                .await
+                .unwrap();
+            }
+            test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 12)).await;
+        })
+        .await
+        .0;
+
+        assert_eq!(received, b"rotaterotate");
+    }
+
+    /// The CN of a DER encoded cert
+    fn common_name(cert_der: &[u8]) -> String {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert_der).unwrap();
+        let common_name = cert.subject().iter_common_name().next().unwrap();
+        common_name.as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn derived_certs_are_deterministic() {
+        let first = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let second = test_utils::material(test_utils::TEST_PASSPHRASE);
+        assert_eq!(
+            first.certificate_der_bytes(),
+            second.certificate_der_bytes()
+        );
+
+        let name = common_name(first.certificate_der_bytes());
+        assert_eq!(name, common_name(second.certificate_der_bytes()));
+        assert_eq!(
+            Some(name.clone()),
+            cert_fingerprint_phrase(first.certificate_der_bytes())
+        );
+        assert_eq!(
+            name.split(PASSPHRASE_WORD_DELIM).count(),
+            FINGERPRINT_PHRASE_WORD_COUNT
+        );
+
+        let other = test_utils::material("qcatothersaltsalt-some-other-words");
+        assert_ne!(name, common_name(other.certificate_der_bytes()));
+    }
+
+    #[test]
+    fn unknown_cipher_suite_is_rejected() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let result = material
+            .crypto_config()
+            .with_cipher_suites(&["TLS12_NOT_A_SUITE".to_owned()]);
+        assert!(matches!(result, Err(CryptoError::UnknownCipherSuite(..))));
+    }
+
+    #[test]
+    fn kdf_benchmark_grows_with_cost() {
+        // each step costs 8x the last, so timing noise can't reorder them. Best of a few runs to shrug off hiccups
+        let costs = [1024, 8 * 1024, 64 * 1024].map(|memory_kib| KdfParams {
+            memory_kib,
+            iterations: 1,
+        });
+        let times: Vec<Duration> = costs
+            .iter()
+            .map(|params| (0..3).map(|_| params.benchmark().unwrap()).min().unwrap())
+            .collect();
+
+        assert!(
+            times.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            times
+        );
+    }
+
+    #[test]
+    fn cert_serial_and_validity_are_derived() {
+        let first = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let second = test_utils::material(test_utils::TEST_PASSPHRASE);
+        assert_eq!(
+            first.certificate_der_bytes(),
+            second.certificate_der_bytes()
+        );
+
+        let (_, cert) = x509_parser::parse_x509_certificate(first.certificate_der_bytes()).unwrap();
+        assert_eq!(cert.raw_serial().len(), SERIAL_NUMBER_LEN);
+        assert_eq!(cert.raw_serial()[0] & 0x80, 0);
+        assert_eq!(
+            cert.validity().not_before.to_datetime().year(),
+            CERT_NOT_BEFORE_YEAR
+        );
+        assert_eq!(
+            cert.validity().not_after.to_datetime().year(),
+            CERT_NOT_AFTER_YEAR
+        );
+
+        let other = test_utils::material("qcatothersaltsalt-some-other-words");
+        let (_, other_cert) =
+            x509_parser::parse_x509_certificate(other.certificate_der_bytes()).unwrap();
+        assert_ne!(cert.raw_serial(), other_cert.raw_serial());
+    }
+
+    #[test]
+    fn embedded_wordlist_meets_minimum() {
+        let wordlist = Wordlist::new(WordlistLang::English);
+        assert!(wordlist.words.len() >= MIN_WORDLIST_LEN);
+        assert!(wordlist.words.iter().all(|word| !word.is_empty()));
+
+        let floor = f64::from(PASSPHRASE_WORD_COUNT) * (MIN_WORDLIST_LEN as f64).log2();
+        let bits = f64::from(PASSPHRASE_WORD_COUNT) * (wordlist.words.len() as f64).log2();
+        assert!(bits >= floor);
+    }
+
+    #[test]
+    fn client_fingerprint_matches_server() {
+        let server = CryptoMaterial::generate_with_kdf(test_utils::TEST_KDF).unwrap();
+        // what the client derives from the passphrase the server printed
+        let printed = server.passphrase().to_string();
+        let client = test_utils::material(&printed);
+
+        assert_eq!(client.fingerprint(), server.fingerprint());
+        assert_eq!(
+            client.fingerprint(),
+            cert_fingerprint(server.certificate_der_bytes())
+        );
+
+        let other = CryptoMaterial::generate_with_kdf(test_utils::TEST_KDF).unwrap();
+        assert_ne!(other.fingerprint(), server.fingerprint());
+    }
+
+    #[tokio::test]
+    async fn second_connection_resumes() {
+        let logs = test_utils::capture_logs();
+        let verifications = || {
+            logs.records()
+                .iter()
+                .filter(|record| record.message == "Peer's cert matches our pin")
+                .count()
+        };
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let config = || material.crypto_config().with_early_data(true);
+        let (server, addr) = test_utils::server(config(), QcatOptions::default());
+        let mut client = test_utils::client(config(), QcatOptions::default());
+
+        let (output, (first, second)) =
+            test_utils::with_receiving_server(server, |received| async move {
+                client.run(addr, &mut &b"first"[..]).await.unwrap();
+                test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() == 5)).await;
+                let first = verifications();
+
+                client.run(addr, &mut &b"second"[..]).await.unwrap();
+                test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() == 11)).await;
+                (first, verifications())
+            })
+            .await;
+
+        assert_eq!(output, b"firstsecond");
+        // both sides checked the other's cert the first time, resuming skips that
+        assert_eq!(first, 2);
+        assert_eq!(second, first);
+    }
+
+    #[tokio::test]
+    async fn crypto_config_connects_client_and_server() {
+        let server_material = CryptoMaterial::generate_with_kdf(test_utils::TEST_KDF).unwrap();
+        let client_material = test_utils::material(&server_material.passphrase().to_string());
+        let (server, addr) =
+            test_utils::server(server_material.crypto_config(), QcatOptions::default());
+        let client = test_utils::client(client_material.crypto_config(), QcatOptions::default());
+
+        let output = test_utils::transfer(server, addr, client, &mut &b"built"[..], 5).await;
+
+        assert_eq!(output, b"built");
+    }
+
+    #[tokio::test]
+    async fn post_quantum_key_exchange() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let pq_config = || {
+            material
+                .crypto_config()
+                .with_post_quantum_key_exchange(true)
+        };
+
+        let (server, addr) = test_utils::server(pq_config(), QcatOptions::default());
+        let client = test_utils::client(pq_config(), QcatOptions::default());
+        let output = test_utils::transfer(server, addr, client, &mut &b"pq"[..], 2).await;
+        assert_eq!(output, b"pq");
+        assert!(logs
+            .records()
+            .iter()
+            .any(|record| record.message.contains("key exchange group X25519MLKEM768")));
+
+        // a peer that can't do the hybrid exchange at all
+        let mut classic_config = material.crypto_config();
+        classic_config.provider = Arc::new(CryptoProvider {
+            kx_groups: vec![aws_lc_rs::kx_group::X25519],
+            ..aws_lc_rs::default_provider()
+        });
+        let (server, addr) = test_utils::server(pq_config(), QcatOptions::default());
+        let mut client = test_utils::client(classic_config, QcatOptions::default());
+        let (output, result) = test_utils::with_receiving_server(server, |_| async move {
+            client.run(addr, &mut &b"classic"[..]).await
+        })
+        .await;
+        assert!(result.is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn generated_salts_round_trip() {
+        for (salt_kind, words) in [
+            (SaltKind::Words(1), 1),
+            (SaltKind::Words(3), 3),
+            (SaltKind::Random, 1),
+        ] {
+            let material =
+                CryptoMaterial::generate_with_salt(test_utils::TEST_KDF, salt_kind.clone())
+                    .unwrap();
+            let generated = material.passphrase();
+            assert!(
+                generated.salt().len() >= RECOMMENDED_SALT_LEN,
+                "{:?}",
+                salt_kind
+            );
+            assert_eq!(generated.salt().split(SALT_WORD_DELIM).count(), words);
+            if salt_kind == SaltKind::Random {
+                assert_eq!(generated.salt().len(), RANDOM_SALT_LEN * 2);
+                assert!(generated.salt().chars().all(|c| c.is_ascii_hexdigit()));
+            }
+
+            let parsed = SaltedPassphrase::from_str(&generated.to_string()).unwrap();
+            assert_eq!(parsed.salt(), generated.salt());
+            assert_eq!(parsed.passphrase(), generated.passphrase());
+            assert_eq!(
+                test_utils::material(&generated.to_string()).fingerprint(),
+                material.fingerprint()
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_band_salt_determines_key() {
+        let derive = |salt: &str, passphrase: &str| {
+            CryptoMaterial::generate_from_salt_and_passphrase(
+                salt,
+                passphrase,
+                test_utils::TEST_KDF,
+            )
+            .unwrap()
+        };
+
+        let first = derive("devicesaltsalt01", "correct-horse-battery");
+        let second = derive("devicesaltsalt01", "correct-horse-battery");
+        assert_eq!(
+            first.private_key().secret_pkcs8_der(),
+            second.private_key().secret_pkcs8_der()
+        );
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        // the same as carrying the salt in the passphrase
+        assert_eq!(
+            first.fingerprint(),
+            test_utils::material("devicesaltsalt01-correct-horse-battery").fingerprint()
+        );
+
+        let other_salt = derive("devicesaltsalt02", "correct-horse-battery");
+        assert_ne!(
+            first.private_key().secret_pkcs8_der(),
+            other_salt.private_key().secret_pkcs8_der()
+        );
+        assert_ne!(first.fingerprint(), other_salt.fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_is_stable_sha256_of_cert() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let fingerprint = material.fingerprint();
+
+        assert_eq!(
+            fingerprint,
+            test_utils::material(test_utils::TEST_PASSPHRASE).fingerprint()
+        );
+        assert_eq!(
+            fingerprint,
+            cert_fingerprint(material.certificate_der_bytes())
+        );
+        assert_eq!(
+            material.certificate_der_bytes(),
+            material.certificate().as_ref()
+        );
+
+        let bytes: Vec<&str> = fingerprint.split(':').collect();
+        assert_eq!(bytes.len(), 32);
+        assert!(bytes.iter().all(|byte| byte.len() == 2
+            && byte
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_lowercase())));
+    }
+
+    #[test]
+    fn wordlist_lang_selects_its_words() {
+        assert_eq!(WordlistLang::from_code("en"), WordlistLang::English);
+        assert_eq!(WordlistLang::from_code("ES"), WordlistLang::Spanish);
+
+        let english: std::collections::HashSet<&str> =
+            WordlistLang::English.words().lines().collect();
+        let spanish: std::collections::HashSet<&str> =
+            WordlistLang::Spanish.words().lines().collect();
+        let wordlist = Wordlist::new(WordlistLang::from_code("es"));
+        let words: Vec<&str> = (0..100).map(|_| wordlist.get_word(&mut OsRng)).collect();
+        assert!(words.iter().all(|word| spanish.contains(word)));
+        assert!(!words.iter().all(|word| english.contains(word)));
+    }
+
+    #[test]
+    fn wordlist_lang_passphrase_round_trips() {
+        let wordlist = Wordlist::new(WordlistLang::Spanish);
+        let generated = CryptoMaterial::generate_passphrase(&wordlist, SaltKind::default());
+
+        let parsed = SaltedPassphrase::from_str(&generated.to_string()).unwrap();
+        assert_eq!(parsed.salt(), generated.salt());
+        assert_eq!(parsed.passphrase(), generated.passphrase());
+        assert!(parsed
+            .passphrase()
+            .split(PASSPHRASE_WORD_DELIM)
+            .all(|word| wordlist.words.contains(&word)));
+    }
+
+    #[test]
+    fn unknown_wordlist_lang_falls_back_to_english() {
+        let logs = test_utils::capture_logs();
+        assert_eq!(WordlistLang::from_code("xx"), WordlistLang::English);
+        assert!(logs
+            .records()
+            .iter()
+            .any(|record| record.message.contains("No wordlist for language \"xx\"")));
+    }
+
+    #[tokio::test]
+    async fn spki_pin_ignores_serial_but_cert_pin_does_not() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let reserialed = CryptoMaterial::generate_from_passphrase_with(
+            SaltedPassphrase::from_str(test_utils::TEST_PASSPHRASE).unwrap(),
+            test_utils::TEST_KDF,
+            |params| params.serial_number = Some(SerialNumber::from_slice(&[0x01, 0x23, 0x45])),
+        )
+        .unwrap();
+        assert_ne!(material.certificate(), reserialed.certificate());
+        assert_eq!(
+            spki_hash(material.certificate()),
+            spki_hash(reserialed.certificate())
+        );
+
+        let pinned = [material.certificate().clone()];
+        assert!(pinned_cert_is_valid(
+            &pinned,
+            reserialed.certificate(),
+            PinMode::Spki
+        ));
+        assert!(!pinned_cert_is_valid(
+            &pinned,
+            reserialed.certificate(),
+            PinMode::Cert
+        ));
+
+        for pin_mode in [PinMode::Spki, PinMode::Cert] {
+            let (server, addr) = test_utils::server(
+                material.crypto_config().with_pin_mode(pin_mode),
+                QcatOptions::default(),
+            );
+            let mut client = test_utils::client(
+                reserialed.crypto_config().with_pin_mode(pin_mode),
+                QcatOptions::default(),
+            );
+            let (received, result) =
+                test_utils::with_receiving_server(server, |received| async move {
+                    let result = client.run(addr, &mut &b"pinned"[..]).await;
+                    if result.is_ok() {
+                        test_utils::wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= 6))
+                            .await;
+                    }
+                    result
+                })
+                .await;
+
+            match pin_mode {
+                PinMode::Spki => {
+                    result.unwrap();
+                    assert_eq!(received, b"pinned");
+                }
+                PinMode::Cert => {
+                    assert!(result.is_err());
+                    assert!(received.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn customized_common_name_is_deterministic() {
+        let generate = || {
+            CryptoMaterial::generate_from_passphrase_with(
+                SaltedPassphrase::from_str(test_utils::TEST_PASSPHRASE).unwrap(),
+                test_utils::TEST_KDF,
+                |params| {
+                    params.distinguished_name = RcgenDistinguishedName::new();
+                    params
+                        .distinguished_name
+                        .push(DnType::CommonName, "qcat.example");
+                },
+            )
+            .unwrap()
+        };
+        let first = generate();
+        let second = generate();
+
+        assert_eq!(common_name(first.certificate_der_bytes()), "qcat.example");
+        assert_eq!(
+            first.certificate_der_bytes(),
+            second.certificate_der_bytes()
+        );
+
+        let uncustomized = test_utils::material(test_utils::TEST_PASSPHRASE);
+        assert_ne!(
+            first.certificate_der_bytes(),
+            uncustomized.certificate_der_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn ed25519_only_still_connects() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let schemes = ["ed25519".to_owned()];
+        let config = || {
+            material
+                .crypto_config()
+                .with_signature_schemes(&schemes)
+                .unwrap()
+        };
+        let (server, addr) = test_utils::server(config(), QcatOptions::default());
+        let client = test_utils::client(config(), QcatOptions::default());
+
+        let received = test_utils::transfer(server, addr, client, &mut &b"hello"[..], 5).await;
+        assert_eq!(received, b"hello");
+    }
+
+    /// DigitallySignedStruct::new is private to rustls, so build one the way rustls reads them off the wire
+    fn digitally_signed(scheme: SignatureScheme, signature: &[u8]) -> DigitallySignedStruct {
+        let mut wire = Vec::new();
+        scheme.encode(&mut wire);
+        wire.extend_from_slice(&(signature.len() as u16).to_be_bytes());
+        wire.extend_from_slice(signature);
+        DigitallySignedStruct::read(&mut Reader::init(&wire)).unwrap()
+    }
+
+    #[test]
+    fn scheme_outside_restriction_is_rejected() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let supported_algs = aws_lc_rs::default_provider().signature_verification_algorithms;
+        let dss = digitally_signed(SignatureScheme::ECDSA_NISTP256_SHA256, &[0; 64]);
+
+        let result = verify_tls13_signature(
+            b"handshake transcript",
+            material.certificate(),
+            &dss,
+            &supported_algs,
+            &[SignatureScheme::ED25519],
+        );
+
+        assert_eq!(
+            result.unwrap_err(),
+            RustlsError::from(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme)
+        );
+    }
+
+    #[test]
+    fn high_security_level_rejects_a_weaker_signature() {
+        // a peer signing with ECDSA P-256, which we accept normally but not at the high level
+        let key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = CertificateParams::new(vec!["qcat".to_owned()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let private_key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signer = aws_lc_rs::sign::any_supported_type(&private_key)
+            .unwrap()
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .unwrap();
+        let message = b"handshake transcript";
+        let dss = digitally_signed(
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &signer.sign(message).unwrap(),
+        );
+
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let std_verifier = material.crypto_config().build_verifier();
+        let high_verifier = material
+            .crypto_config()
+            .with_security_level(SecurityLevel::High)
+            .unwrap()
+            .build_verifier();
+
+        assert!(ClientCertVerifier::verify_tls13_signature(
+            &std_verifier,
+            message,
+            cert.der(),
+            &dss
+        )
+        .is_ok());
+        assert!(
+            !ClientCertVerifier::supported_verify_schemes(&high_verifier)
+                .contains(&SignatureScheme::ECDSA_NISTP256_SHA256)
+        );
+        assert_eq!(
+            ClientCertVerifier::verify_tls13_signature(&high_verifier, message, cert.der(), &dss)
+                .unwrap_err(),
+            RustlsError::from(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme)
+        );
+    }
+
+    #[tokio::test]
+    async fn key_log_file_gets_handshake_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.log");
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let client = test_utils::client(
+            material
+                .crypto_config()
+                .with_key_log_file(Some(path.clone())),
+            QcatOptions::default(),
+        );
+
+        let received = test_utils::transfer(server, addr, client, &mut &b"hello"[..], 5).await;
+        assert_eq!(received, b"hello");
+
+        let key_log = std::fs::read_to_string(&path).unwrap();
+        assert!(!key_log.is_empty());
+        for line in key_log.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3, "{}", line);
+            assert_eq!(fields[1].len(), 64, "{}", line);
+        }
+        assert!(key_log.contains("CLIENT_TRAFFIC_SECRET_0 "));
+    }
+
+    #[test]
+    fn easy_words_are_short_and_entropy_follows_them() {
+        let wordlist = Wordlist::new(WordlistLang::English);
+        let all_words = wordlist.words.len();
+        let easy = wordlist.easy_words();
+
+        assert!(easy
+            .words
+            .iter()
+            .all(|word| EASY_WORD_LENS.contains(&word.chars().count())));
+        for _ in 0..100 {
+            assert!(EASY_WORD_LENS.contains(&easy.get_word(&mut OsRng).chars().count()));
+        }
+
+        assert!(easy.words.len() < all_words);
+        assert!(entropy_bits(easy.words.len()) < entropy_bits(all_words));
+        assert!(entropy_bits(easy.words.len()) >= entropy_bits(MIN_WORDLIST_LEN));
+    }
+
+    #[test]
+    fn mismatched_key_types_are_explained() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let ecdsa_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let ecdsa_cert = CertificateParams::new(vec!["qcat".to_owned()])
+            .unwrap()
+            .self_signed(&ecdsa_key)
+            .unwrap();
+
+        let explanation =
+            explain_cert_mismatch(material.certificate_der_bytes(), ecdsa_cert.der().as_ref());
+        let spki_line = explanation
+            .lines()
+            .find(|line| line.contains("SPKI algorithm"))
+            .unwrap();
+        assert!(spki_line.trim_start().starts_with('*'), "{}", explanation);
+        assert!(spki_line.contains("Ed25519"), "{}", explanation);
+        assert!(spki_line.contains("ECDSA"), "{}", explanation);
+
+        // a different passphrase gives a different key of the same type
+        let other = test_utils::material("qcatothersaltsalt-some-other-words");
+        let explanation = explain_cert_mismatch(
+            material.certificate_der_bytes(),
+            other.certificate_der_bytes(),
+        );
+        let is_marked = |field: &str| {
+            explanation
+                .lines()
+                .find(|line| line.contains(field))
+                .is_some_and(|line| line.trim_start().starts_with('*'))
+        };
+        assert!(!is_marked("SPKI algorithm"), "{}", explanation);
+        assert!(is_marked("public key SHA-256"), "{}", explanation);
+    }
+
+    /// Passes on draws from a seeded rng, counting them, to check words come from the rng we hand in
+    struct CountingRng {
+        inner: rand::rngs::StdRng,
+        draws: usize,
+    }
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            self.draws += 1;
+            self.inner.next_u32()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.draws += 1;
+            self.inner.next_u64()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.draws += 1;
+            self.inner.fill_bytes(dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.draws += 1;
+            self.inner.try_fill_bytes(dest)
+        }
+    }
+
+    #[test]
+    fn words_come_from_the_given_rng_and_stay_in_range() {
+        use rand::SeedableRng;
+
+        let wordlist = Wordlist {
+            words: vec!["zero", "one", "two", "three", "four"],
+        };
+        let mut rng = CountingRng {
+            inner: rand::rngs::StdRng::seed_from_u64(187),
+            draws: 0,
+        };
+
+        let mut counts = [0usize; 5];
+        for _ in 0..5000 {
+            let word = wordlist.get_word(&mut rng);
+            let index = wordlist.words.iter().position(|w| *w == word).unwrap();
+            counts[index] += 1;
+        }
+
+        assert!(rng.draws >= 5000);
+        // each word is expected 1000 times, this leaves room for chance but not for a skewed or stuck selection
+        for count in counts {
+            assert!((800..=1200).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn word_selection_is_uniform() {
+        // 7 words don't divide the rng's range evenly, which is where a modulo would skew things
+        let wordlist = Wordlist {
+            words: vec!["a", "b", "c", "d", "e", "f", "g"],
+        };
+        let samples = 70_000;
+        let expected = samples / wordlist.words.len();
+
+        let mut counts = vec![0usize; wordlist.words.len()];
+        for _ in 0..samples {
+            let word = wordlist.get_word(&mut OsRng);
+            counts[wordlist.words.iter().position(|w| *w == word).unwrap()] += 1;
+        }
+
+        // about 93 is one standard deviation here, more than 5 of them off would be a broken sampler, not bad luck
+        for count in &counts {
+            assert!(count.abs_diff(expected) < 500, "{:?}", counts);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWrite,
+    time::{sleep, Sleep},
+};
+
+/// Holds each write to the wrapped writer back by a fixed delay, so tests can tell whether data goes out with the
+/// write that produced it or only once more is written or the stream closes. Flushes and shutdowns aren't delayed
+pub struct DelayedWriter<W> {
+    inner: W,
+    /// None when writes aren't delayed, so callers can wrap unconditionally
+    delay: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// The current write has waited out its delay, and is only waiting on inner now
+    delayed: bool,
+}
+
+impl<W> DelayedWriter<W> {
+    /// Delay each write to inner by delay, or pass them through untouched if delay is None
+    pub fn new(inner: W, delay: Option<Duration>) -> Self {
+        Self {
+            inner,
+            delay,
+            sleep: None,
+            delayed: false,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for DelayedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let (Some(delay), false) = (this.delay, this.delayed) {
+            ready!(this
+                .sleep
+                .get_or_insert_with(|| Box::pin(sleep(delay)))
+                .as_mut()
+                .poll(cx));
+            this.sleep = None;
+            this.delayed = true;
+        }
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.delayed = false;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
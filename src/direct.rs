@@ -0,0 +1,241 @@
+use log::warn;
+use nix::{errno::Errno, fcntl::OFlag};
+use std::{
+    fs,
+    future::Future,
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{io::AsyncWrite, task::JoinHandle};
+
+/// Alignment O_DIRECT writes need for their buffer, length and offset. 4KiB covers the logical block size of just
+/// about every disk
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// How much we gather before each write, a multiple of DIRECT_IO_ALIGN
+const DIRECT_IO_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Open path for writing with O_DIRECT, bypassing the page cache, truncating anything already there. Filesystems that
+/// don't support O_DIRECT (i.e. tmpfs) get a normal file, with a warning
+pub fn create(path: &Path) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    match options
+        .clone()
+        .custom_flags(OFlag::O_DIRECT.bits())
+        .open(path)
+    {
+        Ok(file) => Ok(Box::new(DirectWriter::new(file))),
+        Err(e) if e.raw_os_error() == Some(Errno::EINVAL as i32) => {
+            warn!(
+                "{} doesn't support direct I/O, writing through the page cache",
+                path.display()
+            );
+            Ok(Box::new(tokio::fs::File::from_std(options.open(path)?)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A buffer whose data starts DIRECT_IO_ALIGN aligned, as O_DIRECT needs. Over allocates and starts at the first
+/// aligned byte, rather than reaching for a custom allocation
+struct AlignedBuffer {
+    storage: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new() -> Self {
+        let storage = vec![0u8; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGN];
+        let start = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        Self {
+            storage,
+            start,
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == DIRECT_IO_BUFFER_SIZE
+    }
+
+    /// Copy as much of buf in as fits, returning how much that was
+    fn extend(&mut self, buf: &[u8]) -> usize {
+        let taken = buf.len().min(DIRECT_IO_BUFFER_SIZE - self.len);
+        let end = self.start + self.len;
+        self.storage[end..end + taken].copy_from_slice(&buf[..taken]);
+        self.len += taken;
+        taken
+    }
+
+    /// Our data, zero padded up to the next DIRECT_IO_ALIGN
+    fn padded(&mut self) -> &[u8] {
+        let padded_len = self.len.next_multiple_of(DIRECT_IO_ALIGN);
+        self.storage[self.start + self.len..self.start + padded_len].fill(0);
+        &self.storage[self.start..self.start + padded_len]
+    }
+}
+
+enum State {
+    Idle(fs::File, AlignedBuffer),
+    /// A blocking write of the buffer is under way, handing both back when done
+    Writing(JoinHandle<(io::Result<()>, fs::File, AlignedBuffer)>),
+    /// A write failed, there's nothing sensible left to do
+    Failed,
+}
+
+/// Writes to a file opened with O_DIRECT, gathering what we're given into aligned, full size buffers and writing
+/// those from the blocking pool. The final partial buffer is padded out to the alignment and the file truncated back
+/// to what we were actually given on shutdown
+pub struct DirectWriter {
+    state: State,
+    /// Bytes we've been given, the file's length once we're done
+    len: u64,
+}
+
+impl DirectWriter {
+    fn new(file: fs::File) -> Self {
+        Self {
+            state: State::Idle(file, AlignedBuffer::new()),
+            len: 0,
+        }
+    }
+
+    /// Write the buffer out in the background, truncating the file to truncate_to afterwards if given
+    fn start_write(
+        &mut self,
+        mut file: fs::File,
+        mut buffer: AlignedBuffer,
+        truncate_to: Option<u64>,
+    ) {
+        self.state = State::Writing(tokio::task::spawn_blocking(move || {
+            use std::io::Write;
+
+            let mut result = file.write_all(buffer.padded());
+            if let (Ok(()), Some(len)) = (&result, truncate_to) {
+                result = file.set_len(len);
+            }
+            buffer.len = 0;
+            (result, file, buffer)
+        }));
+    }
+
+    /// Wait for any write under way, leaving us idle
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.state {
+            State::Idle(..) => Poll::Ready(Ok(())),
+            State::Writing(write) => {
+                let joined = ready!(Pin::new(write).poll(cx));
+                match joined {
+                    Ok((Ok(()), file, buffer)) => {
+                        self.state = State::Idle(file, buffer);
+                        Poll::Ready(Ok(()))
+                    }
+                    Ok((Err(e), ..)) => {
+                        self.state = State::Failed;
+                        Poll::Ready(Err(e))
+                    }
+                    Err(e) => {
+                        self.state = State::Failed;
+                        Poll::Ready(Err(io::Error::other(e)))
+                    }
+                }
+            }
+            State::Failed => {
+                Poll::Ready(Err(io::Error::other("An earlier direct I/O write failed")))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for DirectWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.poll_idle(cx))?;
+
+            let State::Idle(_, buffer) = &mut self.state else {
+                unreachable!("poll_idle leaves us idle");
+            };
+            if !buffer.is_full() {
+                let taken = buffer.extend(buf);
+                self.len += taken as u64;
+                return Poll::Ready(Ok(taken));
+            }
+
+            let State::Idle(file, buffer) = std::mem::replace(&mut self.state, State::Failed)
+            else {
+                unreachable!("poll_idle leaves us idle");
+            };
+            self.start_write(file, buffer, None);
+        }
+    }
+
+    /// Waits for any write under way. A partial buffer stays put until shutdown, as O_DIRECT can only write whole
+    /// blocks
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_idle(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_idle(cx))?;
+
+        let State::Idle(_, buffer) = &self.state else {
+            unreachable!("poll_idle leaves us idle");
+        };
+        if buffer.len == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let State::Idle(file, buffer) = std::mem::replace(&mut self.state, State::Failed) else {
+            unreachable!("poll_idle leaves us idle");
+        };
+        let len = self.len;
+        self.start_write(file, buffer, Some(len));
+        self.poll_idle(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn buffer_is_aligned_and_padded() {
+        let mut buffer = AlignedBuffer::new();
+        assert_eq!(buffer.extend(&[1; 100]), 100);
+
+        let padded = buffer.padded();
+        assert_eq!(padded.as_ptr() as usize % DIRECT_IO_ALIGN, 0);
+        assert_eq!(padded.len(), DIRECT_IO_ALIGN);
+        assert!(padded[100..].iter().all(|&byte| byte == 0));
+    }
+
+    #[tokio::test]
+    async fn aligned_and_unaligned_payloads_are_written_whole() {
+        // somewhere on a real disk rather than /tmp, which is often tmpfs and has no O_DIRECT
+        let dir = tempfile::tempdir_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+        for len in [2 * DIRECT_IO_BUFFER_SIZE, DIRECT_IO_BUFFER_SIZE + 100] {
+            let path = dir.path().join(format!("direct-{}", len));
+            let payload: Vec<u8> = (0..=250u8).cycle().take(len).collect();
+
+            let mut writer = create(&path).unwrap();
+            // odd sized writes, so buffers fill across them
+            for chunk in payload.chunks(10_007) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+
+            assert_eq!(std::fs::read(&path).unwrap(), payload, "{} bytes", len);
+        }
+    }
+}
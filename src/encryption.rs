@@ -0,0 +1,362 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::crypto::{KdfParams, SaltedPassphrase};
+
+/// Start of every file we encrypt, so we can tell one apart from anything else (and version the format)
+const MAGIC: &[u8; 8] = b"QCATENC1";
+
+/// Length of the random salt the file's key is derived with, so each file gets its own key
+const FILE_SALT_LEN: usize = 16;
+
+/// Length of a ChaCha20-Poly1305 nonce. Each chunk's nonce is the file's base nonce XORed with the chunk number
+const NONCE_LEN: usize = 12;
+
+/// Length of a ChaCha20-Poly1305 tag
+const TAG_LEN: usize = 16;
+
+/// Magic, KDF memory cost and iterations (u32 each), file salt then base nonce
+const HEADER_LEN: usize = MAGIC.len() + 4 + 4 + FILE_SALT_LEN + NONCE_LEN;
+
+/// Most plaintext sealed in one chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Every chunk starts with a flag byte saying whether it's the last, which is also its associated data so it can't be
+/// tampered with. A file without a final chunk has been truncated
+const CHUNK_FLAG_FINAL: u8 = 1;
+const CHUNK_FLAG_MORE: u8 = 0;
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("Not a file encrypted by qcat")]
+    NotEncrypted,
+    #[error("Unable to decrypt, wrong passphrase or the file has been tampered with")]
+    DecryptionFailed,
+    #[error(
+        "Encrypted file is truncated, everything decrypted so far is authentic but incomplete"
+    )]
+    Truncated,
+    #[error("Encrypted file has unexpected data after its end")]
+    TrailingData,
+}
+
+/// Derive a file's key from our passphrase. The random file salt keeps this distinct from our TLS key, which is derived
+/// from the same passphrase
+fn derive_file_key(
+    passphrase: &SaltedPassphrase,
+    file_salt: &[u8],
+    kdf_params: KdfParams,
+) -> Result<ChaCha20Poly1305, Box<dyn std::error::Error>> {
+    let mut key = [0u8; 32];
+    kdf_params.argon2()?.hash_password_into(
+        passphrase.to_string().as_bytes(),
+        file_salt,
+        &mut key,
+    )?;
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Nonce for the given chunk number
+fn chunk_nonce(base_nonce: &[u8; NONCE_LEN], chunk: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = *base_nonce;
+    for (byte, counter_byte) in nonce[NONCE_LEN - 8..].iter_mut().zip(chunk.to_be_bytes()) {
+        *byte ^= counter_byte;
+    }
+    nonce
+}
+
+/// Encrypts everything written to it with a key derived from a passphrase, for keeping received data encrypted at
+/// rest. Data is sealed in authenticated chunks (ChaCha20-Poly1305), flushing seals whatever is buffered. Shutting
+/// down writes the final chunk, without which decrypting reports the file as truncated, so always shut this down
+pub struct EncryptingWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    base_nonce: [u8; NONCE_LEN],
+    chunk: u64,
+    /// Plaintext not yet sealed into a chunk
+    plaintext: Vec<u8>,
+    /// Sealed bytes not yet written to inner, from pending_written onwards
+    pending: Vec<u8>,
+    pending_written: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    pub fn new(
+        inner: W,
+        passphrase: &SaltedPassphrase,
+        kdf_params: KdfParams,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file_salt = [0u8; FILE_SALT_LEN];
+        let mut base_nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut file_salt);
+        OsRng.fill_bytes(&mut base_nonce);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&kdf_params.memory_kib.to_be_bytes());
+        header.extend_from_slice(&kdf_params.iterations.to_be_bytes());
+        header.extend_from_slice(&file_salt);
+        header.extend_from_slice(&base_nonce);
+
+        Ok(Self {
+            inner,
+            cipher: derive_file_key(passphrase, &file_salt, kdf_params)?,
+            base_nonce,
+            chunk: 0,
+            plaintext: Vec::with_capacity(CHUNK_SIZE),
+            pending: header,
+            pending_written: 0,
+            finished: false,
+        })
+    }
+
+    /// Seal our buffered plaintext into a chunk, queueing it to be written
+    fn seal_chunk(&mut self, flag: u8) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.base_nonce, self.chunk);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &self.plaintext,
+                    aad: &[flag],
+                },
+            )
+            .map_err(|_| io::Error::other("Failed to encrypt output"))?;
+
+        self.pending.push(flag);
+        self.pending
+            .extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(&ciphertext);
+        self.plaintext.clear();
+        self.chunk += 1;
+
+        Ok(())
+    }
+
+    /// Write out everything we've sealed
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_written < self.pending.len() {
+            let written = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_written..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_written += written;
+        }
+
+        self.pending.clear();
+        self.pending_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.finished {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Encrypted output already finished",
+            )));
+        }
+        ready!(self.poll_write_pending(cx))?;
+
+        let len = buf.len().min(CHUNK_SIZE - self.plaintext.len());
+        self.plaintext.extend_from_slice(&buf[..len]);
+        if self.plaintext.len() == CHUNK_SIZE {
+            self.seal_chunk(CHUNK_FLAG_MORE)?;
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.plaintext.is_empty() && !self.finished {
+            self.seal_chunk(CHUNK_FLAG_MORE)?;
+        }
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.finished {
+            self.seal_chunk(CHUNK_FLAG_FINAL)?;
+            self.finished = true;
+        }
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decrypt a file written by EncryptingWriter, writing the plaintext to output as each chunk is authenticated. Returns
+/// the number of bytes decrypted
+pub async fn decrypt<R, W>(
+    input: &mut R,
+    output: &mut W,
+    passphrase: &SaltedPassphrase,
+) -> Result<u64, Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0u8; HEADER_LEN];
+    input
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| EncryptionError::NotEncrypted)?;
+    let (magic, rest) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(EncryptionError::NotEncrypted.into());
+    }
+    let (memory_kib, rest) = rest.split_at(4);
+    let (iterations, rest) = rest.split_at(4);
+    let (file_salt, base_nonce) = rest.split_at(FILE_SALT_LEN);
+
+    let kdf_params = KdfParams {
+        memory_kib: u32::from_be_bytes(memory_kib.try_into()?),
+        iterations: u32::from_be_bytes(iterations.try_into()?),
+    };
+    let cipher = derive_file_key(passphrase, file_salt, kdf_params)?;
+    let base_nonce: [u8; NONCE_LEN] = base_nonce.try_into()?;
+
+    let mut decrypted = 0;
+    let mut ciphertext = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    for chunk in 0.. {
+        let flag = match input.read_u8().await {
+            Ok(flag) => flag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(EncryptionError::Truncated.into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let len = input.read_u32().await? as usize;
+        if len > CHUNK_SIZE + TAG_LEN {
+            return Err(EncryptionError::DecryptionFailed.into());
+        }
+        ciphertext.resize(len, 0);
+        input
+            .read_exact(&mut ciphertext)
+            .await
+            .map_err(|_| EncryptionError::Truncated)?;
+
+        let nonce = chunk_nonce(&base_nonce, chunk);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &[flag],
+                },
+            )
+            .map_err(|_| EncryptionError::DecryptionFailed)?;
+        output.write_all(&plaintext).await?;
+        decrypted += plaintext.len() as u64;
+
+        if flag == CHUNK_FLAG_FINAL {
+            break;
+        }
+    }
+
+    output.flush().await?;
+
+    if input.read_u8().await.is_ok() {
+        return Err(EncryptionError::TrailingData.into());
+    }
+
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{transfer_in_memory, QcatOptions},
+        test_utils,
+    };
+    use std::{str::FromStr, sync::Arc};
+    use tokio::sync::Mutex;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn encrypted_output_decrypts_to_what_was_received() {
+        // spans several chunks, the last one partial
+        let data: Vec<u8> = (0..=255u8).cycle().take(3 * CHUNK_SIZE + 100).collect();
+        let passphrase = SaltedPassphrase::from_str(test_utils::TEST_PASSPHRASE).unwrap();
+        let writer = EncryptingWriter::new(Vec::new(), &passphrase, test_utils::TEST_KDF).unwrap();
+        let output = Arc::new(Mutex::new(writer));
+
+        transfer_in_memory(&QcatOptions::default(), &mut &data[..], &output)
+            .await
+            .unwrap();
+        let mut writer = output.lock().await;
+        writer.shutdown().await.unwrap();
+        let encrypted = &writer.inner;
+        assert!(!encrypted
+            .windows(CHUNK_SIZE / 2)
+            .any(|window| window == &data[..CHUNK_SIZE / 2]));
+
+        let mut decrypted = Vec::new();
+        let count = decrypt(&mut &encrypted[..], &mut decrypted, &passphrase)
+            .await
+            .unwrap();
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(decrypted, data);
+
+        let wrong = SaltedPassphrase::from_str("qcatwrongsaltsalt-not-the-passphrase").unwrap();
+        assert!(decrypt(&mut &encrypted[..], &mut Vec::new(), &wrong)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn stopped_server_finishes_encrypted_output() {
+        let data = b"encrypted at rest";
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let passphrase = SaltedPassphrase::from_str(test_utils::TEST_PASSPHRASE).unwrap();
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let writer = EncryptingWriter::new(Vec::new(), &passphrase, test_utils::TEST_KDF).unwrap();
+        let mut output = Arc::new(Mutex::new(writer));
+        let received = Arc::clone(&output);
+
+        let send = async {
+            client.run(addr, &mut &data[..]).await.unwrap();
+            // sealed into a chunk if the server flushed it, still buffered otherwise
+            test_utils::wait_until(|| {
+                received
+                    .try_lock()
+                    .is_ok_and(|writer| writer.chunk > 0 || writer.plaintext.len() >= data.len())
+            })
+            .await;
+            cancel.cancel();
+        };
+        let (served, ()) = tokio::join!(server.run_and_shut_down(&mut output), send);
+        test_utils::assert_cancelled(served);
+
+        let writer = output.lock().await;
+        let mut decrypted = Vec::new();
+        decrypt(&mut &writer.inner[..], &mut decrypted, &passphrase)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, data);
+    }
+}
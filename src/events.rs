@@ -0,0 +1,300 @@
+use log::{debug, info, warn};
+use s2n_quic::{
+    connection,
+    provider::event::{events, ConnectionInfo, ConnectionMeta, Subscriber},
+};
+use std::net::IpAddr;
+
+use crate::{
+    core::PASSPHRASE_EXPIRED_ERROR_CODE,
+    limiter::{socket_address_ip, FailedAttemptLimiter},
+    metrics::METRICS,
+};
+
+/// Our s2n-quic event subscriber, used to surface connection level details in our logs and feed handshake outcomes to
+/// our failed attempt limiter
+#[derive(Debug, Default)]
+pub struct QcatEventSubscriber {
+    failed_attempts: FailedAttemptLimiter,
+}
+
+impl QcatEventSubscriber {
+    pub fn new(failed_attempts: FailedAttemptLimiter) -> Self {
+        Self { failed_attempts }
+    }
+}
+
+/// What we track per connection
+#[derive(Debug, Default)]
+pub struct QcatConnectionContext {
+    remote_ip: Option<IpAddr>,
+    handshake_complete: bool,
+    /// The peer's leaf cert (DER) once the handshake has verified it
+    peer_cert: Option<Vec<u8>>,
+}
+
+impl QcatConnectionContext {
+    pub fn peer_cert(&self) -> Option<&[u8]> {
+        self.peer_cert.as_deref()
+    }
+}
+
+impl Subscriber for QcatEventSubscriber {
+    type ConnectionContext = QcatConnectionContext;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        QcatConnectionContext::default()
+    }
+
+    fn on_connection_started(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::ConnectionStarted,
+    ) {
+        context.remote_ip = socket_address_ip(&event.path.remote_addr);
+    }
+
+    fn on_handshake_status_updated(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::HandshakeStatusUpdated,
+    ) {
+        if matches!(event.status, events::HandshakeStatus::Complete { .. }) {
+            context.handshake_complete = true;
+            if let Some(remote_ip) = context.remote_ip {
+                self.failed_attempts.record_success(remote_ip);
+            }
+        }
+    }
+
+    /// Keep the peer's cert, which our verifier has accepted by the time TLS secrets are ready, for the application
+    fn on_tls_exporter_ready(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &events::TlsExporterReady,
+    ) {
+        match event.session.peer_cert_chain_der() {
+            Ok(chain) => context.peer_cert = chain.into_iter().next(),
+            Err(e) => debug!("Connection {}: peer cert unavailable: {:?}", meta.id, e),
+        }
+    }
+
+    /// Log the key exchange group each handshake negotiated. TLS 1.3 only has ephemeral (EC)DHE key exchanges, so
+    /// even though our certs are derived from a static passphrase, recorded traffic stays safe if it later leaks
+    fn on_key_exchange_group(
+        &mut self,
+        _context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &events::KeyExchangeGroup,
+    ) {
+        info!(
+            "Connection {}: key exchange group {}, ephemeral so the session has forward secrecy",
+            meta.id, event.chosen_group_name
+        );
+    }
+
+    /// Log the server name (SNI) a client asked for, which picks our cert when we hold several identities
+    fn on_server_name_information(
+        &mut self,
+        _context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &events::ServerNameInformation,
+    ) {
+        debug!(
+            "Connection {}: client asked for server name {}",
+            meta.id, event.chosen_server_name
+        );
+    }
+
+    /// Log why each connection closed. One closing before completing its handshake because a cert was rejected counts
+    /// as a failed attempt, as that's what a wrong passphrase looks like. Timeouts and network trouble don't
+    fn on_connection_closed(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &events::ConnectionClosed,
+    ) {
+        info!(
+            "Connection {}: connection closed: {}",
+            meta.id,
+            close_reason(&event.error)
+        );
+
+        if let (Some(remote_ip), false) = (context.remote_ip, context.handshake_complete) {
+            debug!(
+                "Connection {}: handshake with {} failed",
+                meta.id, remote_ip
+            );
+            METRICS.handshake_failed();
+            if is_cert_rejection(&event.error) {
+                self.failed_attempts.record_failure(remote_ip);
+            }
+        }
+    }
+
+    /// Log path MTU updates, helpful for diagnosing blackholed packets on paths with small MTUs (VPNs, tunnels, etc.)
+    fn on_mtu_updated(
+        &mut self,
+        _context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &events::MtuUpdated,
+    ) {
+        match event.cause {
+            events::MtuUpdatedCause::ProbeAcknowledged { .. } => {
+                info!("Connection {}: path MTU discovered: {}", meta.id, event.mtu)
+            }
+            events::MtuUpdatedCause::Blackhole { .. } => warn!(
+                "Connection {}: path MTU dropped to {}, larger packets are being lost",
+                meta.id, event.mtu
+            ),
+            _ => debug!(
+                "Connection {}: path MTU updated: {} ({:?})",
+                meta.id, event.mtu, event.cause
+            ),
+        }
+    }
+}
+
+/// TLS alerts sent when a certificate is rejected, by our verifier or the peer's
+const CERT_REJECTED_ALERTS: &[u8] = &[
+    42,  // bad_certificate
+    43,  // unsupported_certificate
+    46,  // certificate_unknown
+    48,  // unknown_ca
+    51,  // decrypt_error
+    116, // certificate_required
+];
+
+/// QUIC carries TLS alerts as transport errors 0x0100 + the alert
+const CRYPTO_ERROR_RANGE: std::ops::RangeInclusive<u64> = 0x0100..=0x01ff;
+
+/// Whether a connection closed because one side rejected the other's cert, either way round
+fn is_cert_rejection(error: &connection::Error) -> bool {
+    match error {
+        connection::Error::Transport { code, .. } => {
+            let code = code.as_u64();
+            CRYPTO_ERROR_RANGE.contains(&code)
+                && CERT_REJECTED_ALERTS.contains(&((code - 0x0100) as u8))
+        }
+        _ => false,
+    }
+}
+
+/// A human readable reason for a connection closing
+pub fn close_reason(error: &connection::Error) -> String {
+    let by = |is_local: bool| if is_local { "us" } else { "the peer" };
+
+    match error {
+        connection::Error::Closed { initiator, .. } => {
+            format!("closed cleanly by {}", by(initiator.is_local()))
+        }
+        connection::Error::Application { error, .. }
+            if u64::from(*error) == u64::from(PASSPHRASE_EXPIRED_ERROR_CODE) =>
+        {
+            "rejected, the passphrase has expired".to_owned()
+        }
+        connection::Error::Application {
+            error, initiator, ..
+        } => format!(
+            "closed by {} with application error {:?}",
+            by(initiator.is_local()),
+            error
+        ),
+        connection::Error::Transport {
+            reason, initiator, ..
+        } if !reason.is_empty() => format!(
+            "transport error from {}: {}",
+            by(initiator.is_local()),
+            reason
+        ),
+        connection::Error::IdleTimerExpired { .. } => {
+            "idle timeout, the peer went quiet".to_owned()
+        }
+        connection::Error::StatelessReset { .. } => {
+            "reset by the peer, which no longer knows the connection (i.e. it restarted)".to_owned()
+        }
+        connection::Error::MaxHandshakeDurationExceeded { .. } => {
+            "handshake timed out, check the passphrase and that the peer is reachable".to_owned()
+        }
+        connection::Error::NoValidPath { .. } => "no usable network path to the peer".to_owned(),
+        connection::Error::EndpointClosing { .. } => "our endpoint is shutting down".to_owned(),
+        error => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use s2n_quic_core::endpoint::Location;
+
+    #[test]
+    fn clean_close_is_told_apart_from_idle_timeout() {
+        assert_eq!(
+            close_reason(&connection::Error::closed(Location::Local)),
+            "closed cleanly by us"
+        );
+        assert_eq!(
+            close_reason(&connection::Error::closed(Location::Remote)),
+            "closed cleanly by the peer"
+        );
+        assert_eq!(
+            close_reason(&connection::Error::idle_timer_expired()),
+            "idle timeout, the peer went quiet"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_logs_its_key_exchange_group() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let output = test_utils::transfer(server, addr, client, &mut &b"kx"[..], 2).await;
+        assert_eq!(output, b"kx");
+
+        let groups: Vec<String> = logs
+            .records()
+            .iter()
+            .filter_map(|record| {
+                let (_, rest) = record.message.split_once("key exchange group ")?;
+                let (group, note) = rest.split_once(", ")?;
+                assert!(note.contains("forward secrecy"), "{}", record.message);
+                Some(group.to_owned())
+            })
+            .collect();
+        // one from each side, which agree on what they negotiated
+        assert_eq!(groups.len(), 2, "{:?}", groups);
+        assert!(!groups[0].is_empty());
+        assert_eq!(groups[0], groups[1]);
+    }
+
+    #[tokio::test]
+    async fn configured_sni_is_sent_and_pinning_still_passes() {
+        let logs = test_utils::capture_logs();
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                server_name: Some("qcat.routed.example".to_owned()),
+                ..Default::default()
+            },
+        );
+
+        let output = test_utils::transfer(server, addr, client, &mut &b"sni"[..], 3).await;
+
+        assert_eq!(output, b"sni");
+        assert!(logs.records().iter().any(|record| record
+            .message
+            .contains("client asked for server name qcat.routed.example")));
+    }
+}
@@ -0,0 +1,219 @@
+use log::debug;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    task::JoinHandle,
+};
+
+/// Spawn a filter command through the platform's shell, so users can give pipelines and arguments as one string
+fn spawn_filter(command: &str) -> io::Result<Child> {
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+
+    debug!("Spawning filter: {}", command);
+    shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+}
+
+/// Fail unless the filter exited successfully
+fn check_exit_status(status: ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("Filter exited with {}", status)))
+    }
+}
+
+/// Input passed through a filter command: input is fed to the command's stdin and we read its stdout. Hitting EOF
+/// only succeeds once the command has exited successfully, so a failed filter isn't mistaken for the end of input
+pub struct FilteredReader {
+    stdout: ChildStdout,
+    /// Feeds input to the command then waits on it, resolving to its exit status. None once we've checked it
+    exit: Option<JoinHandle<io::Result<ExitStatus>>>,
+}
+
+impl FilteredReader {
+    pub fn spawn<R: AsyncRead + Unpin + Send + 'static>(
+        command: &str,
+        mut input: R,
+    ) -> io::Result<Self> {
+        let mut child = spawn_filter(command)?;
+        let mut stdin = child.stdin.take().expect("filter stdin is piped");
+        let stdout = child.stdout.take().expect("filter stdout is piped");
+
+        let exit = tokio::spawn(async move {
+            // the filter may exit without reading all of its input (i.e. head), which is its call to make
+            match tokio::io::copy(&mut input, &mut stdin).await {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    debug!("Filter stopped reading its input");
+                }
+                result => {
+                    result?;
+                }
+            }
+            // closing stdin lets the filter know there's no more input
+            drop(stdin);
+            child.wait().await
+        });
+
+        Ok(Self {
+            stdout,
+            exit: Some(exit),
+        })
+    }
+}
+
+impl AsyncRead for FilteredReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.stdout).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            return Poll::Ready(Ok(()));
+        }
+
+        let Some(exit) = self.exit.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let status = ready!(Pin::new(exit).poll(cx));
+        self.exit = None;
+        Poll::Ready(check_exit_status(status.map_err(io::Error::other)??))
+    }
+}
+
+/// Output passed through a filter command: writes go to the command's stdin and its stdout is copied to output.
+/// Shutting down closes the command's stdin, then waits for it to finish writing and exit. Until then the command
+/// may hold back output (i.e. compressors), so always shut this down
+pub struct FilteredWriter {
+    stdin: Option<ChildStdin>,
+    /// Copies the command's stdout to our output then waits on it. None once we've checked it
+    finished: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl FilteredWriter {
+    pub fn spawn<W: AsyncWrite + Unpin + Send + 'static>(
+        command: &str,
+        mut output: W,
+    ) -> io::Result<Self> {
+        let mut child = spawn_filter(command)?;
+        let stdin = child.stdin.take().expect("filter stdin is piped");
+        let mut stdout = child.stdout.take().expect("filter stdout is piped");
+
+        let finished = tokio::spawn(async move {
+            tokio::io::copy(&mut stdout, &mut output).await?;
+            output.shutdown().await?;
+            check_exit_status(child.wait().await?)
+        });
+
+        Ok(Self {
+            stdin: Some(stdin),
+            finished: Some(finished),
+        })
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Filter input already closed"))
+    }
+}
+
+impl AsyncWrite for FilteredWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.stdin()?).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(stdin) = self.stdin.as_mut() {
+            ready!(Pin::new(stdin).poll_shutdown(cx))?;
+            self.stdin = None;
+        }
+
+        let Some(finished) = self.finished.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(finished).poll(cx));
+        self.finished = None;
+        Poll::Ready(result.map_err(io::Error::other)?)
+    }
+}
+
+// the filters here are POSIX commands
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn sent_data_is_filtered() {
+        let mut input = FilteredReader::spawn("tr a-z A-Z", &b"hello, filter\n"[..]).unwrap();
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            QcatOptions::default(),
+            &mut input,
+            14,
+        )
+        .await;
+
+        assert_eq!(output, b"HELLO, FILTER\n");
+    }
+
+    #[tokio::test]
+    async fn received_data_is_filtered() {
+        let (output, mut filtered) = tokio::io::duplex(1024);
+        let mut writer = FilteredWriter::spawn("tr a-z A-Z", output).unwrap();
+
+        writer.write_all(b"hello, filter\n").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        filtered.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"HELLO, FILTER\n");
+    }
+
+    #[tokio::test]
+    async fn failed_filter_is_an_error() {
+        let mut reader = FilteredReader::spawn("exit 3", &b"ignored"[..]).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+
+        let mut writer = FilteredWriter::spawn("exit 3", tokio::io::sink()).unwrap();
+        let _ = writer.write_all(b"ignored").await;
+        assert!(writer.shutdown().await.is_err());
+    }
+}
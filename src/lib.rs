@@ -1,4 +1,37 @@
 pub mod args;
+pub mod checkpoint;
+pub mod clipboard;
+pub mod compression;
 pub mod core;
 pub mod crypto;
+pub mod delay;
+#[cfg(target_os = "linux")]
+pub mod direct;
+pub mod encryption;
+pub mod events;
+pub mod filter;
+pub mod limiter;
+pub mod logging;
+pub mod masque;
+pub mod merge;
+pub mod message;
+pub mod metrics;
+pub mod parallel;
+#[cfg(unix)]
+pub mod privileges;
+pub mod protocol;
+pub mod qr;
+pub mod ratelimit;
+pub mod reaper;
+pub mod resume;
+pub mod rotate;
+pub mod split;
+pub mod style;
+pub mod tee;
+#[cfg(test)]
+mod test_utils;
+pub mod timings;
+pub mod transcript;
+#[cfg(unix)]
+pub mod unix;
 pub mod utils;
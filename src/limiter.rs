@@ -0,0 +1,187 @@
+use log::warn;
+use s2n_quic::provider::{
+    endpoint_limits::{self, ConnectionAttempt, Limiter, Outcome},
+    event::events::SocketAddress,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Tracks consecutive failed handshakes (i.e. a wrong passphrase, so a cert mismatch) per peer IP, blocking peers
+/// that fail too many times in a row for a while. Slows down brute forcing the passphrase. Failures are forgotten
+/// after block_duration without another. Disabled by default
+#[derive(Debug, Clone, Default)]
+pub struct FailedAttemptLimiter {
+    peers: Arc<Mutex<HashMap<IpAddr, PeerAttempts>>>,
+    max_failed_attempts: Option<u32>,
+    block_duration: Duration,
+}
+
+#[derive(Debug)]
+struct PeerAttempts {
+    consecutive_failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl PeerAttempts {
+    /// Whether there's nothing left worth remembering, the block (if any) over and the last failure long enough ago
+    /// to forget
+    fn is_stale(&self, now: Instant, block_duration: Duration) -> bool {
+        match self.blocked_until {
+            Some(blocked_until) => now >= blocked_until,
+            None => now.duration_since(self.last_failure) >= block_duration,
+        }
+    }
+}
+
+impl FailedAttemptLimiter {
+    pub fn new(max_failed_attempts: Option<u32>, block_duration: Duration) -> Self {
+        Self {
+            peers: Arc::default(),
+            max_failed_attempts,
+            block_duration,
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        let Some(max_failed_attempts) = self.max_failed_attempts else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        // forget peers we no longer need to, so the map only holds ones that failed recently
+        peers.retain(|_, attempts| !attempts.is_stale(now, self.block_duration));
+
+        let attempts = peers.entry(ip).or_insert(PeerAttempts {
+            consecutive_failures: 0,
+            last_failure: now,
+            blocked_until: None,
+        });
+        attempts.consecutive_failures += 1;
+        attempts.last_failure = now;
+
+        if attempts.consecutive_failures >= max_failed_attempts {
+            warn!(
+                "Blocking {} for {}s after {} failed handshakes",
+                ip,
+                self.block_duration.as_secs(),
+                attempts.consecutive_failures
+            );
+            attempts.consecutive_failures = 0;
+            attempts.blocked_until = Some(now + self.block_duration);
+        }
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        if self.max_failed_attempts.is_some() {
+            self.peers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&ip);
+        }
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        if self.max_failed_attempts.is_none() {
+            return false;
+        }
+
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        match peers.get(&ip).and_then(|attempts| attempts.blocked_until) {
+            Some(blocked_until) if Instant::now() < blocked_until => true,
+            Some(_) => {
+                // block has expired, give the peer a clean slate
+                peers.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// An s2n-quic endpoint limiter that drops connection attempts from blocked peers before any handshake
+    pub fn endpoint_limits(&self) -> BlockingEndpointLimits {
+        BlockingEndpointLimits {
+            limiter: self.clone(),
+            default_limits: endpoint_limits::Default::default(),
+        }
+    }
+}
+
+/// Drops attempts from peers our [`FailedAttemptLimiter`] has blocked, otherwise defers to s2n-quic's default limits
+pub struct BlockingEndpointLimits {
+    limiter: FailedAttemptLimiter,
+    default_limits: endpoint_limits::Default,
+}
+
+impl Limiter for BlockingEndpointLimits {
+    fn on_connection_attempt(&mut self, info: &ConnectionAttempt) -> Outcome {
+        match socket_address_ip(&info.remote_address) {
+            Some(ip) if self.limiter.is_blocked(ip) => Outcome::drop(),
+            _ => self.default_limits.on_connection_attempt(info),
+        }
+    }
+}
+
+/// The IP of an s2n-quic event socket address, with IPv4 mapped IPv6 addresses canonicalized to IPv4
+pub fn socket_address_ip(addr: &SocketAddress) -> Option<IpAddr> {
+    match addr {
+        SocketAddress::IpV4 { ip, .. } => Some(Ipv4Addr::from(**ip).into()),
+        SocketAddress::IpV6 { ip, .. } => Some(Ipv6Addr::from(**ip).to_canonical()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn repeated_failures_block_only_that_peer() {
+        let limiter = FailedAttemptLimiter::new(Some(3), Duration::from_secs(60));
+
+        limiter.record_failure(PEER);
+        limiter.record_failure(PEER);
+        assert!(!limiter.is_blocked(PEER));
+        limiter.record_failure(PEER);
+        assert!(limiter.is_blocked(PEER));
+        assert!(!limiter.is_blocked(OTHER_PEER));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let limiter = FailedAttemptLimiter::new(Some(2), Duration::from_secs(60));
+
+        limiter.record_failure(PEER);
+        limiter.record_success(PEER);
+        limiter.record_failure(PEER);
+        assert!(!limiter.is_blocked(PEER));
+    }
+
+    #[test]
+    fn block_expires() {
+        let limiter = FailedAttemptLimiter::new(Some(1), Duration::from_millis(50));
+
+        limiter.record_failure(PEER);
+        assert!(limiter.is_blocked(PEER));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!limiter.is_blocked(PEER));
+    }
+
+    #[test]
+    fn disabled_never_blocks() {
+        let limiter = FailedAttemptLimiter::default();
+
+        for _ in 0..100 {
+            limiter.record_failure(PEER);
+        }
+        assert!(!limiter.is_blocked(PEER));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::args::LogFormat;
+use env_logger::{fmt::Formatter, Builder, Target};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter, Record,
+};
+use std::{error::Error, fs::OpenOptions, io::Write, path::Path, time::SystemTime};
+
+/// Build and install our global logger. Logs always go to stderr (or the given log file) so stdout stays clean for data
+pub fn init_logger(
+    level: LevelFilter,
+    format: LogFormat,
+    log_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let mut builder = Builder::from_default_env();
+    builder.filter_level(level);
+
+    match format {
+        LogFormat::Plain => {}
+        LogFormat::Compact => {
+            builder.format(format_compact);
+        }
+        LogFormat::Json => {
+            builder.format(format_json);
+        }
+    }
+
+    if let Some(path) = log_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        builder.target(Target::Pipe(Box::new(file)));
+    }
+
+    builder.try_init()?;
+
+    Ok(())
+}
+
+/// Single line format without timestamps or module paths
+fn format_compact(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut fields = String::new();
+    let _ = record.key_values().visit(&mut PlainFields(&mut fields));
+
+    writeln!(buf, "{} {}{}", record.level(), record.args(), fields)
+}
+
+/// Appends a record's structured fields as " key=value" pairs
+struct PlainFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for PlainFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+/// Appends a record's structured fields as JSON members, numbers unquoted and anything else as a string
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match (value.to_u64(), value.to_i64(), value.to_f64()) {
+            (Some(n), _, _) => n.to_string(),
+            (None, Some(n), _) => n.to_string(),
+            (None, None, Some(n)) if n.is_finite() => n.to_string(),
+            _ => format!("\"{}\"", escape_json(&value.to_string())),
+        };
+        self.0
+            .push_str(&format!(",\"{}\":{}", escape_json(key.as_str()), value));
+        Ok(())
+    }
+}
+
+/// One JSON object per line
+fn format_json<W: Write>(buf: &mut W, record: &Record) -> std::io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+
+    // structured fields become top level members alongside the message
+    let mut fields = String::new();
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+
+    writeln!(
+        buf,
+        "{{\"timestamp\":{:.6},\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"{}}}",
+        timestamp,
+        record.level(),
+        escape_json(record.target()),
+        escape_json(&record.args().to_string()),
+        fields,
+    )
+}
+
+/// Escape a string to be embedded in a JSON string literal
+pub fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn json_records_parse() {
+        let fields = [
+            ("bytes", Value::from(42u64)),
+            ("peer", Value::from("127.0.0.1:4433")),
+        ];
+        let mut buf = Vec::new();
+        format_json(
+            &mut buf,
+            &Record::builder()
+                .args(format_args!("said \"hi\"\n\tthen left"))
+                .level(Level::Info)
+                .target("qcat::core")
+                .key_values(&fields)
+                .build(),
+        )
+        .unwrap();
+
+        let line = String::from_utf8(buf).unwrap();
+        assert_eq!(line.lines().count(), 1);
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], "qcat::core");
+        assert_eq!(record["message"], "said \"hi\"\n\tthen left");
+        assert_eq!(record["bytes"], 42);
+        assert_eq!(record["peer"], "127.0.0.1:4433");
+        assert!(record["timestamp"].as_f64().is_some_and(|t| t > 0.0));
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(escape_json("a\u{1}b"), "a\\u0001b");
+    }
+}
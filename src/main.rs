@@ -1,26 +1,61 @@
 use clap::Parser;
-use log::info;
+use log::{error, info, warn};
+#[cfg(target_os = "linux")]
+use qcat::direct;
+#[cfg(target_os = "linux")]
+use qcat::utils::scrub_passphrases_from_argv;
 use qcat::{
-    args, core,
-    crypto::{CryptoMaterial, QcatCryptoConfig},
-    utils::receive_passphrase_input,
+    args::{self, Command, QcatUri, QCAT_URI_SCHEME},
+    clipboard::{read_clipboard, ClipboardWriter},
+    compression::ZstdDictionary,
+    core::{self, CoreError},
+    crypto::{self, CryptoMaterial, KdfParams, QcatCryptoConfig, SaltedPassphrase},
+    encryption::{self, EncryptingWriter},
+    filter::{FilteredReader, FilteredWriter},
+    logging,
+    merge::MergedReader,
+    metrics,
+    qr::decode_qr_file,
+    rotate::RotatingFile,
+    style::{self, Style},
+    tee::TeeWriter,
+    timings::TIMINGS,
+    transcript::{Direction, Transcript},
+    utils::{
+        announce_generated_passphrase, check_passphrase, concat_files, confirm_peer_on_terminal,
+        open_output_file, peer_command, print_terminal_input_hint, read_credential_passphrase,
+        receive_passphrase_input, resolve, warn_passphrase_in_argv,
+    },
 };
+#[cfg(unix)]
+use qcat::{privileges, unix::UnixSocketAddr};
 use std::{
     error::Error,
+    future::Future,
+    io::IsTerminal,
     net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::Mutex,
 };
-use tokio::sync::Mutex;
-use webpki::types::PrivateKeyDer;
 
 // TODO:
 // - add support for reading/writing from files rather than just stdin/stdout
 // - fix args to be more like nc
-// - ipv6 support, try to resolve addresses rather than just using ip addrs from args (i.e. should be able to type "localhost")
+// - ipv6 support for the client
 // - remove RSA support
 // - look at cert params and defaults
 
+/// Exit status when the session exceeds --max-duration, matching timeout(1)
+const EXIT_MAX_DURATION: i32 = 124;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = args::Args::parse();
@@ -31,38 +66,707 @@ async fn main() -> Result<(), Box<dyn Error>> {
         log::LevelFilter::Info
     };
 
-    env_logger::Builder::from_default_env()
-        .filter_level(log_level_filter)
-        .init();
+    logging::init_logger(log_level_filter, args.log_format, args.log_file.as_deref())?;
+    style::init_color(args.no_color);
 
-    let ip_addr = IpAddr::from_str(&args.hostname)?;
-    let socket_addr = SocketAddr::new(ip_addr, args.port);
+    let print_timings = args.timings;
+    let result = run(args).await;
+    if print_timings {
+        eprint!("{}", TIMINGS.summary());
+    }
+
+    match result {
+        Err(e)
+            if matches!(
+                e.downcast_ref::<CoreError>(),
+                Some(CoreError::MaxDurationExceeded)
+            ) =>
+        {
+            error!("{}", e);
+            std::process::exit(EXIT_MAX_DURATION);
+        }
+        result => result,
+    }
+}
+
+async fn run(args: args::Args) -> Result<(), Box<dyn Error>> {
+    if let Some(command) = &args.command {
+        return match command {
+            Command::KdfBench { target } => kdf_bench(Duration::from_secs_f64(*target)),
+            Command::Decrypt {
+                input,
+                output,
+                passphrase,
+                salt,
+            } => {
+                decrypt_file(
+                    input,
+                    output.as_deref(),
+                    passphrase.as_deref(),
+                    salt.as_deref(),
+                )
+                .await
+            }
+            Command::Check { passphrase } => check_passphrase(passphrase),
+        };
+    }
+
+    warn_passphrase_in_argv(&args.passphrases);
+    #[cfg(target_os = "linux")]
+    if !args.passphrases.is_empty() {
+        if let Err(e) = scrub_passphrases_from_argv(&args.passphrases) {
+            log::debug!("Unable to hide passphrases from our command line: {}", e);
+        }
+    }
+
+    let qr_content = args.qr_file.as_deref().map(decode_qr_file).transpose()?;
+
+    // a client may be given a URI in place of the hostname, port and passphrase, either directly or in a QR code
+    let uri = qr_content
+        .as_deref()
+        .or(args.hostname.as_deref().filter(|_| !args.listen))
+        .filter(|content| content.starts_with(QCAT_URI_SCHEME))
+        .map(QcatUri::from_str)
+        .transpose()?;
+    let (hostname, port) = match &uri {
+        Some(uri) => (uri.host.as_str(), uri.port),
+        // clap requires a hostname unless we're running a subcommand or given a QR code
+        None => (
+            args.hostname.as_deref().ok_or("Missing hostname")?,
+            args.port.ok_or("Missing port")?,
+        ),
+    };
+    let kdf_params = args.kdf_params();
+    let salt = args.salt.as_deref();
+    if let Some(code) = &args.wordlist_lang {
+        crypto::select_wordlist_lang(crypto::WordlistLang::from_code(code));
+    }
+    if args.easy_words {
+        crypto::select_easy_words()?;
+        info!(
+            "Using easy words, ~{:.1} bits per passphrase",
+            crypto::passphrase_entropy_bits()
+        );
+    }
+
+    let lookup_start = Instant::now();
+    let socket_addrs = resolve(hostname, port, args.numeric).await?;
+    TIMINGS.record("DNS lookup", lookup_start.elapsed());
+    let socket_addr = preferred_addr(&socket_addrs)
+        .ok_or_else(|| format!("{} did not resolve to any addresses", hostname))?;
+
+    let transcript = args
+        .transcript
+        .as_deref()
+        .map(Transcript::create)
+        .transpose()?;
+
+    let options = core::QcatOptions {
+        max_mtu: args
+            .mtu
+            .or(args.conservative_mtu.then_some(core::CONSERVATIVE_MTU)),
+        max_duration: args.max_duration.map(Duration::from_secs),
+        no_delay: args.no_delay,
+        buffer_size: args.low_memory.map(|buffer_size| buffer_size as usize),
+        receive_window: args.receive_window,
+        wait_for_server: args.wait,
+        retry_delay: Duration::from_secs_f64(args.retry_delay),
+        stream_window: args.stream_window,
+        allowed_networks: args.allowed_networks.clone(),
+        wait_for_ready: args.wait_ready,
+        keep_receiving: args.keep_receiving,
+        ping_interval: args.ping.map(Duration::from_secs_f64),
+        compression: args.compress,
+        zstd_dictionary: args
+            .zstd_dict
+            .as_deref()
+            .map(ZstdDictionary::load)
+            .transpose()?,
+        dscp: args.dscp,
+        max_clock_skew: args.max_clock_skew.map(Duration::from_secs),
+        expires_at: args
+            .expire_in
+            .map(|expire_in| SystemTime::now() + Duration::from_secs(expire_in)),
+        max_failed_attempts: args.max_failed_attempts,
+        strict_wordlist: args.strict_wordlist,
+        block_duration: Duration::from_secs(args.block_duration),
+        file_name: args
+            .send
+            .as_deref()
+            .and_then(|path| path.file_name())
+            .map(|file_name| file_name.to_string_lossy().into_owned()),
+        file_metadata: match &args.send {
+            Some(path) if args.preserve => {
+                Some(core::FileMetadata::from_metadata(&std::fs::metadata(path)?))
+            }
+            _ => None,
+        },
+        // with nowhere else specified, named files a client sends are saved where we're running
+        save_named_files: (args.output.is_none() && args.unix.is_none())
+            .then(|| PathBuf::from(".")),
+        network_impairment: (args.simulate_latency.is_some() || args.simulate_loss.is_some()).then(
+            || core::NetworkImpairment {
+                latency: Duration::from_millis(args.simulate_latency.unwrap_or_default()),
+                loss_percent: args.simulate_loss.unwrap_or_default(),
+            },
+        ),
+        masque_proxy: args.masque_proxy.clone(),
+        rate_up: args.rate_up,
+        rate_down: args.rate_down,
+        send_limit: args.send_bytes,
+        receive_limit: args.recv_bytes,
+        reap_idle: args.reap_idle.map(Duration::from_secs),
+        reconnect_attempts: args.reconnect,
+        checkpoint_every: args.checkpoint_every,
+        confirm_peer: match (args.confirm, std::io::stdin().is_terminal()) {
+            (true, true) => Some(core::ConfirmCallback::new(confirm_peer_on_terminal)),
+            (true, false) => {
+                warn!("stdin isn't a terminal, skipping --confirm");
+                None
+            }
+            (false, _) => None,
+        },
+        ..Default::default()
+    };
 
     if args.listen {
-        let crypto = CryptoMaterial::generate()?;
-        info!("Generated salt + passphrase: \"{}\"", crypto.passphrase());
+        let mut passphrases = args
+            .passphrases
+            .iter()
+            .map(|passphrase| SaltedPassphrase::parse_with_salt(passphrase, salt))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if passphrases.is_empty() {
+            passphrases.extend(read_credential_passphrase(salt)?);
+        }
+
+        let crypto = if passphrases.is_empty() {
+            let crypto = CryptoMaterial::generate_with_salt(kdf_params, args.salt_kind())?;
+            let generated = match salt {
+                Some(_) => format!(
+                    "Generated passphrase: \"{}\" (use with --salt)",
+                    crypto.passphrase().passphrase()
+                ),
+                None => format!("Generated salt + passphrase: \"{}\"", crypto.passphrase()),
+            };
+            announce_generated_passphrase(&args, &generated);
+            crypto
+        } else {
+            CryptoMaterial::generate_from_passphrase_with_kdf(passphrases.remove(0), kdf_params)?
+        };
+
+        info!(
+            "Certificate fingerprint: {}",
+            Style::Bold.paint(crypto.fingerprint())
+        );
+        if let Some(expire_in) = args.expire_in {
+            info!("Passphrase expires in {}s", expire_in);
+        }
+        // a salt given out-of-band stays out of what we give the client too
+        let peer_passphrase = match salt {
+            Some(_) => crypto.passphrase().passphrase().to_owned(),
+            None => crypto.passphrase().to_string(),
+        };
+        let advertise_host = args.advertise_host.as_deref().unwrap_or(hostname);
+        if (args.uri || args.print_peer_command)
+            && args.advertise_host.is_none()
+            && IpAddr::from_str(hostname).is_ok_and(|ip| ip.is_unspecified())
+        {
+            warn!(
+                "{} isn't reachable by clients, give the host they can reach us at with --advertise-host",
+                hostname
+            );
+        }
+        if args.uri {
+            let uri = QcatUri {
+                passphrase: peer_passphrase.clone(),
+                host: advertise_host.to_owned(),
+                port,
+            };
+            if args.log_passphrase() {
+                info!("Connect with: {}", uri);
+            } else {
+                eprintln!("Connect with: {}", uri);
+            }
+        }
+        if args.print_peer_command {
+            eprintln!(
+                "{}",
+                peer_command(advertise_host, port, &peer_passphrase, salt.is_some())
+            );
+        }
+
+        // any other passphrases are accepted too, i.e. the old passphrase while rotating to a new one
+        let additional_crypto = passphrases
+            .into_iter()
+            .map(|passphrase| {
+                CryptoMaterial::generate_from_passphrase_with_kdf(passphrase, kdf_params)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut config = crypto_config(&args, &crypto)?;
+        for crypto in &additional_crypto {
+            config = config.with_additional_identity(
+                crypto.server_name_hint(),
+                crypto.certificate(),
+                crypto.private_key_der(),
+            );
+        }
+        let bind_addrs = if args.bind_all {
+            socket_addrs.as_slice()
+        } else {
+            std::slice::from_ref(&socket_addr)
+        };
+        #[cfg(unix)]
+        let activated = qcat::utils::systemd_listen_sockets()?;
+        #[cfg(not(unix))]
+        let activated = Vec::new();
+        let mut server = if activated.is_empty() {
+            core::QcatServer::with_addresses(bind_addrs, config, options.clone())?
+        } else {
+            info!(
+                "Socket activated, listening on the {} sockets systemd passed us",
+                activated.len()
+            );
+            core::QcatServer::with_sockets(activated, config, options.clone())?
+        };
+
+        if let Some(metrics_addr) = &args.metrics_addr {
+            tokio::spawn(metrics::serve(TcpListener::bind(metrics_addr).await?));
+        }
 
-        let private_key_der = PrivateKeyDer::Pkcs8(crypto.private_key().clone_key());
-        let config = QcatCryptoConfig::new(crypto.certificate(), &private_key_der);
-        let mut server = core::QcatServer::new(socket_addr, config)?;
+        // our sockets are bound, we no longer need to be root
+        #[cfg(unix)]
+        privileges::drop_privileges(args.user.as_deref(), args.group.as_deref())?;
+        #[cfg(not(unix))]
+        if args.user.is_some() {
+            return Err("--user is only supported on Unix".into());
+        }
 
-        // we spawn a new tokio task for each connection, so wrap stdout in arc + mutex
-        let stdout = Mutex::new(tokio::io::stdout());
-        let mut stdout_arc = Arc::new(stdout);
+        if let Some(path) = &args.push {
+            server.run_push(path).await?;
+            return Ok(());
+        }
 
-        server.run(&mut stdout_arc).await?;
+        if args.echo {
+            server.run_echo().await?;
+            return Ok(());
+        }
+
+        if let Some(target) = &args.tcp_connect {
+            server.run_tcp_connect(target).await?;
+            return Ok(());
+        }
+
+        if let Some(listen_addr) = &args.tcp_listen {
+            server.run_tcp_listen(listen_addr).await?;
+            return Ok(());
+        }
+
+        let encrypt_with = args.encrypt_output.then(|| {
+            info!("Encrypting output at rest, decrypt it with qcat decrypt and the passphrase");
+            (crypto.passphrase(), kdf_params)
+        });
+        let output = open_output(&args, transcript.as_ref(), encrypt_with).await?;
+        let (output, forward) = match &args.forward {
+            Some(target) => {
+                let (forward_output, forward) =
+                    forward_to(&args, &options, target, crypto.passphrase(), kdf_params).await?;
+                let output: Box<dyn AsyncWrite + Unpin + Send> =
+                    Box::new(TeeWriter::new(output, forward_output));
+                (output, Some(forward))
+            }
+            None => (output, None),
+        };
+
+        // we spawn a new tokio task for each connection, so wrap output in arc + mutex
+        let mut output_arc = Arc::new(Mutex::new(output));
+
+        // shutting output down lets --encrypt-output write its final chunk, a --filter finish writing and --forward
+        // finish relaying
+        let serve = server.run_and_shut_down(&mut output_arc);
+        match forward {
+            Some(forward) => {
+                let (served, forwarded) = tokio::join!(serve, forward);
+                served?;
+                forwarded?;
+            }
+            None => serve.await?,
+        }
     } else {
-        let mut stdin = tokio::io::stdin();
+        let passphrase = match args.passphrases.as_slice() {
+            [] => match (&uri, &qr_content) {
+                (Some(uri), _) => SaltedPassphrase::parse_with_salt(&uri.passphrase, salt)?,
+                (None, Some(passphrase)) => SaltedPassphrase::parse_with_salt(passphrase, salt)?,
+                (None, None) => {
+                    let timeout =
+                        (args.prompt_timeout > 0).then(|| Duration::from_secs(args.prompt_timeout));
+                    receive_passphrase_input(salt, timeout).await?
+                }
+            },
+            [passphrase] => SaltedPassphrase::parse_with_salt(passphrase, salt)?,
+            _ => return Err("The client only accepts a single passphrase".into()),
+        };
+        let crypto = CryptoMaterial::generate_from_passphrase_with_kdf(passphrase, kdf_params)?;
+        let options = client_options(&args, &crypto, &options);
+
+        if args.show_fingerprint {
+            // piped, i.e. into a script, the fingerprint stays plain
+            if std::io::stdout().is_terminal() {
+                println!("{}", Style::Bold.paint(crypto.fingerprint()));
+            } else {
+                println!("{}", crypto.fingerprint());
+            }
+            return Ok(());
+        }
+
+        let config = crypto_config(&args, &crypto)?;
+        let mut client = core::QcatClient::with_options(config, options)?;
+
+        if args.receive {
+            let mut output = open_output(&args, transcript.as_ref(), None).await?;
+            client.run_receive(socket_addr, &mut output).await?;
+            // lets a --filter finish writing
+            output.shutdown().await?;
+            return Ok(());
+        }
+
+        if let Some(target) = &args.tcp_connect {
+            if args.reverse {
+                client.run_tcp_connect(socket_addr, target).await?;
+                return Ok(());
+            }
+
+            let (mut tcp_input, mut tcp_output) = TcpStream::connect(target).await?.into_split();
+            info!("Bridging {} over the connection", target);
+            client
+                .run_duplex(socket_addr, &mut tcp_input, &mut tcp_output)
+                .await?;
+            return Ok(());
+        }
+
+        if let (Some(path), Some(streams)) = (&args.send, args.parallel) {
+            client.run_parallel(socket_addr, path, streams).await?;
+            return Ok(());
+        }
+
+        if let (Some(path), true) = (&args.send, args.reconnect > 0) {
+            let mut file = File::open(path).await?;
+            client.run_resumable(socket_addr, &mut file).await?;
+            return Ok(());
+        }
+
+        let mut input = open_input(&args, transcript.as_ref()).await?;
+
+        if args.duplex {
+            let mut output = open_output(&args, transcript.as_ref(), None).await?;
+            client
+                .run_duplex(socket_addr, &mut input, &mut output)
+                .await?;
+            output.shutdown().await?;
+            return Ok(());
+        }
+
+        if args.request {
+            let mut output = open_output(&args, transcript.as_ref(), None).await?;
+            client
+                .run_request(socket_addr, &mut input, &mut output)
+                .await?;
+            output.shutdown().await?;
+            return Ok(());
+        }
+
+        if args.unix.is_none() && args.inputs.is_empty() && args.send.is_none() {
+            print_terminal_input_hint();
+        }
+
+        client.run(socket_addr, &mut input).await?;
+    }
+
+    Ok(())
+}
+
+/// Argon2 costs we benchmark, from argon2's defaults upwards
+const KDF_BENCHMARK_PARAMS: &[KdfParams] = &[
+    KdfParams {
+        memory_kib: 19 * 1024,
+        iterations: 2,
+    },
+    KdfParams {
+        memory_kib: 64 * 1024,
+        iterations: 2,
+    },
+    KdfParams {
+        memory_kib: 64 * 1024,
+        iterations: 4,
+    },
+    KdfParams {
+        memory_kib: 256 * 1024,
+        iterations: 3,
+    },
+    KdfParams {
+        memory_kib: 512 * 1024,
+        iterations: 4,
+    },
+    KdfParams {
+        memory_kib: 1024 * 1024,
+        iterations: 4,
+    },
+];
+
+/// Decrypt a file written with --encrypt-output, to output or stdout
+async fn decrypt_file(
+    input: &Path,
+    output: Option<&Path>,
+    passphrase: Option<&str>,
+    salt: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let passphrase = match passphrase {
+        Some(passphrase) => SaltedPassphrase::parse_with_salt(passphrase, salt)?,
+        None => receive_passphrase_input(salt, None).await?,
+    };
 
-        let passphrase = receive_passphrase_input()?;
-        let crypto = CryptoMaterial::generate_from_passphrase(passphrase)?;
+    let mut input = File::open(input).await?;
+    let mut output: Box<dyn AsyncWrite + Unpin + Send> = match output {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    let decrypted = encryption::decrypt(&mut input, &mut output, &passphrase).await?;
+    info!("Decrypted {} bytes", decrypted);
 
-        let private_key_der = PrivateKeyDer::Pkcs8(crypto.private_key().clone_key());
-        let config = QcatCryptoConfig::new(crypto.certificate(), &private_key_der);
-        let mut client = core::QcatClient::new(config)?;
+    Ok(())
+}
 
-        client.run(socket_addr, &mut stdin).await?;
+/// Benchmark key derivation with increasing costs, recommending the most expensive one within our target duration
+fn kdf_bench(target: Duration) -> Result<(), Box<dyn Error>> {
+    let mut recommended = None;
+
+    for params in KDF_BENCHMARK_PARAMS {
+        let elapsed = params.benchmark()?;
+        println!(
+            "--kdf-mem {} --kdf-iters {}: {:.3}s",
+            params.memory_kib,
+            params.iterations,
+            elapsed.as_secs_f64()
+        );
+
+        if elapsed > target {
+            // costs only go up from here
+            break;
+        }
+        recommended = Some(params);
+    }
+
+    match recommended {
+        Some(params) => println!(
+            "Recommended for ~{:.1}s: --kdf-mem {} --kdf-iters {}",
+            target.as_secs_f64(),
+            params.memory_kib,
+            params.iterations
+        ),
+        None => println!(
+            "Even the cheapest settings took longer than {:.1}s, use the defaults",
+            target.as_secs_f64()
+        ),
     }
 
     Ok(())
 }
+
+/// Where data to send is read from, stdin unless otherwise specified
+async fn open_input(
+    args: &args::Args,
+    transcript: Option<&Transcript>,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>, Box<dyn Error>> {
+    let input: Box<dyn AsyncRead + Unpin + Send> = match &args.unix {
+        #[cfg(unix)]
+        Some(socket) => Box::new(UnixSocketAddr::parse(socket)?.connect().await?),
+        _ if args.clipboard => Box::new(std::io::Cursor::new(read_clipboard().await?)),
+        _ if args.merge => merge_files(&args.inputs).await?,
+        _ if !args.inputs.is_empty() => concat_files(&args.inputs).await?,
+        _ if args.send.is_some() => concat_files(args.send.as_slice()).await?,
+        _ => Box::new(tokio::io::stdin()),
+    };
+    let input: Box<dyn AsyncRead + Unpin + Send> = match &args.filter {
+        Some(command) => Box::new(FilteredReader::spawn(command, input)?),
+        None => input,
+    };
+
+    Ok(match transcript {
+        Some(transcript) => Box::new(transcript.tap_reader(input, Direction::Sent)),
+        None => input,
+    })
+}
+
+/// Open each file, reading from all of them at once as they have data
+async fn merge_files(
+    paths: &[PathBuf],
+) -> Result<Box<dyn AsyncRead + Unpin + Send>, Box<dyn Error>> {
+    let mut files = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        files.push(
+            File::open(path)
+                .await
+                .map_err(|e| format!("Unable to open {}: {}", path.display(), e))?,
+        );
+    }
+
+    Ok(Box::new(MergedReader::new(files)))
+}
+
+/// Where received data is written, stdout unless otherwise specified
+async fn open_output(
+    args: &args::Args,
+    transcript: Option<&Transcript>,
+    encrypt_with: Option<(&SaltedPassphrase, KdfParams)>,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send>, Box<dyn Error>> {
+    let output = open_output_destination(args).await?;
+    // encrypted last thing before it hits the disk, so filters and the transcript see plaintext
+    let output: Box<dyn AsyncWrite + Unpin + Send> = match encrypt_with {
+        Some((passphrase, kdf_params)) => {
+            Box::new(EncryptingWriter::new(output, passphrase, kdf_params)?)
+        }
+        None => output,
+    };
+    let output: Box<dyn AsyncWrite + Unpin + Send> = match &args.filter {
+        Some(command) => Box::new(FilteredWriter::spawn(command, output)?),
+        None => output,
+    };
+
+    Ok(match transcript {
+        Some(transcript) => Box::new(transcript.tap_writer(output, Direction::Received)),
+        None => output,
+    })
+}
+
+/// Pick which of a host's addresses to connect to, preferring IPv4 as our client endpoint is bound to an IPv4 address
+fn preferred_addr(addrs: &[SocketAddr]) -> Option<SocketAddr> {
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+}
+
+/// Size of the pipe between a --forward server and the client relaying what it receives
+const FORWARD_PIPE_SIZE: usize = 64 * 1024;
+
+/// crypto's config with what our arguments ask for, the same for a server, a client and the client it forwards to
+fn crypto_config<'a>(
+    args: &args::Args,
+    crypto: &'a CryptoMaterial,
+) -> Result<QcatCryptoConfig<'a>, Box<dyn Error>> {
+    Ok(crypto
+        .crypto_config()
+        .with_cipher_suites(&args.ciphers)?
+        .with_signature_schemes(&args.signature_schemes)?
+        .with_security_level(args.security_level)?
+        .with_early_data(args.zero_rtt)
+        .with_post_quantum_key_exchange(args.pq)
+        .with_pin_mode(args.pin_mode)
+        .with_explain_cert(args.explain_cert)
+        .with_key_log_file(std::env::var_os(crypto::KEY_LOG_FILE_ENV).map(PathBuf::from)))
+}
+
+/// options for a client connecting to a server holding crypto, including one a server forwards to
+fn client_options(
+    args: &args::Args,
+    crypto: &CryptoMaterial,
+    options: &core::QcatOptions,
+) -> core::QcatOptions {
+    core::QcatOptions {
+        server_name: Some(
+            args.sni
+                .clone()
+                .unwrap_or_else(|| crypto.server_name_hint()),
+        ),
+        // a tunnel stays open until both ends of it are done
+        keep_receiving: options.keep_receiving || args.tcp_connect.is_some(),
+        ..options.clone()
+    }
+}
+
+/// Set up a client to the qcat server at target, for a --forward server to relay what it receives through. Returns the
+/// writer to relay through and the client's run, which finishes once the writer is shut down
+async fn forward_to(
+    args: &args::Args,
+    options: &core::QcatOptions,
+    target: &str,
+    passphrase: &SaltedPassphrase,
+    kdf_params: KdfParams,
+) -> Result<
+    (
+        DuplexStream,
+        impl Future<Output = Result<(), Box<dyn Error>>>,
+    ),
+    Box<dyn Error>,
+> {
+    let addrs: Vec<SocketAddr> = if args.numeric {
+        vec![SocketAddr::from_str(target)
+            .map_err(|_| format!("{} is not a numeric IP address and port (-n)", target))?]
+    } else {
+        lookup_host(target).await?.collect()
+    };
+    let addr = preferred_addr(&addrs)
+        .ok_or_else(|| format!("{} did not resolve to any addresses", target))?;
+
+    let passphrase = match &args.forward_passphrase {
+        Some(passphrase) => SaltedPassphrase::parse_with_salt(passphrase, args.salt.as_deref())?,
+        None => SaltedPassphrase::new(passphrase.salt(), passphrase.passphrase()),
+    };
+    let crypto = CryptoMaterial::generate_from_passphrase_with_kdf(passphrase, kdf_params)?;
+    // only what shapes the connection carries over, not our own server's expiry, limits and the like
+    let options = core::QcatOptions {
+        max_mtu: options.max_mtu,
+        compression: options.compression,
+        zstd_dictionary: options.zstd_dictionary.clone(),
+        receive_window: options.receive_window,
+        stream_window: options.stream_window,
+        ..Default::default()
+    };
+    let options = client_options(args, &crypto, &options);
+    let config = crypto_config(args, &crypto)?;
+    let client = core::QcatClient::with_options(config, options)?;
+
+    info!("Forwarding received data to {}", target);
+    Ok(client.forward(addr, FORWARD_PIPE_SIZE))
+}
+
+async fn open_output_destination(
+    args: &args::Args,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send>, Box<dyn Error>> {
+    #[cfg(unix)]
+    if let Some(socket) = &args.unix {
+        return Ok(Box::new(UnixSocketAddr::parse(socket)?.connect().await?));
+    }
+
+    if let Some(path) = &args.output {
+        if let Some(threshold) = args.output_rotate {
+            return Ok(Box::new(RotatingFile::create(path, threshold)?));
+        }
+        #[cfg(target_os = "linux")]
+        if args.direct_io {
+            return Ok(direct::create(path)?);
+        }
+        #[cfg(not(target_os = "linux"))]
+        if args.direct_io {
+            return Err("--direct-io is only supported on Linux".into());
+        }
+        return Ok(Box::new(open_output_file(path, args.append).await?));
+    }
+
+    if args.clipboard {
+        return Ok(Box::new(ClipboardWriter::new()));
+    }
+
+    // a --forward relay with nowhere local to write only relays
+    if args.forward.is_some() {
+        return Ok(Box::new(tokio::io::sink()));
+    }
+
+    // binary safe on Windows too: Rust writes straight to the handle rather than through the C runtime, so there's no
+    // text mode CRLF translation to switch off (and files are always opened as binary)
+    Ok(Box::new(tokio::io::stdout()))
+}
@@ -0,0 +1,447 @@
+use log::{debug, warn};
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, UdpSocket,
+    },
+};
+
+/// URI template for UDP proxying from RFC 9298, used when the proxy URL doesn't give its own
+const DEFAULT_URI_TEMPLATE: &str = "/.well-known/masque/udp/{target_host}/{target_port}/";
+
+/// Most we'll read of the proxy's response headers before giving up on it
+const MAX_RESPONSE_HEADER_LEN: usize = 8 * 1024;
+
+/// Capsule type for an HTTP datagram (RFC 9297)
+const DATAGRAM_CAPSULE_TYPE: u64 = 0x00;
+
+/// Context ID that marks a capsule's payload as a whole UDP datagram (RFC 9298)
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+
+/// Largest value a QUIC variable-length integer can hold
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// A MASQUE proxy we tunnel our QUIC packets through with CONNECT-UDP (RFC 9298). We speak the HTTP/1.1 flavor,
+/// upgrading a TCP connection with "Upgrade: connect-udp" and exchanging datagrams as capsules (RFC 9297), so only
+/// plain http:// proxies are supported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MasqueProxy {
+    host: String,
+    port: u16,
+    uri_template: String,
+}
+
+impl MasqueProxy {
+    /// Open a tunnel to target through the proxy, relaying it to a local socket. Returns the address the client
+    /// should connect to in place of the target
+    pub async fn spawn_relay(&self, target: SocketAddr) -> io::Result<SocketAddr> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream.set_nodelay(true)?;
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        write_half
+            .write_all(self.connect_request(target).as_bytes())
+            .await?;
+        read_response(&mut reader).await?;
+        debug!(
+            "MASQUE proxy {}:{} tunneling to {}",
+            self.host, self.port, target
+        );
+
+        // our client endpoint is bound to an IPv4 address
+        let client_side = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let relay_addr = client_side.local_addr()?;
+
+        tokio::spawn(async move {
+            let result = tokio::select! {
+                result = relay_to_proxy(&client_side, &mut write_half) => result,
+                result = relay_from_proxy(&client_side, &mut reader) => result,
+            };
+            if let Err(e) = result {
+                warn!("MASQUE tunnel closed: {}", e);
+            }
+        });
+
+        Ok(relay_addr)
+    }
+
+    /// Build the HTTP/1.1 request upgrading our connection to a UDP tunnel to target
+    fn connect_request(&self, target: SocketAddr) -> String {
+        // IPv6 addresses are percent-encoded, as the template expansion would do
+        let target_host = target.ip().to_string().replace(':', "%3A");
+        let path = self
+            .uri_template
+            .replace("{target_host}", &target_host)
+            .replace("{target_port}", &target.port().to_string());
+
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\nCapsule-Protocol: ?1\r\n\r\n",
+            path, self.host, self.port
+        )
+    }
+}
+
+impl FromStr for MasqueProxy {
+    type Err = String;
+
+    /// Parse a proxy URL, i.e. "http://proxy:8080" or with its own URI template,
+    /// "http://proxy:8080/masque?h={target_host}&p={target_port}"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or("MASQUE proxy URL must start with http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|e| format!("invalid MASQUE proxy port: {}", e))?,
+            ),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("MASQUE proxy URL has no host".to_owned());
+        }
+
+        let uri_template = match path {
+            "" | "/" => DEFAULT_URI_TEMPLATE.to_owned(),
+            path if path.contains("{target_host}") && path.contains("{target_port}") => {
+                path.to_owned()
+            }
+            _ => {
+                return Err(
+                    "MASQUE proxy URI template must contain {target_host} and {target_port}"
+                        .to_owned(),
+                )
+            }
+        };
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            uri_template,
+        })
+    }
+}
+
+impl fmt::Display for MasqueProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(
+                f,
+                "http://[{}]:{}{}",
+                self.host, self.port, self.uri_template
+            )
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.uri_template)
+        }
+    }
+}
+
+/// Read the proxy's response headers, failing unless it switched protocols
+async fn read_response(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<()> {
+    let mut status_line = String::new();
+    let mut header_len = reader.read_line(&mut status_line).await?;
+
+    // drain the rest of the headers so the capsules that follow start cleanly
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).await?;
+        header_len += read;
+        if read == 0 || header_len > MAX_RESPONSE_HEADER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "MASQUE proxy sent a malformed response",
+            ));
+        }
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    match status_line.split_whitespace().nth(1) {
+        Some("101") => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "MASQUE proxy refused the tunnel: {}",
+                status_line.trim_end()
+            ),
+        )),
+    }
+}
+
+/// Wrap each datagram our client sends in a capsule to the proxy
+async fn relay_to_proxy(client_side: &UdpSocket, proxy: &mut OwnedWriteHalf) -> io::Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut capsule = Vec::with_capacity(u16::MAX as usize + 16);
+    let mut client_addr = None;
+
+    loop {
+        let (len, from) = client_side.recv_from(&mut buf).await?;
+        // replies go back to whoever's sending, which is only ever our client
+        if client_addr != Some(from) {
+            client_side.connect(from).await?;
+            client_addr = Some(from);
+        }
+
+        capsule.clear();
+        write_varint(&mut capsule, DATAGRAM_CAPSULE_TYPE);
+        write_varint(
+            &mut capsule,
+            varint_len(UDP_PAYLOAD_CONTEXT_ID) + len as u64,
+        );
+        write_varint(&mut capsule, UDP_PAYLOAD_CONTEXT_ID);
+        capsule.extend_from_slice(&buf[..len]);
+        proxy.write_all(&capsule).await?;
+    }
+}
+
+/// Unwrap the datagrams in the proxy's capsules and pass them on to our client, skipping any other capsules
+async fn relay_from_proxy(
+    client_side: &UdpSocket,
+    proxy: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+
+    loop {
+        let capsule_type = read_varint(proxy).await?;
+        let len = read_varint(proxy).await?;
+        if len > u16::MAX as u64 + 8 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "MASQUE proxy sent an oversized capsule",
+            ));
+        }
+        buf.resize(len as usize, 0);
+        proxy.read_exact(&mut buf).await?;
+
+        if capsule_type != DATAGRAM_CAPSULE_TYPE {
+            continue;
+        }
+
+        let mut payload = buf.as_slice();
+        let context_id = read_varint(&mut payload).await?;
+        if context_id != UDP_PAYLOAD_CONTEXT_ID {
+            continue;
+        }
+
+        // nothing to send to until our client has sent something, which it always does first
+        if let Err(e) = client_side.send(payload).await {
+            debug!("MASQUE relay failed to forward datagram: {}", e);
+        }
+    }
+}
+
+/// Number of bytes a QUIC variable-length integer takes to encode value
+fn varint_len(value: u64) -> u64 {
+    match value {
+        0..=0x3f => 1,
+        0x40..=0x3fff => 2,
+        0x4000..=0x3fff_ffff => 4,
+        _ => 8,
+    }
+}
+
+/// Append value as a QUIC variable-length integer (RFC 9000 section 16)
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    debug_assert!(value <= MAX_VARINT);
+    match varint_len(value) {
+        1 => buf.push(value as u8),
+        2 => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        4 => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Read a QUIC variable-length integer
+async fn read_varint<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<u64> {
+    let first = reader.read_u8().await?;
+    let len = 1 << (first >> 6);
+    let mut value = (first & 0x3f) as u64;
+
+    for _ in 1..len {
+        value = (value << 8) | reader.read_u8().await? as u64;
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use tokio::net::TcpListener;
+
+    /// Start a minimal CONNECT-UDP proxy accepting one tunnel, relaying capsules to whatever target the request's
+    /// path names. Answers with status instead of upgrading if it isn't 101
+    async fn spawn_connect_udp_stub(status: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Err(e) = run_connect_udp_stub(listener, status).await {
+                debug!("CONNECT-UDP stub stopped: {}", e);
+            }
+        });
+
+        addr
+    }
+
+    async fn run_connect_udp_stub(listener: TcpListener, status: &str) -> io::Result<()> {
+        let (stream, _) = listener.accept().await?;
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            if line == "\r\n" {
+                break;
+            }
+        }
+        write_half
+            .write_all(format!("HTTP/1.1 {}\r\nCapsule-Protocol: ?1\r\n\r\n", status).as_bytes())
+            .await?;
+
+        let target: SocketAddr = request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.strip_prefix("/.well-known/masque/udp/"))
+            .and_then(|target| target.strip_suffix('/'))
+            .and_then(|target| target.replacen('/', ":", 1).parse().ok())
+            .ok_or_else(|| io::Error::other("bad CONNECT-UDP request"))?;
+        let target_side = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        target_side.connect(target).await?;
+
+        tokio::select! {
+            result = stub_to_target(&mut reader, &target_side) => result,
+            result = stub_from_target(&target_side, &mut write_half) => result,
+        }
+    }
+
+    async fn stub_to_target(
+        proxy: &mut BufReader<OwnedReadHalf>,
+        target_side: &UdpSocket,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        loop {
+            let capsule_type = read_varint(proxy).await?;
+            let len = read_varint(proxy).await?;
+            buf.resize(len as usize, 0);
+            proxy.read_exact(&mut buf).await?;
+
+            let mut payload = buf.as_slice();
+            if capsule_type == DATAGRAM_CAPSULE_TYPE
+                && read_varint(&mut payload).await? == UDP_PAYLOAD_CONTEXT_ID
+            {
+                target_side.send(payload).await?;
+            }
+        }
+    }
+
+    async fn stub_from_target(
+        target_side: &UdpSocket,
+        proxy: &mut OwnedWriteHalf,
+    ) -> io::Result<()> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let len = target_side.recv(&mut buf).await?;
+            let mut capsule = Vec::new();
+            write_varint(&mut capsule, DATAGRAM_CAPSULE_TYPE);
+            write_varint(
+                &mut capsule,
+                varint_len(UDP_PAYLOAD_CONTEXT_ID) + len as u64,
+            );
+            write_varint(&mut capsule, UDP_PAYLOAD_CONTEXT_ID);
+            capsule.extend_from_slice(&buf[..len]);
+            proxy.write_all(&capsule).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_connect_udp_proxy() {
+        let proxy_addr = spawn_connect_udp_stub("101 Switching Protocols").await;
+        let client_options = QcatOptions {
+            masque_proxy: Some(format!("http://{}", proxy_addr).parse().unwrap()),
+            ..Default::default()
+        };
+        let input: Vec<u8> = (0..=255u8).cycle().take(64 * 1024).collect();
+
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            client_options,
+            &mut input.as_slice(),
+            input.len(),
+        )
+        .await;
+
+        assert_eq!(output, input);
+    }
+
+    #[tokio::test]
+    async fn refused_tunnel_is_an_error() {
+        let proxy_addr = spawn_connect_udp_stub("403 Forbidden").await;
+        let proxy: MasqueProxy = format!("http://{}", proxy_addr).parse().unwrap();
+
+        let err = proxy
+            .spawn_relay((Ipv4Addr::LOCALHOST, 4433).into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn varints_round_trip() {
+        for value in [
+            0,
+            0x3f,
+            0x40,
+            0x3fff,
+            0x4000,
+            0x3fff_ffff,
+            0x4000_0000,
+            MAX_VARINT,
+        ] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf.len() as u64, varint_len(value));
+            assert_eq!(read_varint(&mut buf.as_slice()).await.unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn parses_proxy_urls() {
+        let proxy: MasqueProxy = "http://proxy:8080".parse().unwrap();
+        assert_eq!(
+            proxy.to_string(),
+            format!("http://proxy:8080{}", DEFAULT_URI_TEMPLATE)
+        );
+        let proxy: MasqueProxy = "http://[::1]/masque?h={target_host}&p={target_port}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            proxy.to_string(),
+            "http://[::1]:80/masque?h={target_host}&p={target_port}"
+        );
+
+        assert!("https://proxy:8080".parse::<MasqueProxy>().is_err());
+        assert!("http://proxy:8080/no-template"
+            .parse::<MasqueProxy>()
+            .is_err());
+        assert!("http://:8080".parse::<MasqueProxy>().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Reads from several readers at once, passing on whatever any of them has as soon as it's available rather than one
+/// after another, i.e. to merge live logs. Each read comes whole from one reader, so a chunk one source wrote in one
+/// go isn't interleaved with another's. Readers are polled in turn so a busy one can't starve the rest. Ends once
+/// every reader has
+pub struct MergedReader<R> {
+    /// Readers that haven't ended yet
+    readers: Vec<R>,
+    /// Which reader to try first next time
+    next: usize,
+}
+
+impl<R> MergedReader<R> {
+    pub fn new(readers: Vec<R>) -> Self {
+        Self { readers, next: 0 }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MergedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        // a reader can't tell us it's ended by reading nothing into a full buffer
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+
+        let mut tried = 0;
+        while tried < this.readers.len() {
+            let index = (this.next + tried) % this.readers.len();
+            match Pin::new(&mut this.readers[index]).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() > filled => {
+                    this.next = index + 1;
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Ok(())) => {
+                    // ended, whatever's after it moves down into its place so gets tried next
+                    this.readers.remove(index);
+                    this.next = index;
+                    tried = 0;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => tried += 1,
+            }
+        }
+
+        if this.readers.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+    use std::io::Write;
+    use tokio::{fs::File, io::AsyncReadExt};
+
+    #[tokio::test]
+    async fn everything_from_both_fifos_arrives() {
+        let dir = tempfile::tempdir().unwrap();
+        // letters from one source, digits from the other, so what came from where can be picked back out
+        let sources = [
+            (dir.path().join("letters"), b"abcdefghij".repeat(2000)),
+            (dir.path().join("digits"), b"0123456789".repeat(3000)),
+        ];
+
+        let mut writers = Vec::new();
+        for (path, data) in &sources {
+            mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+            let (path, data) = (path.clone(), data.clone());
+            // opening a FIFO to write blocks until it's opened to read, so write from threads of their own
+            writers.push(std::thread::spawn(move || {
+                let mut fifo = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+                for chunk in data.chunks(100) {
+                    fifo.write_all(chunk).unwrap();
+                }
+            }));
+        }
+
+        let mut files = Vec::new();
+        for (path, _) in &sources {
+            files.push(File::open(path).await.unwrap());
+        }
+        let mut merged = Vec::new();
+        MergedReader::new(files)
+            .read_to_end(&mut merged)
+            .await
+            .unwrap();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(
+            merged.len(),
+            sources.iter().map(|(_, data)| data.len()).sum::<usize>()
+        );
+        let letters: Vec<u8> = merged
+            .iter()
+            .copied()
+            .filter(u8::is_ascii_alphabetic)
+            .collect();
+        let digits: Vec<u8> = merged.iter().copied().filter(u8::is_ascii_digit).collect();
+        assert_eq!(letters, sources[0].1);
+        assert_eq!(digits, sources[1].1);
+    }
+}
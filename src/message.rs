@@ -0,0 +1,177 @@
+use std::io::{self, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest message we send or accept unless told otherwise, so a peer can't have us allocate whatever it likes
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Splits a byte stream (i.e. a stream from [`crate::core::QcatStream`]) into whole messages, each sent as its u32
+/// big-endian length then the payload, so library users keep message boundaries without framing things themselves
+#[derive(Debug)]
+pub struct MessageStream<S> {
+    inner: S,
+    max_message_len: usize,
+}
+
+impl<S> MessageStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Refuse to send or accept messages longer than max_message_len. Capped to what the u32 length can say
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len.min(u32::MAX as usize);
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MessageStream<S> {
+    /// Send message whole, flushing so it goes out now rather than waiting on the next
+    pub async fn send_message(&mut self, message: &[u8]) -> io::Result<()> {
+        if message.len() > self.max_message_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Message is too long to send",
+            ));
+        }
+
+        self.inner.write_u32(message.len() as u32).await?;
+        self.inner.write_all(message).await?;
+        self.inner.flush().await
+    }
+
+    /// Close our side of the stream, telling the peer there are no more messages
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+impl<S: AsyncRead + Unpin> MessageStream<S> {
+    /// The next message the peer sent, however it was split up on the way, or None once the peer has closed its side
+    /// between messages. Closing partway through one is an error
+    pub async fn recv_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 4];
+        let read = self.inner.read(&mut len).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut len[read..]).await?;
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_message_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Peer sent an overlong message",
+            ));
+        }
+
+        let mut message = vec![0u8; len];
+        self.inner.read_exact(&mut message).await?;
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use futures_core::Stream;
+    use std::pin::Pin;
+
+    fn messages() -> Vec<Vec<u8>> {
+        [0, 1, 5, 1000, 100_000]
+            .into_iter()
+            .map(|len| (0..=255u8).cycle().take(len).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn messages_keep_their_boundaries() {
+        // a pipe far smaller than the messages, so each arrives in many pieces
+        let (sending, receiving) = tokio::io::duplex(7);
+        let mut sender = MessageStream::new(sending);
+        let mut receiver = MessageStream::new(receiving);
+
+        let send = async {
+            for message in messages() {
+                sender.send_message(&message).await.unwrap();
+            }
+            sender.shutdown().await.unwrap();
+        };
+        let receive = async {
+            let mut received = Vec::new();
+            while let Some(message) = receiver.recv_message().await.unwrap() {
+                received.push(message);
+            }
+            received
+        };
+        let ((), received) = tokio::join!(send, receive);
+
+        assert_eq!(received, messages());
+    }
+
+    #[tokio::test]
+    async fn overlong_and_cut_off_messages_are_errors() {
+        let (sending, receiving) = tokio::io::duplex(1024);
+        let mut sender = MessageStream::new(sending).with_max_message_len(16);
+        let error = sender.send_message(&[0; 17]).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        // the peer's limit is its own, we refuse what's over ours
+        let mut sender = MessageStream::new(sender.into_inner());
+        sender.send_message(&[0; 17]).await.unwrap();
+        let mut receiver = MessageStream::new(receiving).with_max_message_len(16);
+        let error = receiver.recv_message().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let (mut sending, receiving) = tokio::io::duplex(1024);
+        sending.write_u32(10).await.unwrap();
+        sending.write_all(b"short").await.unwrap();
+        drop(sending);
+        let error = MessageStream::new(receiving)
+            .recv_message()
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn messages_cross_a_qcat_connection() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut server, addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let mut incoming = server.incoming();
+
+        let serve = async {
+            let mut conn = std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let mut stream = conn.accept_message_stream().await.unwrap().unwrap();
+            let mut received = Vec::new();
+            while let Some(message) = stream.recv_message().await.unwrap() {
+                received.push(message);
+            }
+            received
+        };
+        let send = async {
+            let mut conn = client.open(addr).await.unwrap();
+            let mut stream = conn.open_message_stream().await.unwrap();
+            for message in messages() {
+                stream.send_message(&message).await.unwrap();
+            }
+            stream.shutdown().await.unwrap();
+            conn
+        };
+        let (received, _conn) = tokio::join!(serve, send);
+
+        assert_eq!(received, messages());
+    }
+}
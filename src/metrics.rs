@@ -0,0 +1,209 @@
+use log::{debug, info};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Process wide counters, served in the Prometheus text format by [`serve`]
+pub static METRICS: Metrics = Metrics::new();
+
+/// Most of a request we bother reading before answering, we only ever serve the one page
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    active_connections: AtomicU64,
+    bytes_transferred_total: AtomicU64,
+    handshake_failures_total: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections_total: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            bytes_transferred_total: AtomicU64::new(0),
+            handshake_failures_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_transferred(&self, bytes: u64) {
+        self.bytes_transferred_total
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Our metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "qcat_connections_total",
+                "counter",
+                "Connections accepted",
+                &self.connections_total,
+            ),
+            (
+                "qcat_active_connections",
+                "gauge",
+                "Connections currently open",
+                &self.active_connections,
+            ),
+            (
+                "qcat_bytes_transferred_total",
+                "counter",
+                "Bytes transferred over completed connections",
+                &self.bytes_transferred_total,
+            ),
+            (
+                "qcat_handshake_failures_total",
+                "counter",
+                "Connections that closed before completing their handshake",
+                &self.handshake_failures_total,
+            ),
+        ];
+
+        let mut rendered = String::new();
+        for (name, kind, help, value) in metrics {
+            // writing to a String can't fail
+            let _ = writeln!(rendered, "# HELP {} {}", name, help);
+            let _ = writeln!(rendered, "# TYPE {} {}", name, kind);
+            let _ = writeln!(rendered, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        rendered
+    }
+}
+
+/// Answer every HTTP request on listener with our metrics, regardless of path
+pub async fn serve(listener: TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream).await {
+                        debug!("Failed to serve metrics: {}", e);
+                    }
+                });
+            }
+            Err(e) => debug!("Failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    // read up to the end of the request headers, we don't care what was asked for
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() > MAX_REQUEST_LEN {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let body = METRICS.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with("HTTP/1.1 200 OK"));
+        body.to_owned()
+    }
+
+    fn value(metrics: &str, name: &str) -> u64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn scrape_reflects_a_transfer() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let metrics_addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        // counters are process wide and other tests move them too, so only look at how far ours moved them
+        let before = scrape(metrics_addr).await;
+        let input = vec![7u8; 4096];
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let (output, after) = test_utils::with_receiving_server(server, |_| async {
+            client.run(addr, &mut input.as_slice()).await.unwrap();
+            // bytes are counted once the connection is done
+            let expected_bytes =
+                value(&before, "qcat_bytes_transferred_total") + input.len() as u64;
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    let after = scrape(metrics_addr).await;
+                    if value(&after, "qcat_bytes_transferred_total") >= expected_bytes {
+                        return after;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("bytes never counted")
+        })
+        .await;
+
+        assert_eq!(output, input);
+        assert!(value(&after, "qcat_connections_total") > value(&before, "qcat_connections_total"));
+        for name in [
+            "qcat_connections_total",
+            "qcat_active_connections",
+            "qcat_bytes_transferred_total",
+            "qcat_handshake_failures_total",
+        ] {
+            assert!(after.contains(&format!("# TYPE {} ", name)));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+use tokio::io::AsyncReadExt;
+
+/// Process wide record of how much of each parallel transfer we've received across its segments, so whichever
+/// segment finishes last knows to check the whole file
+pub static PARALLEL_TRANSFERS: ParallelTransfers = ParallelTransfers::new();
+
+/// Bytes received per transfer ID, for parallel transfers still in progress
+#[derive(Debug)]
+pub struct ParallelTransfers {
+    received: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl ParallelTransfers {
+    const fn new() -> Self {
+        Self {
+            received: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Note a segment of transfer_id has arrived, true if it's the first so should create the file
+    pub fn start(&self, transfer_id: u64) -> bool {
+        let mut received = self.received.lock().unwrap_or_else(PoisonError::into_inner);
+        if received.contains_key(&transfer_id) {
+            return false;
+        }
+        received.insert(transfer_id, 0);
+        true
+    }
+
+    /// Count bytes towards transfer_id, true once all total_len have arrived, at which point it's forgotten
+    pub fn add(&self, transfer_id: u64, bytes: u64, total_len: u64) -> bool {
+        let mut received = self.received.lock().unwrap_or_else(PoisonError::into_inner);
+        let transfer = received.entry(transfer_id).or_default();
+        *transfer += bytes;
+        if *transfer < total_len {
+            return false;
+        }
+
+        received.remove(&transfer_id);
+        true
+    }
+
+    /// Forget a transfer that failed, so nothing waits on it
+    pub fn abandon(&self, transfer_id: u64) {
+        self.received
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&transfer_id);
+    }
+}
+
+/// Length and SHA-256 of a file, read through once. The digest lets the receiver check a file reassembled from
+/// segments matches what was sent
+pub async fn file_digest(path: &Path) -> io::Result<(u64, [u8; 32])> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut len = 0;
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok((len, hasher.finalize().into()));
+        }
+        hasher.update(&buffer[..read]);
+        len += read as u64;
+    }
+}
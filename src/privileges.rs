@@ -0,0 +1,100 @@
+use log::info;
+use nix::unistd::{geteuid, setgid, setuid, Gid, Group, Uid, User};
+use std::error::Error;
+
+/// Switch to an unprivileged user and group, i.e. once the server has bound a low port as root. Running as root
+/// without a user to drop to is an error, so we never end up serving as root by accident
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let Some(user) = user else {
+        if geteuid().is_root() {
+            return Err(
+                "Refusing to serve as root, use --user to drop privileges once we've bound our socket".into(),
+            );
+        }
+        return Ok(());
+    };
+
+    let (uid, primary_gid) = resolve_user(user)?;
+    let gid = match group {
+        Some(group) => resolve_group(group)?,
+        None => {
+            primary_gid.ok_or_else(|| format!("User {} has no primary group, use --group", user))?
+        }
+    };
+
+    // supplementary groups and gid first, we can't change them once we've given up root
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    nix::unistd::setgroups(&[gid])?;
+    setgid(gid)?;
+    setuid(uid)?;
+
+    info!("Dropped privileges to uid {} gid {}", uid, gid);
+
+    Ok(())
+}
+
+/// Resolve a user name or numeric uid, along with the user's primary group if they have a passwd entry
+pub fn resolve_user(user: &str) -> Result<(Uid, Option<Gid>), Box<dyn Error>> {
+    if let Some(found) = User::from_name(user)? {
+        return Ok((found.uid, Some(found.gid)));
+    }
+
+    let uid = Uid::from_raw(
+        user.parse()
+            .map_err(|_| format!("Unknown user: {}", user))?,
+    );
+    Ok((uid, User::from_uid(uid)?.map(|found| found.gid)))
+}
+
+/// Resolve a group name or numeric gid
+pub fn resolve_group(group: &str) -> Result<Gid, Box<dyn Error>> {
+    if let Some(found) = Group::from_name(group)? {
+        return Ok(found.gid);
+    }
+
+    Ok(Gid::from_raw(
+        group
+            .parse()
+            .map_err(|_| format!("Unknown group: {}", group))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_users_by_name_and_uid() {
+        assert_eq!(
+            resolve_user("root").unwrap(),
+            (Uid::from_raw(0), Some(Gid::from_raw(0)))
+        );
+        assert_eq!(
+            resolve_user("0").unwrap(),
+            (Uid::from_raw(0), Some(Gid::from_raw(0)))
+        );
+        // a uid without a passwd entry is still usable, it just has no primary group
+        assert_eq!(
+            resolve_user("4000000000").unwrap(),
+            (Uid::from_raw(4_000_000_000), None)
+        );
+        assert!(resolve_user("no-such-qcat-user").is_err());
+    }
+
+    #[test]
+    fn resolves_groups_by_name_and_gid() {
+        assert_eq!(resolve_group("0").unwrap(), Gid::from_raw(0));
+        assert_eq!(
+            resolve_group("4000000000").unwrap(),
+            Gid::from_raw(4_000_000_000)
+        );
+        assert!(resolve_group("no-such-qcat-group").is_err());
+    }
+
+    #[test]
+    fn root_needs_a_user_to_drop_to() {
+        // without a user this is a no-op, unless we're root. Dropping to one would change this whole test process
+        let result = drop_privileges(None, None);
+        assert_eq!(result.is_err(), geteuid().is_root());
+    }
+}
@@ -0,0 +1,193 @@
+use bytes::Bytes;
+use log::info;
+use s2n_quic::{connection::Handle, stream::BidirectionalStream, Connection};
+
+use crate::{core::Handshake, resume::RESUMABLE_TRANSFERS};
+use std::{io::ErrorKind, time::Duration};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+/// Sent by the server on its own stream once it has accepted a connection and is ready to receive data, followed by
+/// the server's own handshake so the client can check its clock too
+const READY_MARKER: &[u8] = b"QCAT READY\n";
+
+/// Control stream message tags. Pings and pongs carry a u64 sequence number
+const CONTROL_PING: u8 = 0x01;
+const CONTROL_PONG: u8 = 0x02;
+
+/// Asks how much of a resumable transfer the server has received, carrying the u64 transfer ID. Answered with
+/// CONTROL_RESUME_OFFSET carrying the u64 byte count
+const CONTROL_RESUME_QUERY: u8 = 0x03;
+const CONTROL_RESUME_OFFSET: u8 = 0x04;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Connection closed before the server signaled it was ready")]
+    NotReady,
+    #[error("Unexpected ready marker from server")]
+    BadReadyMarker,
+    #[error("Unexpected control message: {0:#04x}")]
+    BadControlMessage(u8),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Signal to the client that we're ready to receive data, sending it our handshake
+pub async fn send_ready(conn: &mut Handle, handshake: &Handshake) -> Result<(), ProtocolError> {
+    let mut stream = conn
+        .open_send_stream()
+        .await
+        .map_err(std::io::Error::from)?;
+    let mut ready = READY_MARKER.to_vec();
+    ready.extend_from_slice(&handshake.serialize());
+    stream
+        .send(Bytes::from(ready))
+        .await
+        .map_err(std::io::Error::from)?;
+    // finish rather than close, we don't want to wait on the client acknowledging the marker before receiving
+    stream.finish().map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+/// Wait for the server to signal it's ready to receive data, returning its handshake
+pub async fn wait_for_ready(conn: &mut Connection) -> Result<Option<Handshake>, ProtocolError> {
+    let mut stream = conn
+        .accept_receive_stream()
+        .await
+        .map_err(std::io::Error::from)?
+        .ok_or(ProtocolError::NotReady)?;
+
+    read_ready(&mut stream).await
+}
+
+/// Read the server's ready signal, returning the handshake after it. None from servers that only sent the marker
+async fn read_ready<R: AsyncReadExt + Unpin + ?Sized>(
+    stream: &mut R,
+) -> Result<Option<Handshake>, ProtocolError> {
+    let mut marker = [0u8; READY_MARKER.len()];
+    stream.read_exact(&mut marker).await?;
+
+    if marker != READY_MARKER {
+        return Err(ProtocolError::BadReadyMarker);
+    }
+
+    match Handshake::read(stream).await {
+        Ok(handshake) => Ok(Some(handshake)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Periodically ping the peer over the control stream, logging the application level round trip time of each
+pub async fn ping_loop(
+    control_stream: BidirectionalStream,
+    interval: Duration,
+) -> Result<(), ProtocolError> {
+    let (mut receive_stream, mut send_stream) = control_stream.split();
+    let mut ticker = tokio::time::interval(interval);
+
+    let mut sequence = 0u64;
+    loop {
+        ticker.tick().await;
+
+        let sent_at = Instant::now();
+        send_stream.write_u8(CONTROL_PING).await?;
+        send_stream.write_u64(sequence).await?;
+        // SendStream's own flush returns a stream::Error, AsyncWriteExt's an io::Error like the writes above
+        AsyncWriteExt::flush(&mut send_stream).await?;
+
+        let tag = receive_stream.read_u8().await?;
+        if tag != CONTROL_PONG {
+            return Err(ProtocolError::BadControlMessage(tag));
+        }
+        let echoed_sequence = receive_stream.read_u64().await?;
+
+        if echoed_sequence == sequence {
+            info!(
+                "Ping {}: rtt {:.2}ms",
+                sequence,
+                sent_at.elapsed().as_secs_f64() * 1000.0
+            );
+        }
+        sequence += 1;
+    }
+}
+
+/// Ask the server how many bytes of a resumable transfer it has received, i.e. where to resume it from
+pub async fn query_resume_offset(
+    conn: &mut Connection,
+    transfer_id: u64,
+) -> Result<u64, ProtocolError> {
+    let control_stream = conn
+        .open_bidirectional_stream()
+        .await
+        .map_err(std::io::Error::from)?;
+    let (mut receive_stream, mut send_stream) = control_stream.split();
+
+    send_stream.write_u8(CONTROL_RESUME_QUERY).await?;
+    send_stream.write_u64(transfer_id).await?;
+    AsyncWriteExt::shutdown(&mut send_stream).await?;
+
+    let tag = receive_stream.read_u8().await?;
+    if tag != CONTROL_RESUME_OFFSET {
+        return Err(ProtocolError::BadControlMessage(tag));
+    }
+
+    Ok(receive_stream.read_u64().await?)
+}
+
+/// Answer the peer's control messages until it closes the control stream
+pub async fn serve_control(control_stream: BidirectionalStream) -> Result<(), ProtocolError> {
+    let (mut receive_stream, mut send_stream) = control_stream.split();
+
+    loop {
+        let tag = match receive_stream.read_u8().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match tag {
+            CONTROL_PING => {
+                let sequence = receive_stream.read_u64().await?;
+                send_stream.write_u8(CONTROL_PONG).await?;
+                send_stream.write_u64(sequence).await?;
+                AsyncWriteExt::flush(&mut send_stream).await?;
+            }
+            CONTROL_RESUME_QUERY => {
+                let transfer_id = receive_stream.read_u64().await?;
+                send_stream.write_u8(CONTROL_RESUME_OFFSET).await?;
+                send_stream
+                    .write_u64(RESUMABLE_TRANSFERS.received(transfer_id))
+                    .await?;
+                AsyncWriteExt::flush(&mut send_stream).await?;
+            }
+            tag => return Err(ProtocolError::BadControlMessage(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ready_signal_carries_the_servers_handshake() {
+        let handshake = Handshake::new(0);
+        let mut ready = READY_MARKER.to_vec();
+        ready.extend_from_slice(&handshake.serialize());
+        assert_eq!(read_ready(&mut &ready[..]).await.unwrap(), Some(handshake));
+
+        // servers from before the handshake was sent back only send the marker
+        assert_eq!(read_ready(&mut &READY_MARKER[..]).await.unwrap(), None);
+
+        assert!(matches!(
+            read_ready(&mut &b"QCAT READY?\n"[..]).await,
+            Err(ProtocolError::BadReadyMarker)
+        ));
+    }
+}
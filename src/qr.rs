@@ -0,0 +1,74 @@
+use std::{error::Error, path::Path};
+
+/// Decode the first QR code found in an image file, i.e. a photo of the qcat:// URI or passphrase a server showed
+pub fn decode_qr_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let image = image::open(path)
+        .map_err(|e| format!("Unable to read image {}: {}", path.display(), e))?
+        .to_luma8();
+
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        image.width() as usize,
+        image.height() as usize,
+        |x, y| image.get_pixel(x as u32, y as u32).0[0],
+    );
+
+    let grid = prepared
+        .detect_grids()
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No QR code found in {}", path.display()))?;
+    let (_, content) = grid
+        .decode()
+        .map_err(|e| format!("Unable to decode QR code in {}: {}", path.display(), e))?;
+
+    Ok(content.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+    use qrcode::{Color, QrCode};
+
+    /// Pixels per QR module, and modules of blank border around the code
+    const SCALE: u32 = 8;
+    const QUIET_ZONE: u32 = 4;
+
+    fn write_qr_png(path: &Path, content: &str) {
+        let code = QrCode::new(content).unwrap();
+        let width = code.width() as u32;
+        let colors = code.to_colors();
+        let size = (width + 2 * QUIET_ZONE) * SCALE;
+
+        let image = GrayImage::from_fn(size, size, |x, y| {
+            let (x, y) = (x / SCALE, y / SCALE);
+            let dark = (QUIET_ZONE..QUIET_ZONE + width).contains(&x)
+                && (QUIET_ZONE..QUIET_ZONE + width).contains(&y)
+                && colors[((y - QUIET_ZONE) * width + (x - QUIET_ZONE)) as usize] == Color::Dark;
+            Luma([if dark { 0 } else { 255 }])
+        });
+        image.save(path).unwrap();
+    }
+
+    #[test]
+    fn decodes_generated_qr_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qcat.png");
+        let uri = "qcat://salt-correct-horse-battery@example.com:4433";
+        write_qr_png(&path, uri);
+
+        assert_eq!(decode_qr_file(&path).unwrap(), uri);
+    }
+
+    #[test]
+    fn image_without_qr_code_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blank.png");
+        GrayImage::from_pixel(64, 64, Luma([255]))
+            .save(&path)
+            .unwrap();
+
+        assert!(decode_qr_file(&path).is_err());
+        assert!(decode_qr_file(&dir.path().join("missing.png")).is_err());
+    }
+}
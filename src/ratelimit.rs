@@ -0,0 +1,99 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+/// A token bucket refilling at rate bytes per second, holding up to a second's worth so short bursts go through
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    /// Bytes we may still take. Goes negative when a read takes more than we had, which later reads wait to pay off
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// How long until we have a byte to give, None if we have one now
+    fn wait_time(&self) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / self.rate as f64))
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Limits how fast we read from the wrapped reader to a rate in bytes per second, averaged over time. Each read can
+/// take up to a buffer's worth at once, the reads after it wait until that's paid off
+pub struct RateLimitedReader<R> {
+    inner: R,
+    /// None when we're not limited, so callers can wrap unconditionally
+    bucket: Option<TokenBucket>,
+    /// Waiting for the bucket to refill
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> RateLimitedReader<R> {
+    /// Limit inner to rate bytes per second, or pass it through untouched if rate is None
+    pub fn new(inner: R, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            bucket: rate.map(TokenBucket::new),
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RateLimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(bucket) = this.bucket.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+
+            bucket.refill();
+            match bucket.wait_time() {
+                Some(wait) => this.sleep = Some(Box::pin(sleep(wait))),
+                None => break,
+            }
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        bucket.take(buf.filled().len() - filled);
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,131 @@
+use log::info;
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+/// Most time between sweeps for idle connections, so short thresholds are noticed promptly
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks when each of a server's connections last received anything, so connections that stall can be reaped. Unlike
+/// QUIC's idle timeout this is about our data, a peer that keeps the connection alive but never sends still counts
+/// as idle
+#[derive(Debug, Clone, Default)]
+pub struct ActivityMap {
+    connections: Arc<Mutex<HashMap<u64, ConnectionActivity>>>,
+}
+
+#[derive(Debug)]
+struct ConnectionActivity {
+    last_active: Instant,
+    /// Stops the connection's task when it's reaped
+    cancel: CancellationToken,
+}
+
+impl ActivityMap {
+    /// Start tracking a connection, which is reaped by cancelling cancel
+    pub fn register(&self, id: u64, cancel: CancellationToken) {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                id,
+                ConnectionActivity {
+                    last_active: Instant::now(),
+                    cancel,
+                },
+            );
+    }
+
+    /// Note that a connection just did something
+    pub fn touch(&self, id: u64) {
+        if let Some(activity) = self
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&id)
+        {
+            activity.last_active = Instant::now();
+        }
+    }
+
+    /// Stop tracking a connection, once it's finished
+    pub fn remove(&self, id: u64) {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+    }
+
+    /// Cancel every connection that's been idle longer than max_idle, no longer tracking them
+    fn reap(&self, max_idle: Duration) {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|id, activity| {
+                let idle = activity.last_active.elapsed();
+                if idle <= max_idle {
+                    return true;
+                }
+
+                info!(
+                    "Connection {}: reaping after {}s without activity",
+                    id,
+                    idle.as_secs()
+                );
+                activity.cancel.cancel();
+                false
+            });
+    }
+}
+
+/// Periodically reap connections idle longer than max_idle, until aborted
+pub async fn reap_idle(activity: ActivityMap, max_idle: Duration) {
+    let mut sweep =
+        tokio::time::interval((max_idle / 4).clamp(Duration::from_millis(100), MAX_SWEEP_INTERVAL));
+
+    loop {
+        sweep.tick().await;
+        activity.reap(max_idle);
+    }
+}
+
+/// Marks a connection active in an ActivityMap whenever anything is read from the wrapped stream
+pub struct ActivityReader<R> {
+    inner: R,
+    activity: ActivityMap,
+    id: u64,
+}
+
+impl<R> ActivityReader<R> {
+    pub fn new(inner: R, activity: ActivityMap, id: u64) -> Self {
+        Self {
+            inner,
+            activity,
+            id,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ActivityReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if buf.filled().len() > filled {
+            self.activity.touch(self.id);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Process wide record of how much of each resumable transfer we've received, so a client whose connection dropped can
+/// ask where to carry on from
+pub static RESUMABLE_TRANSFERS: ResumableTransfers = ResumableTransfers::new();
+
+/// Bytes received per transfer ID, for transfers still in progress
+#[derive(Debug)]
+pub struct ResumableTransfers {
+    received: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl ResumableTransfers {
+    const fn new() -> Self {
+        Self {
+            received: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// How many bytes of transfer_id we've received, 0 for a transfer we haven't seen
+    pub fn received(&self, transfer_id: u64) -> u64 {
+        self.received
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&transfer_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn add(&self, transfer_id: u64, bytes: u64) {
+        *self
+            .received
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(transfer_id)
+            .or_default() += bytes;
+    }
+
+    /// Forget a transfer once it's complete, there's nothing left to resume
+    pub fn finish(&self, transfer_id: u64) {
+        self.received
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&transfer_id);
+    }
+}
+
+/// Counts everything read from the wrapped stream towards a transfer in RESUMABLE_TRANSFERS. We write each chunk we
+/// read before reading the next, so this is what the client can resume after
+pub struct ResumeCounter<R> {
+    inner: R,
+    transfer_id: u64,
+}
+
+impl<R> ResumeCounter<R> {
+    pub fn new(inner: R, transfer_id: u64) -> Self {
+        Self { inner, transfer_id }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ResumeCounter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        let read = buf.filled().len() - filled;
+        if read > 0 {
+            RESUMABLE_TRANSFERS.add(self.transfer_id, read as u64);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
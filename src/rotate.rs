@@ -0,0 +1,213 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{fs::File, io::AsyncWrite};
+
+/// When to move on to the next output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotateThreshold {
+    /// After this many bytes have been written to a file
+    Size(u64),
+    /// After a file has been open this long
+    Duration(Duration),
+}
+
+impl FromStr for RotateThreshold {
+    type Err = String;
+
+    /// Sizes are bytes with an optional K/M/G suffix (i.e. 100M), durations need an s/min/h suffix (i.e. 30s)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let split = lower
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(lower.len());
+        let (value, unit) = lower.split_at(split);
+
+        let value: u64 = value
+            .parse()
+            .map_err(|_| format!("Invalid rotation threshold: {}", s))?;
+        if value == 0 {
+            return Err("Rotation threshold must be greater than zero".to_owned());
+        }
+
+        let threshold = match unit {
+            "" | "b" => Self::Size(value),
+            "k" | "kb" => Self::Size(value * 1024),
+            "m" | "mb" => Self::Size(value * 1024 * 1024),
+            "g" | "gb" => Self::Size(value * 1024 * 1024 * 1024),
+            "s" => Self::Duration(Duration::from_secs(value)),
+            "min" => Self::Duration(Duration::from_secs(value * 60)),
+            "h" => Self::Duration(Duration::from_secs(value * 60 * 60)),
+            _ => return Err(format!("Unknown rotation threshold unit: {}", unit)),
+        };
+
+        Ok(threshold)
+    }
+}
+
+/// An output that writes to a series of numbered files (i.e. capture-0001.bin, capture-0002.bin, ...), moving on to
+/// the next file when the current one crosses our threshold
+#[derive(Debug)]
+pub struct RotatingFile {
+    base_path: PathBuf,
+    threshold: RotateThreshold,
+    index: u32,
+    file: File,
+    written: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFile {
+    pub fn create(base_path: &Path, threshold: RotateThreshold) -> io::Result<Self> {
+        let base_path = base_path.to_owned();
+        let index = 1;
+        let file = open_numbered(&base_path, index)?;
+
+        Ok(Self {
+            base_path,
+            threshold,
+            index,
+            file,
+            written: 0,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn needs_rotation(&self) -> bool {
+        match self.threshold {
+            RotateThreshold::Size(size) => self.written >= size,
+            RotateThreshold::Duration(duration) => self.opened_at.elapsed() >= duration,
+        }
+    }
+
+    /// How much of the next write fits in the current file
+    fn writable_len(&self, len: usize) -> usize {
+        match self.threshold {
+            RotateThreshold::Size(size) => {
+                len.min(usize::try_from(size - self.written).unwrap_or(usize::MAX))
+            }
+            RotateThreshold::Duration(_) => len,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Too many rotated output files"))?;
+        self.file = open_numbered(&self.base_path, self.index)?;
+        self.written = 0;
+        self.opened_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl AsyncWrite for RotatingFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.needs_rotation() {
+            // make sure everything has hit the current file before moving on
+            ready!(Pin::new(&mut self.file).poll_flush(cx))?;
+            self.rotate()?;
+        }
+
+        let len = self.writable_len(buf.len());
+        let written = ready!(Pin::new(&mut self.file).poll_write(cx, &buf[..len]))?;
+        self.written += written as u64;
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+/// Path of the index'th output file, i.e. capture.bin -> capture-0001.bin
+fn numbered_path(base_path: &Path, index: u32) -> PathBuf {
+    let stem = base_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let file_name = match base_path.extension() {
+        Some(extension) => format!("{}-{:04}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{:04}", stem, index),
+    };
+
+    base_path.with_file_name(file_name)
+}
+
+fn open_numbered(base_path: &Path, index: u32) -> io::Result<File> {
+    // opened synchronously as we rotate from within poll_write, creating a file is quick enough not to matter
+    let file = fs::File::create(numbered_path(base_path, index))?;
+    Ok(File::from_std(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn parses_thresholds() {
+        assert_eq!("100".parse(), Ok(RotateThreshold::Size(100)));
+        assert_eq!("2K".parse(), Ok(RotateThreshold::Size(2 * 1024)));
+        assert_eq!(
+            "30s".parse(),
+            Ok(RotateThreshold::Duration(Duration::from_secs(30)))
+        );
+        assert!("0".parse::<RotateThreshold>().is_err());
+        assert!("10parsecs".parse::<RotateThreshold>().is_err());
+    }
+
+    #[tokio::test]
+    async fn transfer_rotates_at_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("capture.bin");
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+
+        let input: Vec<u8> = (0..=255u8).cycle().take(1500).collect();
+        let mut output = Arc::new(Mutex::new(
+            RotatingFile::create(&base_path, RotateThreshold::Size(1000)).unwrap(),
+        ));
+        let received = Arc::clone(&output);
+        let send = async {
+            client.run(addr, &mut input.as_slice()).await.unwrap();
+            test_utils::wait_until(|| {
+                received
+                    .try_lock()
+                    .is_ok_and(|file| file.index == 2 && file.written == 500)
+            })
+            .await;
+            cancel.cancel();
+        };
+        let _ = tokio::join!(server.run(&mut output), send);
+
+        let first = fs::read(dir.path().join("capture-0001.bin")).unwrap();
+        let second = fs::read(dir.path().join("capture-0002.bin")).unwrap();
+        assert_eq!(first, input[..1000]);
+        assert_eq!(second, input[1000..]);
+        assert!(!dir.path().join("capture-0003.bin").exists());
+    }
+}
@@ -0,0 +1,91 @@
+use s2n_quic::stream::BidirectionalStream;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+
+/// The receiving half of a stream from [`crate::core::QcatStream`], for integrations that only ever receive. Only
+/// implements AsyncRead, so sending on it by mistake doesn't compile
+#[derive(Debug)]
+pub struct QcatReceiver(ReadHalf<BidirectionalStream>);
+
+/// The sending half of a stream from [`crate::core::QcatStream`], for integrations that only ever send. Only
+/// implements AsyncWrite, so reading from it by mistake doesn't compile
+#[derive(Debug)]
+pub struct QcatSender(WriteHalf<BidirectionalStream>);
+
+/// Split stream into its receiving and sending halves. Either can be dropped without closing the other, the stream
+/// stays open until both are gone
+pub fn split(stream: BidirectionalStream) -> (QcatReceiver, QcatSender) {
+    let (receiver, sender) = tokio::io::split(stream);
+    (QcatReceiver(receiver), QcatSender(sender))
+}
+
+impl AsyncRead for QcatReceiver {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QcatSender {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    /// Closes our side of the stream, the peer sees EOF
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{core::QcatOptions, test_utils};
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn receive_only_half_gets_what_the_send_only_half_sent() {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (mut server, addr) =
+            test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let mut incoming = server.incoming();
+
+        let receive = async {
+            let mut conn = std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_next(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let mut receiver = conn.accept_receiver().await.unwrap().unwrap();
+            let mut received = Vec::new();
+            receiver.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let send = async {
+            let mut conn = client.open(addr).await.unwrap();
+            let mut sender = conn.open_sender().await.unwrap();
+            sender.write_all(b"one way only").await.unwrap();
+            sender.shutdown().await.unwrap();
+            conn
+        };
+        let (received, _conn) = tokio::join!(receive, send);
+
+        assert_eq!(received, b"one way only");
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt::Display, io::IsTerminal, sync::OnceLock};
+
+/// Whether human facing stderr output (timings, hints) is colored, see init_color
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+/// Decide whether to color our human facing stderr output for the rest of the process. Only the first call takes
+/// effect, until then nothing is colored. Separate from logging, whose format is up to --log-format
+pub fn init_color(no_color: bool) {
+    let _ = COLOR.set(color_enabled(
+        no_color,
+        std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()),
+        std::io::stderr().is_terminal(),
+    ));
+}
+
+/// Whether to color output: only on a terminal, and never with --no-color or NO_COLOR set (see https://no-color.org)
+pub fn color_enabled(no_color: bool, no_color_env: bool, stderr_is_terminal: bool) -> bool {
+    !no_color && !no_color_env && stderr_is_terminal
+}
+
+/// The styles we use for human facing output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Dim,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Dim => "2",
+        }
+    }
+
+    /// text in this style if color is enabled, otherwise as is
+    pub fn paint(self, text: impl Display) -> String {
+        if COLOR.get().copied().unwrap_or_default() {
+            format!("\x1b[{}m{}\x1b[0m", self.code(), text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timings::TIMINGS;
+    use std::time::Duration;
+
+    #[test]
+    fn color_only_on_a_terminal_without_opting_out() {
+        assert!(color_enabled(false, false, true));
+        assert!(!color_enabled(true, false, true));
+        assert!(!color_enabled(false, true, true));
+        assert!(!color_enabled(false, false, false));
+    }
+
+    #[test]
+    fn output_without_color_has_no_escapes() {
+        // init_color is never called in tests, so color stays off as it would with --no-color or a non-TTY stderr
+        assert_eq!(Style::Bold.paint("phrase"), "phrase");
+        assert_eq!(Style::Dim.paint("hint"), "hint");
+
+        TIMINGS.record("styled phase", Duration::from_millis(5));
+        let summary = TIMINGS.summary();
+        assert!(summary.contains("styled phase"));
+        assert!(!summary.contains('\x1b'));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+/// Writes everything to both of its writers. A write completes once primary has taken the bytes, secondary is given
+/// the same bytes before the next write, flush or shutdown goes ahead. A failure on either side fails the write
+pub struct TeeWriter<A, B> {
+    primary: A,
+    secondary: B,
+    /// Bytes primary has taken that secondary hasn't yet, from pending_written onwards
+    pending: Vec<u8>,
+    pending_written: usize,
+}
+
+impl<A, B> TeeWriter<A, B> {
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self {
+            primary,
+            secondary,
+            pending: Vec::new(),
+            pending_written: 0,
+        }
+    }
+}
+
+impl<A: AsyncWrite + Unpin, B: AsyncWrite + Unpin> TeeWriter<A, B> {
+    /// Catch secondary up with primary
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_written < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.secondary)
+                    .poll_write(cx, &self.pending[self.pending_written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_written += written;
+        }
+
+        self.pending.clear();
+        self.pending_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<A: AsyncWrite + Unpin, B: AsyncWrite + Unpin> AsyncWrite for TeeWriter<A, B> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_write_pending(cx))?;
+
+        let written = ready!(Pin::new(&mut self.primary).poll_write(cx, buf))?;
+        self.pending.extend_from_slice(&buf[..written]);
+        // get secondary going now, whatever it doesn't take is finished off by our next call
+        if let Poll::Ready(Err(e)) = self.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        ready!(Pin::new(&mut self.primary).poll_flush(cx))?;
+        Pin::new(&mut self.secondary).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        ready!(Pin::new(&mut self.primary).poll_shutdown(cx))?;
+        Pin::new(&mut self.secondary).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,218 @@
+//! Helpers shared by our tests: cheap crypto material, loopback servers and clients, and capturing what we log
+
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter, Log, Metadata, Record,
+};
+use std::{
+    cell::RefCell,
+    error::Error,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Once},
+    time::Duration,
+};
+use tokio::{io::AsyncRead, sync::Mutex, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    core::{CoreError, QcatClient, QcatOptions, QcatServer},
+    crypto::{CryptoMaterial, KdfParams, QcatCryptoConfig, SaltedPassphrase},
+};
+
+/// Passphrase tests use unless they need another, with a salt as long as argon2 recommends
+pub const TEST_PASSPHRASE: &str = "qcattestsaltsalt-correct-horse-battery";
+
+/// Cheapest parameters argon2 allows, so tests don't spend their time deriving keys
+pub const TEST_KDF: KdfParams = KdfParams {
+    memory_kib: 8,
+    iterations: 1,
+};
+
+/// How long wait_until waits before failing the test
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Crypto material derived from passphrase ("salt-passphrase") with TEST_KDF
+pub fn material(passphrase: &str) -> CryptoMaterial {
+    let passphrase = SaltedPassphrase::from_str(passphrase).expect("parse test passphrase");
+    CryptoMaterial::generate_from_passphrase_with_kdf(passphrase, TEST_KDF)
+        .expect("derive test crypto material")
+}
+
+/// A UDP socket bound to a free loopback port, ready to hand to QcatServer::with_sockets
+pub fn loopback_socket() -> (UdpSocket, SocketAddr) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind loopback socket");
+    socket
+        .set_nonblocking(true)
+        .expect("make loopback socket nonblocking");
+    let addr = socket.local_addr().expect("get loopback socket address");
+    (socket, addr)
+}
+
+/// A server listening on a free loopback port, and the address it's listening on
+pub fn server(config: QcatCryptoConfig, options: QcatOptions) -> (QcatServer, SocketAddr) {
+    let (socket, addr) = loopback_socket();
+    let server =
+        QcatServer::with_sockets(vec![socket], config, options).expect("start test server");
+    (server, addr)
+}
+
+pub fn client(config: QcatCryptoConfig, options: QcatOptions) -> QcatClient {
+    QcatClient::with_options(config, options).expect("start test client")
+}
+
+/// Wait for condition to hold, checking every few milliseconds. Panics if it doesn't within WAIT_TIMEOUT
+pub async fn wait_until(mut condition: impl FnMut() -> bool) {
+    let give_up = Instant::now() + WAIT_TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < give_up, "Timed out waiting for condition");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Run server in receive mode while work runs, then stop it. work is handed the server's output, which is returned
+/// along with what work returns
+pub async fn with_receiving_server<F, Fut, T>(server: QcatServer, work: F) -> (Vec<u8>, T)
+where
+    F: FnOnce(Arc<Mutex<Vec<u8>>>) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let cancel = CancellationToken::new();
+    let mut server = server.with_cancellation_token(cancel.clone());
+
+    let mut output = Arc::new(Mutex::new(Vec::new()));
+    let work = work(Arc::clone(&output));
+    let work = async {
+        let result = work.await;
+        cancel.cancel();
+        result
+    };
+    let (_, result) = tokio::join!(server.run(&mut output), work);
+
+    let output = output.lock().await.clone();
+    (output, result)
+}
+
+/// Check a server run stopped because its token was cancelled, which is how tests shut servers down
+pub fn assert_cancelled(result: Result<(), Box<dyn Error>>) {
+    match result {
+        Err(e) if matches!(e.downcast_ref(), Some(CoreError::Cancelled)) => {}
+        other => panic!("Expected the server to be cancelled, got {:?}", other),
+    }
+}
+
+/// Send input from client to server (in receive mode), returning what the server wrote to its output once it has at
+/// least expected bytes. Fails the test if the client does
+pub async fn transfer<R: AsyncRead + Unpin + ?Sized>(
+    server: QcatServer,
+    addr: SocketAddr,
+    mut client: QcatClient,
+    input: &mut R,
+    expected: usize,
+) -> Vec<u8> {
+    let (output, ()) = with_receiving_server(server, |received| async move {
+        client.run(addr, input).await.expect("client transfer");
+        wait_until(|| received.try_lock().is_ok_and(|r| r.len() >= expected)).await;
+    })
+    .await;
+    output
+}
+
+/// transfer between a server and client holding TEST_PASSPHRASE, each with the given options
+pub async fn loopback_transfer<R: AsyncRead + Unpin + ?Sized>(
+    server_options: QcatOptions,
+    client_options: QcatOptions,
+    input: &mut R,
+    expected: usize,
+) -> Vec<u8> {
+    let material = material(TEST_PASSPHRASE);
+    let (server, addr) = server(material.crypto_config(), server_options);
+    let client = client(material.crypto_config(), client_options);
+    transfer(server, addr, client, input, expected).await
+}
+
+/// A record we captured, see capture_logs
+#[derive(Debug, Clone)]
+pub struct CapturedRecord {
+    pub message: String,
+    /// The record's structured key-values, as strings
+    pub fields: Vec<(String, String)>,
+}
+
+impl CapturedRecord {
+    /// The value of the structured field key, if the record has it
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Collects a record's key-values into CapturedRecord::fields
+struct FieldCollector(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// Records logged on this thread while a LogCapture is alive
+    static CAPTURED: RefCell<Option<Vec<CapturedRecord>>> = const { RefCell::new(None) };
+}
+
+/// Global logger handing records to whichever LogCapture is alive on the thread logging them
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED.with(|captured| {
+            if let Some(captured) = captured.borrow_mut().as_mut() {
+                let mut fields = FieldCollector(Vec::new());
+                let _ = record.key_values().visit(&mut fields);
+                captured.push(CapturedRecord {
+                    message: record.args().to_string(),
+                    fields: fields.0,
+                });
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURING_LOGGER: CapturingLogger = CapturingLogger;
+static INSTALL_LOGGER: Once = Once::new();
+
+/// Captures what's logged on this thread until dropped. A #[tokio::test] runs the tasks it spawns on its own thread,
+/// so this sees our servers' and clients' logs without seeing other tests'
+pub struct LogCapture(());
+
+pub fn capture_logs() -> LogCapture {
+    INSTALL_LOGGER.call_once(|| {
+        log::set_logger(&CAPTURING_LOGGER).expect("install capturing logger");
+        log::set_max_level(LevelFilter::Trace);
+    });
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    LogCapture(())
+}
+
+impl LogCapture {
+    pub fn records(&self) -> Vec<CapturedRecord> {
+        CAPTURED.with(|captured| captured.borrow().clone().unwrap_or_default())
+    }
+}
+
+impl Drop for LogCapture {
+    fn drop(&mut self) {
+        CAPTURED.with(|captured| *captured.borrow_mut() = None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    fmt::Write,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::style::Style;
+
+/// Process wide record of how long each phase of setting up a session took, summarized by --timings
+pub static TIMINGS: Timings = Timings::new();
+
+#[derive(Debug)]
+pub struct Timings {
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl Timings {
+    const fn new() -> Self {
+        Self {
+            phases: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record that phase took duration. Phases can be recorded more than once, i.e. deriving a key per passphrase
+    pub fn record(&self, phase: &'static str, duration: Duration) {
+        self.phases
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((phase, duration));
+    }
+
+    /// Run f, recording how long it took as phase
+    pub fn time<T>(&self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    /// Every phase recorded so far, in order, one per line
+    pub fn summary(&self) -> String {
+        let phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        let width = phases
+            .iter()
+            .map(|(phase, _)| phase.len())
+            .max()
+            .unwrap_or_default();
+
+        let mut summary = String::new();
+        for (phase, duration) in phases.iter() {
+            let _ = writeln!(
+                summary,
+                "{}  {:>10.3}ms",
+                Style::Bold.paint(format_args!("{:width$}", phase, width = width)),
+                duration.as_secs_f64() * 1000.0,
+            );
+        }
+        summary
+    }
+}
+
+/// Records the time until the first byte is read from the wrapped reader as phase
+pub struct FirstByteReader<R> {
+    inner: R,
+    phase: &'static str,
+    /// When we started waiting, None once the first byte has arrived
+    start: Option<Instant>,
+}
+
+impl<R> FirstByteReader<R> {
+    pub fn new(inner: R, phase: &'static str) -> Self {
+        Self::since(inner, phase, Some(Instant::now()))
+    }
+
+    /// Records the time from start rather than from now, i.e. from when a connection was accepted. Nothing is
+    /// recorded without a start, for the streams after the first
+    pub fn since(inner: R, phase: &'static str, start: Option<Instant>) -> Self {
+        Self {
+            inner,
+            phase,
+            start,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FirstByteReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if buf.filled().len() > filled {
+            if let Some(start) = self.start.take() {
+                TIMINGS.record(self.phase, start.elapsed());
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Records the time from start until the peer acknowledges the first bytes written to the wrapped stream as phase.
+/// Flushing a QUIC send stream waits for the peer's acknowledgement, so once something is written we poll a flush
+/// alongside each write without waiting on it
+pub struct FirstAckWriter<W> {
+    inner: W,
+    phase: &'static str,
+    start: Option<Instant>,
+    /// Whether anything has been written yet, the flush only means something after
+    written: bool,
+}
+
+impl<W> FirstAckWriter<W> {
+    pub fn new(inner: W, phase: &'static str, start: Instant) -> Self {
+        Self {
+            inner,
+            phase,
+            start: Some(start),
+            written: false,
+        }
+    }
+
+    fn acknowledged(&mut self) {
+        if let Some(start) = self.start.take().filter(|_| self.written) {
+            TIMINGS.record(self.phase, start.elapsed());
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> FirstAckWriter<W> {
+    /// Check for the acknowledgement without waiting on it. Errors are left for the next write to report
+    fn poll_acknowledged(&mut self, cx: &mut Context<'_>) {
+        if self.written
+            && self.start.is_some()
+            && matches!(
+                Pin::new(&mut self.inner).poll_flush(cx),
+                Poll::Ready(Ok(()))
+            )
+        {
+            self.acknowledged();
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FirstAckWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_acknowledged(cx);
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+
+        if written > 0 && !self.written {
+            self.written = true;
+            self.poll_acknowledged(cx);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        self.acknowledged();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        self.acknowledged();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn summary_includes_key_derivation_time() {
+        test_utils::material(test_utils::TEST_PASSPHRASE);
+
+        let summary = TIMINGS.summary();
+        let key_derivations: Vec<f64> = summary
+            .lines()
+            .filter(|line| line.contains("key derivation"))
+            .map(|line| {
+                let duration = line.split_whitespace().last().unwrap();
+                duration.trim_end_matches("ms").parse().unwrap()
+            })
+            .collect();
+
+        assert!(!key_derivations.is_empty(), "{}", summary);
+        assert!(key_derivations.iter().all(|&ms| ms > 0.0), "{}", summary);
+    }
+}
@@ -0,0 +1,191 @@
+use log::warn;
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Which way a chunk of data went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn marker(self) -> char {
+        match self {
+            Self::Sent => '>',
+            Self::Received => '<',
+        }
+    }
+}
+
+/// A record of every chunk of data sent and received, one line per chunk with a timestamp and direction marker, i.e.
+/// `1760572800.123456 > hello\n`. Non-printable bytes are escaped so the transcript stays text
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    writer: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Transcript {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: Arc::new(Mutex::new(LineWriter::new(File::create(path)?))),
+        })
+    }
+
+    /// Append a chunk to the transcript. Failing to write it is logged rather than interrupting the transfer
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:06} {} {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            direction.marker(),
+            data.escape_ascii()
+        );
+
+        // a poisoned lock only means another record panicked, the transcript itself is still usable
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            warn!("Failed to write to transcript: {}", e);
+        }
+    }
+
+    /// Wrap a reader, recording everything read from it
+    pub fn tap_reader<R>(&self, inner: R, direction: Direction) -> TranscriptReader<R> {
+        TranscriptReader {
+            inner,
+            transcript: self.clone(),
+            direction,
+        }
+    }
+
+    /// Wrap a writer, recording everything written to it
+    pub fn tap_writer<W>(&self, inner: W, direction: Direction) -> TranscriptWriter<W> {
+        TranscriptWriter {
+            inner,
+            transcript: self.clone(),
+            direction,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TranscriptReader<R> {
+    inner: R,
+    transcript: Transcript,
+    direction: Direction,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TranscriptReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let already_filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.transcript
+            .record(self.direction, &buf.filled()[already_filled..]);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Debug)]
+pub struct TranscriptWriter<W> {
+    inner: W,
+    transcript: Transcript,
+    direction: Direction,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TranscriptWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.transcript.record(self.direction, &buf[..written]);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use tokio_util::sync::CancellationToken;
+
+    /// What the transcript recorded in direction, the escaped chunks joined back together
+    fn recorded(transcript: &str, direction: Direction) -> String {
+        transcript
+            .lines()
+            .filter_map(|line| {
+                let (timestamp, rest) = line.split_once(' ')?;
+                assert!(timestamp.parse::<f64>().is_ok(), "bad timestamp: {}", line);
+                let (marker, data) = rest.split_once(' ')?;
+                (marker.starts_with(direction.marker())).then_some(data)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn exchange_is_recorded_in_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transcript.txt");
+        let transcript = Transcript::create(&path).unwrap();
+
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+        let mut client = test_utils::client(
+            material.crypto_config(),
+            QcatOptions {
+                keep_receiving: true,
+                ..Default::default()
+            },
+        );
+
+        let mut input = transcript.tap_reader(&b"hello\x00\xff"[..], Direction::Sent);
+        let mut output = transcript.tap_writer(Vec::new(), Direction::Received);
+        let exchange = async {
+            let result = client.run_duplex(addr, &mut input, &mut output).await;
+            cancel.cancel();
+            result
+        };
+        let (_, result) = tokio::join!(server.run_echo(), exchange);
+        result.unwrap();
+        drop(transcript);
+        drop(input);
+        assert_eq!(output.inner, b"hello\x00\xff");
+        drop(output);
+
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(recorded(&transcript, Direction::Sent), r"hello\x00\xff");
+        assert_eq!(recorded(&transcript, Direction::Received), r"hello\x00\xff");
+    }
+}
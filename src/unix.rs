@@ -0,0 +1,103 @@
+use std::io;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Prefix used to specify a socket in the Linux abstract namespace, i.e. "@myservice"
+const ABSTRACT_SOCKET_PREFIX: char = '@';
+
+/// Where a UNIX socket lives, either on the filesystem or in the Linux abstract namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixSocketAddr {
+    Path(String),
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+}
+
+impl UnixSocketAddr {
+    /// Parse a socket spec from the command line. A leading '@' selects the abstract namespace (Linux only)
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        match spec.strip_prefix(ABSTRACT_SOCKET_PREFIX) {
+            #[cfg(target_os = "linux")]
+            Some(name) => Ok(Self::Abstract(name.to_owned())),
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract UNIX sockets are only supported on Linux",
+            )),
+            None => Ok(Self::Path(spec.to_owned())),
+        }
+    }
+
+    /// Connect to the socket
+    pub async fn connect(&self) -> io::Result<UnixStream> {
+        match self {
+            Self::Path(path) => UnixStream::connect(path).await,
+            #[cfg(target_os = "linux")]
+            Self::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+                stream.set_nonblocking(true)?;
+                UnixStream::from_std(stream)
+            }
+        }
+    }
+
+    /// Bind a listener on the socket
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        match self {
+            Self::Path(path) => UnixListener::bind(path),
+            #[cfg(target_os = "linux")]
+            Self::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+                listener.set_nonblocking(true)?;
+                UnixListener::from_std(listener)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn parses_abstract_and_path_sockets() {
+        assert_eq!(
+            UnixSocketAddr::parse("@qcat").unwrap(),
+            UnixSocketAddr::Abstract("qcat".to_owned())
+        );
+        assert_eq!(
+            UnixSocketAddr::parse("/run/qcat.sock").unwrap(),
+            UnixSocketAddr::Path("/run/qcat.sock".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_from_abstract_socket() {
+        let payload = b"from the abstract namespace";
+        let socket = UnixSocketAddr::parse(&format!("@qcat-test-{}", std::process::id())).unwrap();
+        let listener = socket.bind().unwrap();
+        let service = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(payload).await.unwrap();
+        });
+
+        let mut input = socket.connect().await.unwrap();
+        service.await.unwrap();
+
+        let received = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            QcatOptions::default(),
+            &mut input,
+            payload.len(),
+        )
+        .await;
+        assert_eq!(received, payload);
+    }
+}
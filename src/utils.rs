@@ -1,8 +1,650 @@
-use crate::crypto::SaltedPassphrase;
-use std::{self, str::FromStr};
+use crate::{
+    args::Args,
+    crypto::{estimate_entropy_bits, SaltedPassphrase},
+    style::Style,
+};
+use log::{debug, info, warn};
+use std::{
+    self,
+    error::Error,
+    future::Future,
+    io::{BufRead, IsTerminal},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt},
+    net::lookup_host,
+    sync::oneshot,
+};
 
-/// Receive a passphrase input by the user. Intended for use by the client with the generated server passphrase
-pub fn receive_passphrase_input() -> Result<SaltedPassphrase, Box<dyn std::error::Error>> {
-    let received_passphrase = rpassword::prompt_password("Enter password from server: ")?;
-    Ok(SaltedPassphrase::from_str(received_passphrase.trim())?)
+/// Env var systemd sets to the directory holding credentials passed with LoadCredential= and friends
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Env vars systemd sets when passing sockets to a socket activated service, the PID they're for and how many
+#[cfg(unix)]
+const LISTEN_PID_ENV: &str = "LISTEN_PID";
+#[cfg(unix)]
+const LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+
+/// First fd systemd passes sockets from, after stdin, stdout and stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Name of the systemd credential holding our passphrase
+const PASSPHRASE_CREDENTIAL_NAME: &str = "qcat-passphrase";
+
+/// Flag passphrases are given on the command line with
+const PASSPHRASE_FLAG: &str = "--passphrase";
+
+/// Shown when we're about to send stdin and it's an interactive terminal, so waiting on the user doesn't look like a hang
+const TERMINAL_INPUT_HINT: &str = "Reading from terminal; type data and press Ctrl-D to send";
+
+/// Receive a passphrase input by the user. Intended for use by the client with the generated server passphrase. The
+/// systemd credential is used instead if present. With a salt given out-of-band, only the passphrase is expected. If
+/// a timeout is given we give up on the prompt after it, rather than hanging a script that forgot to give one
+pub async fn receive_passphrase_input(
+    salt: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<SaltedPassphrase, Box<dyn std::error::Error>> {
+    if let Some(passphrase) = read_credential_passphrase(salt)? {
+        return Ok(passphrase);
+    }
+
+    // the prompt blocks on the terminal, so it gets its own thread. Not spawn_blocking, as the runtime would wait on
+    // a prompt we've timed out on when shutting down
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(rpassword::prompt_password("Enter password from server: "));
+    });
+
+    let prompt = async { receiver.await.map_err(std::io::Error::other)? };
+    let received_passphrase = await_prompt(prompt, timeout).await?;
+    Ok(SaltedPassphrase::parse_with_salt(
+        received_passphrase.trim(),
+        salt,
+    )?)
+}
+
+/// Wait for what's typed at prompt, giving up with a TimedOut error after timeout if there is one
+async fn await_prompt(
+    prompt: impl Future<Output = std::io::Result<String>>,
+    timeout: Option<Duration>,
+) -> std::io::Result<String> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, prompt).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "Timed out after {}s waiting for the passphrase",
+                    timeout.as_secs()
+                ),
+            )
+        })?,
+        None => prompt.await,
+    }
+}
+
+/// Read our passphrase from the systemd credentials directory, i.e. when run with LoadCredential=qcat-passphrase:...
+pub fn read_credential_passphrase(
+    salt: Option<&str>,
+) -> Result<Option<SaltedPassphrase>, Box<dyn std::error::Error>> {
+    let Some(credentials_directory) = std::env::var_os(CREDENTIALS_DIRECTORY_ENV) else {
+        return Ok(None);
+    };
+
+    read_passphrase_credential(Path::new(&credentials_directory), salt)
+}
+
+/// Read our passphrase credential from credentials_directory, if it's there
+fn read_passphrase_credential(
+    credentials_directory: &Path,
+    salt: Option<&str>,
+) -> Result<Option<SaltedPassphrase>, Box<dyn std::error::Error>> {
+    let path = credentials_directory.join(PASSPHRASE_CREDENTIAL_NAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    debug!(
+        "Reading passphrase from systemd credential {}",
+        path.display()
+    );
+    let contents = std::fs::read_to_string(path)?;
+    Ok(Some(SaltedPassphrase::parse_with_salt(
+        contents.trim(),
+        salt,
+    )?))
+}
+
+/// The hint to show before sending stdin, if stdin is an interactive terminal rather than piped
+pub fn terminal_input_hint(stdin_is_terminal: bool) -> Option<&'static str> {
+    stdin_is_terminal.then_some(TERMINAL_INPUT_HINT)
+}
+
+/// Let the user know we're waiting on them if we're about to send an interactive stdin
+pub fn print_terminal_input_hint() {
+    if let Some(hint) = terminal_input_hint(std::io::stdin().is_terminal()) {
+        eprintln!("{}", Style::Dim.paint(hint));
+    }
+}
+
+/// The command a client runs to connect to us at host and port, followed by a line telling them the passphrase to
+/// enter at its prompt. With a salt given out-of-band the client needs it too, but it's left for them to fill in
+pub fn peer_command(host: &str, port: u16, passphrase: &str, salt_out_of_band: bool) -> String {
+    let salt = if salt_out_of_band { " --salt SALT" } else { "" };
+    format!(
+        "Run on the other side: qcat{} {} {}\nand enter the passphrase: {}",
+        salt, host, port, passphrase
+    )
+}
+
+/// Show the peer's fingerprint phrase and ask the user whether it matches what the other side sees, reading their
+/// answer from stdin. Anything but yes is a no
+pub fn confirm_peer_on_terminal(phrase: &str) -> bool {
+    confirm_peer(phrase, &mut std::io::stdin().lock())
+}
+
+/// confirm_peer_on_terminal, reading the answer from answers
+pub fn confirm_peer(phrase: &str, answers: &mut impl BufRead) -> bool {
+    eprint!(
+        "Peer's fingerprint: {}. Does the other side see the same? [y/N] ",
+        Style::Bold.paint(phrase)
+    );
+
+    let mut answer = String::new();
+    if answers.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Let the user know the passphrase we generated, as announced by generated: on stderr with --print-passphrase,
+/// otherwise in our logs if it's allowed there (see Args::log_passphrase)
+pub fn announce_generated_passphrase(args: &Args, generated: &str) {
+    if args.print_passphrase {
+        eprintln!("{}", generated);
+    } else if args.log_passphrase() {
+        info!("{}", generated);
+    } else if !args.uri && !args.print_peer_command {
+        warn!("Not logging the generated passphrase, get it with --print-passphrase, --uri or --print-peer-command");
+    }
+}
+
+/// Warn that passphrases given on the command line can be read by other users, i.e. with ps, and point at the safer
+/// ways of providing one. Does nothing if passphrases (from --passphrase) is empty
+pub fn warn_passphrase_in_argv(passphrases: &[String]) {
+    if passphrases.is_empty() {
+        return;
+    }
+    warn!(
+        "Passphrases given with {} are visible to other users on this host (i.e. in ps) and may end up in shell \
+        history. Prefer entering it at the prompt, --qr-file or a systemd credential ({})",
+        PASSPHRASE_FLAG, PASSPHRASE_CREDENTIAL_NAME
+    );
+}
+
+/// Overwrite the passphrases in our own argv with '*', so they no longer show up in /proc/self/cmdline (and so ps).
+/// Anything that read our command line before this (or /proc/<pid>/cmdline while we start up) has still seen them
+#[cfg(target_os = "linux")]
+pub fn scrub_passphrases_from_argv(passphrases: &[String]) -> std::io::Result<()> {
+    let malformed_stat = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Unable to find our argv in /proc/self/stat",
+        )
+    };
+
+    // fields 48 and 49 are where our argv strings start and end. Skip past the command name first as it may contain
+    // spaces, the field after it is field 3
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')').ok_or_else(malformed_stat)? + 2..)
+        .ok_or_else(malformed_stat)?
+        .split(' ')
+        .collect();
+    let field = |n: usize| {
+        fields
+            .get(n - 3)
+            .and_then(|field| field.parse::<usize>().ok())
+            .ok_or_else(malformed_stat)
+    };
+    let (arg_start, arg_end) = (field(48)?, field(49)?);
+    if arg_start == 0 || arg_end <= arg_start {
+        return Err(malformed_stat());
+    }
+
+    // SAFETY: the kernel says this is our argv area, which stays mapped and writable for the life of the process.
+    // std::env::args copies strings out of it rather than holding references, and clap has already done so
+    let argv = unsafe { std::slice::from_raw_parts_mut(arg_start as *mut u8, arg_end - arg_start) };
+
+    let flag_prefix = format!("{}=", PASSPHRASE_FLAG);
+    for arg in argv.split_mut(|byte| *byte == 0) {
+        let value = arg.strip_prefix(flag_prefix.as_bytes()).unwrap_or(arg);
+        if passphrases
+            .iter()
+            .any(|passphrase| passphrase.as_bytes() == value)
+        {
+            let value_start = arg.len() - value.len();
+            arg[value_start..].fill(b'*');
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether systemd_listen_sockets has taken ownership of the passed fds already
+#[cfg(unix)]
+static LISTEN_FDS_TAKEN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// UDP sockets systemd passed us for socket activation (LISTEN_FDS/LISTEN_PID, see sd_listen_fds(3)), empty if we
+/// weren't socket activated or they've been taken already. The env vars are left alone, as changing the environment
+/// isn't safe once other threads are running. Anything we spawn has another PID so won't think they're its own
+#[cfg(unix)]
+pub fn systemd_listen_sockets() -> std::io::Result<Vec<std::net::UdpSocket>> {
+    let listen_pid = std::env::var(LISTEN_PID_ENV).ok();
+    let listen_fds = std::env::var(LISTEN_FDS_ENV).ok();
+    if listen_fds.is_none() || LISTEN_FDS_TAKEN.swap(true, std::sync::atomic::Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    listen_sockets(
+        listen_pid.as_deref(),
+        listen_fds.as_deref(),
+        LISTEN_FDS_START,
+    )
+}
+
+/// Take ownership of the listen_fds sockets from first_fd on, given the values of LISTEN_PID and LISTEN_FDS. Empty
+/// unless listen_pid is ours
+#[cfg(unix)]
+fn listen_sockets(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    first_fd: i32,
+) -> std::io::Result<Vec<std::net::UdpSocket>> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = listen_pid.is_some_and(|pid| {
+        pid.parse::<u32>()
+            .is_ok_and(|pid| pid == std::process::id())
+    });
+    let count = listen_fds.and_then(|count| count.parse::<i32>().ok());
+
+    let Some(count) = count.filter(|_| for_us) else {
+        return Ok(Vec::new());
+    };
+
+    (first_fd..first_fd + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these fds to us alone, and we take ownership of each exactly once
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            if socket.r#type()? != socket2::Type::DGRAM {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Socket activation passed fd {} which isn't a UDP socket",
+                        fd
+                    ),
+                ));
+            }
+            socket.set_nonblocking(true)?;
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+/// Resolve host to its addresses. With numeric (-n) host must be an IP address, we never look it up with DNS
+pub async fn resolve(
+    host: &str,
+    port: u16,
+    numeric: bool,
+) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    if numeric {
+        let ip = IpAddr::from_str(host)
+            .map_err(|_| format!("{} is not a numeric IP address (-n)", host))?;
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    Ok(lookup_host((host, port)).await?.collect())
+}
+
+/// Report how a salted passphrase splits and roughly how strong it is, failing if it doesn't parse
+pub fn check_passphrase(passphrase: &str) -> Result<(), Box<dyn Error>> {
+    let passphrase = SaltedPassphrase::from_str(passphrase)?;
+
+    println!("Salt: {}", passphrase.salt());
+    println!("Passphrase: {}", passphrase.passphrase());
+    println!(
+        "Estimated entropy: ~{:.1} bits (excluding the salt)",
+        estimate_entropy_bits(passphrase.passphrase())
+    );
+    if passphrase.salt_is_short() {
+        warn!(
+            "Salt is shorter than the {} bytes Argon2 recommends, the derived key is weaker for it",
+            argon2::RECOMMENDED_SALT_LEN
+        );
+    }
+
+    Ok(())
+}
+
+/// Chain files together in order into a single reader. Every file is opened up front so a missing one fails before
+/// we connect
+pub async fn concat_files(
+    paths: &[PathBuf],
+) -> Result<Box<dyn AsyncRead + Unpin + Send>, Box<dyn Error>> {
+    let mut input: Box<dyn AsyncRead + Unpin + Send> = Box::new(tokio::io::empty());
+
+    for path in paths {
+        let file = File::open(path)
+            .await
+            .map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+        input = Box::new(input.chain(file));
+    }
+
+    Ok(input)
+}
+
+/// Open the file to write what we receive to, appending to anything already there rather than truncating it if asked
+pub async fn open_output_file(path: &Path, append: bool) -> std::io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::QcatOptions, test_utils};
+    use clap::Parser;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn reads_passphrase_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_passphrase_credential(dir.path(), None)
+            .unwrap()
+            .is_none());
+
+        std::fs::write(
+            dir.path().join(PASSPHRASE_CREDENTIAL_NAME),
+            "qcattestsaltsalt-correct-horse-battery\n",
+        )
+        .unwrap();
+        let passphrase = read_passphrase_credential(dir.path(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(passphrase.salt(), "qcattestsaltsalt");
+        assert_eq!(passphrase.passphrase(), "correct-horse-battery");
+
+        // with the salt given out-of-band, the whole credential is the passphrase
+        let passphrase = read_passphrase_credential(dir.path(), Some("outofbandsalt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(passphrase.salt(), "outofbandsalt");
+        assert_eq!(
+            passphrase.passphrase(),
+            "qcattestsaltsalt-correct-horse-battery"
+        );
+    }
+
+    #[tokio::test]
+    async fn concatenated_files_arrive_back_to_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("a");
+        let second = dir.path().join("b");
+        std::fs::write(&first, b"first file\n").unwrap();
+        std::fs::write(&second, b"second file\n").unwrap();
+
+        let mut input = concat_files(&[first, second]).await.unwrap();
+        let output = test_utils::loopback_transfer(
+            QcatOptions::default(),
+            QcatOptions::default(),
+            &mut input,
+            23,
+        )
+        .await;
+
+        assert_eq!(output, b"first file\nsecond file\n");
+    }
+
+    #[tokio::test]
+    async fn concat_fails_on_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(concat_files(&[dir.path().join("missing")]).await.is_err());
+    }
+
+    #[test]
+    fn terminal_hint_only_for_interactive_stdin() {
+        assert_eq!(terminal_input_hint(true), Some(TERMINAL_INPUT_HINT));
+        assert_eq!(terminal_input_hint(false), None);
+    }
+
+    /// Receive message into the file at path, opening it the way we would for --output
+    async fn receive_into_file(path: &Path, append: bool, message: &[u8], expected_len: u64) {
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let (server, addr) = test_utils::server(material.crypto_config(), QcatOptions::default());
+        let mut client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let cancel = CancellationToken::new();
+        let mut server = server.with_cancellation_token(cancel.clone());
+
+        let file = open_output_file(path, append).await.unwrap();
+        let mut output = Arc::new(Mutex::new(file));
+        let send = async {
+            client.run(addr, &mut &message[..]).await.unwrap();
+            test_utils::wait_until(|| {
+                std::fs::metadata(path).is_ok_and(|m| m.len() >= expected_len)
+            })
+            .await;
+            cancel.cancel();
+        };
+        let (result, ()) = tokio::join!(server.run(&mut output), send);
+        test_utils::assert_cancelled(result);
+    }
+
+    #[tokio::test]
+    async fn appended_connections_arrive_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("received");
+        std::fs::write(&path, b"stale\n").unwrap();
+
+        receive_into_file(&path, false, b"first connection\n", 17).await;
+        receive_into_file(&path, true, b"second connection\n", 35).await;
+
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"first connection\nsecond connection\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn prompt_gives_up_after_timeout() {
+        let err = await_prompt(std::future::pending(), Some(Duration::from_millis(50)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn prompt_answered_in_time_is_returned() {
+        let typed = async { Ok("salt-some-words".to_owned()) };
+        assert_eq!(
+            await_prompt(typed, Some(Duration::from_secs(10)))
+                .await
+                .unwrap(),
+            "salt-some-words"
+        );
+        let typed = async { Ok("salt-some-words".to_owned()) };
+        assert_eq!(await_prompt(typed, None).await.unwrap(), "salt-some-words");
+    }
+
+    #[test]
+    fn passphrase_flag_is_warned_about() {
+        let warned = |argv: &[&str]| {
+            let logs = test_utils::capture_logs();
+            warn_passphrase_in_argv(&Args::try_parse_from(argv).unwrap().passphrases);
+            logs.records()
+                .iter()
+                .any(|record| record.message.contains("are visible to other users"))
+        };
+
+        assert!(warned(&[
+            "qcat",
+            "--passphrase",
+            test_utils::TEST_PASSPHRASE,
+            "localhost",
+            "4433"
+        ]));
+        assert!(!warned(&["qcat", "localhost", "4433"]));
+    }
+
+    #[tokio::test]
+    async fn numeric_hosts_are_never_looked_up() {
+        assert_eq!(
+            resolve("127.0.0.1", 4433, true).await.unwrap(),
+            [SocketAddr::from(([127, 0, 0, 1], 4433))]
+        );
+        assert_eq!(
+            resolve("::1", 4433, true).await.unwrap(),
+            [SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 4433))]
+        );
+
+        let err = resolve("localhost", 4433, true).await.unwrap_err();
+        assert!(err.to_string().contains("not a numeric IP address"));
+    }
+
+    #[test]
+    fn checks_passphrases() {
+        let short_salt_warnings = |logs: &test_utils::LogCapture| {
+            logs.records()
+                .iter()
+                .filter(|record| record.message.starts_with("Salt is shorter than"))
+                .count()
+        };
+
+        let logs = test_utils::capture_logs();
+        check_passphrase(test_utils::TEST_PASSPHRASE).unwrap();
+        assert_eq!(short_salt_warnings(&logs), 0);
+
+        assert!(check_passphrase("nodelimiterhere").is_err());
+
+        check_passphrase("salt-correct-horse-battery").unwrap();
+        assert_eq!(short_salt_warnings(&logs), 1);
+    }
+
+    #[test]
+    fn peer_command_has_advertised_host_port_and_passphrase() {
+        let args = Args::try_parse_from([
+            "qcat",
+            "--listen",
+            "--print-peer-command",
+            "--advertise-host",
+            "qcat.example.com",
+            "0.0.0.0",
+            "4433",
+        ])
+        .unwrap();
+        let host = args.advertise_host.as_deref().unwrap();
+
+        let command = peer_command(host, 4433, "correct-horse-battery", false);
+        assert!(
+            command.contains("qcat qcat.example.com 4433"),
+            "{}",
+            command
+        );
+        assert!(command.contains("correct-horse-battery"), "{}", command);
+        assert!(!command.contains("0.0.0.0"), "{}", command);
+
+        let command = peer_command(host, 4433, "correct-horse-battery", true);
+        assert!(
+            command.contains("qcat --salt SALT qcat.example.com 4433"),
+            "{}",
+            command
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn server_uses_socket_passed_for_activation() {
+        use std::os::fd::IntoRawFd;
+
+        let bound = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = bound.local_addr().unwrap();
+        let fd = bound.into_raw_fd();
+        let our_pid = std::process::id().to_string();
+
+        // meant for some other process, so left alone
+        assert!(listen_sockets(Some("1"), Some("1"), fd).unwrap().is_empty());
+
+        let sockets = listen_sockets(Some(&our_pid), Some("1"), fd).unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].local_addr().unwrap(), addr);
+
+        let material = test_utils::material(test_utils::TEST_PASSPHRASE);
+        let server = crate::core::QcatServer::with_sockets(
+            sockets,
+            material.crypto_config(),
+            QcatOptions::default(),
+        )
+        .unwrap();
+        let client = test_utils::client(material.crypto_config(), QcatOptions::default());
+        let received = test_utils::transfer(server, addr, client, &mut &b"activated"[..], 9).await;
+        assert_eq!(received, b"activated");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_activation_refuses_a_tcp_socket() {
+        use std::os::fd::IntoRawFd;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.into_raw_fd();
+        let our_pid = std::process::id().to_string();
+
+        let error = listen_sockets(Some(&our_pid), Some("1"), fd).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn passphrase_stays_out_of_logs_when_asked() {
+        let generated = format!(
+            "Generated salt + passphrase: \"{}\"",
+            test_utils::TEST_PASSPHRASE
+        );
+        let logged = |argv: &[&str]| {
+            let logs = test_utils::capture_logs();
+            let args = Args::try_parse_from(argv).unwrap();
+            announce_generated_passphrase(&args, &generated);
+            logs.records()
+                .iter()
+                .any(|record| record.message.contains(test_utils::TEST_PASSPHRASE))
+        };
+
+        assert!(logged(&["qcat", "--listen", "localhost", "4433"]));
+        assert!(!logged(&[
+            "qcat",
+            "--listen",
+            "--no-log-passphrase",
+            "localhost",
+            "4433"
+        ]));
+        assert!(!logged(&[
+            "qcat",
+            "--listen",
+            "--log-file",
+            "qcat.log",
+            "localhost",
+            "4433"
+        ]));
+    }
 }